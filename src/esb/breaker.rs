@@ -0,0 +1,174 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::time::{Duration, Instant};
+
+/// Configuration of circuit breakers protecting failing destinations from a
+/// storm of send attempts (see [`super::BusConfig::circuit_breaker`]).
+///
+/// After `failures` consecutive send failures to the same destination within
+/// `window` the circuit opens and further sends to the destination fail
+/// immediately with [`super::Error::CircuitOpen`]. Once `cooldown` passes, a
+/// single probe send is allowed: its success closes the circuit, while
+/// failure opens it for another cooldown period.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive send failures opening the circuit
+    pub failures: usize,
+
+    /// Time window within which the failures are counted
+    pub window: Duration,
+
+    /// Time during which the circuit stays open before a probe is allowed
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failures: 5,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+/// State of the circuit breaker for a destination
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+pub enum CircuitState {
+    /// Messages are sent to the destination normally
+    #[display("closed")]
+    Closed,
+
+    /// Sends to the destination fail immediately
+    #[display("open")]
+    Open,
+
+    /// Cooldown has passed and the next send is a probe deciding whether the
+    /// circuit closes or opens again
+    #[display("half-open")]
+    HalfOpen,
+}
+
+impl Default for CircuitState {
+    fn default() -> Self { CircuitState::Closed }
+}
+
+/// Circuit breaker of a single destination
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub(super) struct CircuitBreaker {
+    failures: usize,
+    window_start: Option<Instant>,
+    opened: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// Returns current state of the circuit
+    pub(super) fn state(&self, config: &CircuitBreakerConfig) -> CircuitState {
+        match self.opened {
+            Some(opened) if opened.elapsed() >= config.cooldown => CircuitState::HalfOpen,
+            Some(_) => CircuitState::Open,
+            None => CircuitState::Closed,
+        }
+    }
+
+    /// Registers successful send, closing the circuit
+    pub(super) fn on_success(&mut self) { *self = default!(); }
+
+    /// Registers failed send. Returns `true` if the failure has opened the
+    /// circuit.
+    pub(super) fn on_failure(&mut self, config: &CircuitBreakerConfig) -> bool {
+        let now = Instant::now();
+        if self.opened.is_some() {
+            // Failed probe
+            self.opened = Some(now);
+            return false;
+        }
+        match self.window_start {
+            Some(start) if now.duration_since(start) <= config.window => self.failures += 1,
+            _ => {
+                self.window_start = Some(now);
+                self.failures = 1;
+            }
+        }
+        if self.failures >= config.failures {
+            self.opened = Some(now);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+
+    fn config(failures: usize, window: Duration, cooldown: Duration) -> CircuitBreakerConfig {
+        CircuitBreakerConfig { failures, window, cooldown }
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let config = config(3, Duration::from_secs(60), Duration::from_secs(60));
+        let mut breaker = CircuitBreaker::default();
+        assert!(!breaker.on_failure(&config));
+        assert!(!breaker.on_failure(&config));
+        assert_eq!(breaker.state(&config), CircuitState::Closed);
+        assert!(breaker.on_failure(&config));
+        assert_eq!(breaker.state(&config), CircuitState::Open);
+        // Circuit is already open
+        assert!(!breaker.on_failure(&config));
+        assert_eq!(breaker.state(&config), CircuitState::Open);
+    }
+
+    #[test]
+    fn success_resets_failures() {
+        let config = config(3, Duration::from_secs(60), Duration::from_secs(60));
+        let mut breaker = CircuitBreaker::default();
+        breaker.on_failure(&config);
+        breaker.on_failure(&config);
+        breaker.on_success();
+        assert!(!breaker.on_failure(&config));
+        assert!(!breaker.on_failure(&config));
+        assert_eq!(breaker.state(&config), CircuitState::Closed);
+    }
+
+    #[test]
+    fn failures_outside_window() {
+        let config = config(2, Duration::from_millis(0), Duration::from_secs(60));
+        let mut breaker = CircuitBreaker::default();
+        for _ in 0..3 {
+            assert!(!breaker.on_failure(&config));
+            // Each failure starts a new window
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(breaker.state(&config), CircuitState::Closed);
+    }
+
+    #[test]
+    fn half_open_probe() {
+        let config = config(1, Duration::from_secs(60), Duration::from_millis(0));
+        let mut breaker = CircuitBreaker::default();
+        assert!(breaker.on_failure(&config));
+        assert_eq!(breaker.state(&config), CircuitState::HalfOpen);
+
+        // Failed probe opens the circuit for another cooldown period
+        assert!(!breaker.on_failure(&config));
+        assert_eq!(breaker.state(&config), CircuitState::HalfOpen);
+
+        breaker.on_success();
+        assert_eq!(breaker.state(&config), CircuitState::Closed);
+    }
+}
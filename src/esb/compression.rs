@@ -0,0 +1,111 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Compression of the messages exceeding size threshold, see
+//! [`super::BusConfig::compress_over`]
+
+/// Flag of the messages sent as is
+const RAW: u8 = 0;
+/// Flag of the messages compressed with LZ4
+const LZ4: u8 = 1;
+/// Length of the original data size prepended to LZ4-compressed data
+const SIZE_PREFIX_LEN: usize = 4;
+
+/// Compresses message data if it exceeds `threshold` and the compression
+/// actually reduces its size, prepending the data with one-byte flag telling
+/// whether the data were compressed. Returns flagged data and whether they
+/// were compressed.
+pub(super) fn compress(data: Vec<u8>, threshold: usize) -> (Vec<u8>, bool) {
+    if data.len() > threshold {
+        let compressed = lz4_flex::compress_prepend_size(&data);
+        if compressed.len() < data.len() {
+            let mut packed = Vec::with_capacity(compressed.len() + 1);
+            packed.push(LZ4);
+            packed.extend(compressed);
+            return (packed, true);
+        }
+    }
+    let mut packed = Vec::with_capacity(data.len() + 1);
+    packed.push(RAW);
+    packed.extend(data);
+    (packed, false)
+}
+
+/// Restores original message data from the data produced by [`compress`].
+/// Returns `None` if the data are malformed or the original data size
+/// exceeds `max_size`.
+pub(super) fn decompress(mut data: Vec<u8>, max_size: usize) -> Option<Vec<u8>> {
+    match data.first()? {
+        &RAW => {
+            data.remove(0);
+            Some(data)
+        }
+        &LZ4 => {
+            // The size is set by the remote peer and must be checked before
+            // allocating the buffer for the decompressed data
+            let prefix = data.get(1..1 + SIZE_PREFIX_LEN)?;
+            let size = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]);
+            if size as usize > max_size {
+                return None;
+            }
+            lz4_flex::decompress_size_prepended(&data[1..]).ok()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = b"abcd".repeat(100);
+        let (packed, compressed) = compress(data.clone(), 16);
+        assert!(compressed);
+        assert!(packed.len() < data.len());
+        assert_eq!(decompress(packed, data.len()), Some(data));
+    }
+
+    #[test]
+    fn small_and_incompressible_data_are_raw() {
+        let (packed, compressed) = compress(b"abcd".to_vec(), 16);
+        assert!(!compressed);
+        assert_eq!(packed, b"\x00abcd".to_vec());
+        assert_eq!(decompress(packed, 4), Some(b"abcd".to_vec()));
+
+        let data = (0u8..=255).collect::<Vec<_>>();
+        let (packed, compressed) = compress(data.clone(), 16);
+        assert!(!compressed);
+        assert_eq!(decompress(packed, 0), Some(data));
+    }
+
+    #[test]
+    fn size_limit() {
+        let data = vec![0u8; 1024];
+        let (packed, _) = compress(data.clone(), 16);
+        assert_eq!(decompress(packed.clone(), 1023), None);
+        assert_eq!(decompress(packed, 1024), Some(data));
+
+        // Tiny frame claiming 4 GiB of data
+        assert_eq!(decompress(vec![LZ4, 0xFF, 0xFF, 0xFF, 0xFF], 1024), None);
+    }
+
+    #[test]
+    fn malformed_data() {
+        assert_eq!(decompress(vec![], 1024), None);
+        assert_eq!(decompress(vec![2, 1, 2, 3], 1024), None);
+        assert_eq!(decompress(vec![LZ4, 1], 1024), None);
+        assert_eq!(decompress(vec![LZ4, 16, 0, 0, 0, 0xFF], 1024), None);
+    }
+}
@@ -11,16 +11,47 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
-use std::collections::HashMap;
+use std::any::Any;
+#[cfg(feature = "node")]
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::hash::{Hash, Hasher};
 use std::io::Cursor;
+use std::ops::Range;
+#[cfg(feature = "node")]
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{iter, thread};
 
-use internet2::transport::zmqsocket;
+use internet2::transport::{self, zmqsocket};
 use internet2::{session, PlainTranscoder, Session, Unmarshall, Unmarshaller};
 
-use super::{BusId, Error, ServiceAddress};
-use crate::esb::BusConfig;
+use super::breaker::CircuitBreaker;
+use super::fragment::Reassembler;
+use super::ping::{Probe, PING_TIMEOUT};
+#[cfg(feature = "node")]
+use super::worker::{Command, WorkerPool};
+use super::{
+    BusId, CircuitBreakerConfig, CircuitState, ConnEvent, ConnEventKind, Envelope, Error,
+    FragmentConfig, Header, Layer, PreparedMessage, RecvTransform, SendTransform, ServiceAddress,
+    ENVELOPE_VERSION,
+};
+#[cfg(feature = "node")]
+use crate::esb::ShutdownReport;
 #[cfg(feature = "node")]
-use crate::node::TryService;
+use crate::esb::Worker;
+use crate::esb::{
+    BusConfig, BusRole, BusStats, ControllerConfig, IntegrityMode, InterruptHandle,
+    LatencyHistogram, MessageSizes, RetryPolicy, RoutingTable, RttStats, SlowConsumerConfig,
+    Watchdog,
+};
+#[cfg(feature = "node")]
+use crate::node::{ShutdownReason, ShutdownSignal, TryService};
 use crate::rpc_connection::Request;
 
 /// Trait for types handling specific set of ESB RPC API requests structured as
@@ -51,50 +82,1018 @@ where
         endpoints: &mut EndpointList<B>,
         error: Error<B::Address>,
     ) -> Result<(), Self::Error>;
+
+    /// Called when a frame received from `source` on the `bus_id` service bus
+    /// has failed integrity check (see [`crate::esb::BusConfig::integrity`]).
+    /// The frame is dropped; by default nothing else happens.
+    fn on_corrupt_frame(
+        &mut self,
+        _endpoints: &mut EndpointList<B>,
+        _bus_id: B,
+        _source: B::Address,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called when a router drops a message instead of forwarding it to the
+    /// destination, for instance because the message time-to-live has
+    /// expired. By default nothing happens.
+    fn on_undeliverable(
+        &mut self,
+        _endpoints: &mut EndpointList<B>,
+        _bus_id: B,
+        _source: B::Address,
+        _dest: B::Address,
+        _request: Self::Request,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called on buses with enabled sequencing (see
+    /// [`crate::esb::BusConfig::sequenced`]) when the sequence number `got`
+    /// of a message from `peer` differs from the `expected` one. If `got` is
+    /// greater, some messages were missed; if it is less, the peer has most
+    /// likely restarted. Allows the handler to trigger resynchronization; by
+    /// default nothing happens and the message is processed normally.
+    fn on_sequence_gap(
+        &mut self,
+        _endpoints: &mut EndpointList<B>,
+        _bus_id: B,
+        _peer: B::Address,
+        _expected: u64,
+        _got: u64,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called on buses with enabled sequencing when messages from `source`
+    /// with sequence numbers in the `missing` range were not received, after
+    /// [`Handler::on_sequence_gap`]. If the sender keeps retransmission buffer
+    /// (see [`crate::esb::BusConfig::retransmit_buffer`]), the handler may
+    /// request it to resend the missing messages, for instance with a request
+    /// over another bus processed by [`Controller::retransmit`] on the sender
+    /// side. Retransmitted messages are delivered as usual. By default
+    /// nothing happens.
+    fn on_gap_detected(
+        &mut self,
+        _endpoints: &mut EndpointList<B>,
+        _bus_id: B,
+        _source: B::Address,
+        _missing: Range<u64>,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called by the run loop after processing a batch of received messages
+    /// for each message which was not sent to `dest` since outgoing queue of
+    /// the bus has reached its high water mark (see
+    /// [`crate::esb::BusConfig::hwm`]). The number of such messages is also
+    /// reported by [`crate::esb::BusStats::dropped`]. By default nothing
+    /// happens.
+    fn on_message_dropped(
+        &mut self,
+        _endpoints: &mut EndpointList<B>,
+        _bus_id: B,
+        _dest: B::Address,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called by the run loop after processing a batch of received messages
+    /// when sends to `dest` were rejected due to the high water mark more
+    /// times than configured in [`crate::esb::BusConfig::slow_consumer`],
+    /// indicating that `dest` does not keep up with the incoming messages.
+    /// The handler may disconnect the consumer or throttle the upstream. By
+    /// default nothing happens.
+    fn on_slow_consumer(
+        &mut self,
+        _endpoints: &mut EndpointList<B>,
+        _bus_id: B,
+        _dest: B::Address,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called instead of [`Handler::handle`] or routing when `request` from
+    /// `source` is not permitted by the access control list of the bus (see
+    /// [`Controller::set_access_control`]). The request is dropped; by
+    /// default nothing else happens.
+    fn on_access_denied(
+        &mut self,
+        _endpoints: &mut EndpointList<B>,
+        _bus_id: B,
+        _source: B::Address,
+        _request: Self::Request,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called instead of [`Handler::handle`] when `source` repeats request
+    /// with the idempotency `key` which was already executed (see
+    /// [`crate::esb::BusConfig::idempotency_window`]). If a request is
+    /// returned, it is sent back to the `source` as a replayed response. By
+    /// default nothing is sent.
+    fn cached_response(
+        &mut self,
+        _endpoints: &mut EndpointList<B>,
+        _bus_id: B,
+        _source: B::Address,
+        _key: &[u8],
+    ) -> Result<Option<Self::Request>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Called when [`Handler::handle`] has panicked while processing `request`
+    /// from `source`, if panic isolation is enabled (see
+    /// [`crate::esb::ControllerConfig::catch_panics`]). The request is
+    /// skipped; `panic` contains the panic message. By default nothing else
+    /// happens.
+    fn on_handler_panic(
+        &mut self,
+        _endpoints: &mut EndpointList<B>,
+        _bus_id: B,
+        _source: B::Address,
+        _request: Self::Request,
+        _panic: String,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called when a message from `source` uses envelope `version` which is
+    /// not supported by this controller (see
+    /// [`crate::esb::ControllerConfig::envelope_version`]). The message is
+    /// dropped; by default nothing else happens.
+    fn on_version_mismatch(
+        &mut self,
+        _endpoints: &mut EndpointList<B>,
+        _bus_id: B,
+        _source: B::Address,
+        _version: u8,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Wraps the handler into a middleware [`Layer`], returning the handler
+    /// produced by the layer. Layers can be chained, for instance
+    /// `handler.layer(MetricsLayer).layer(LoggingLayer::default())`.
+    fn layer<L>(self, layer: L) -> L::Handler
+    where
+        L: Layer<Self>,
+    {
+        layer.layer(self)
+    }
 }
 
+/// Callback receiving raw message data together with message source and
+/// destination addresses
+#[cfg(feature = "wire-trace")]
+pub type RawTap<A> = Box<dyn FnMut(&A, &A, &[u8]) + Send>;
+
+/// Access control list of a service bus: decides whether the request from
+/// the given source address is permitted
+pub type AccessControl<A, R> = Box<dyn Fn(&A, &R) -> bool + Send>;
+
+/// Direction of a message passing the service bus boundary
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+pub enum Direction {
+    /// Message received from the bus
+    #[display("inbound")]
+    Inbound,
+
+    /// Message sent to the bus
+    #[display("outbound")]
+    Outbound,
+}
+
+/// Function translating addresses of the messages passing the service bus
+/// boundary, see [`Controller::set_address_rewriter`]
+pub type AddressRewriter<A> = Box<dyn Fn(Direction, A) -> A + Send>;
+
+/// Maximal number of messages which may be read from a single service bus
+/// ahead of their dispatching with [`Controller::peek_ahead`]
+pub const MAX_LOOKAHEAD: usize = 64;
+
+/// Messages read from a service bus ahead of their dispatching (see
+/// [`Controller::peek_ahead`]), with their source, destination and header
+/// kept separately from the requests
+struct Lookahead<A, R> {
+    requests: Vec<R>,
+    routing: Vec<(A, A, Option<Header>)>,
+}
+
+impl<A, R> Default for Lookahead<A, R> {
+    fn default() -> Self { Lookahead { requests: vec![], routing: vec![] } }
+}
+
+/// [`SendTransform`] bound to a specific service bus
+type BusTransform<A> = Box<dyn Fn(Vec<u8>) -> Result<Vec<u8>, Error<A>> + Send>;
+
+/// Binds [`SendTransform`] to the service bus with a given id
+type TransformBinder<B> = Box<dyn Fn(B) -> BusTransform<<B as BusId>::Address> + Send>;
+
 struct Endpoint<A>
 where
     A: ServiceAddress,
 {
     pub(self) session: session::Raw<PlainTranscoder, zmqsocket::Connection>,
     pub(self) router: Option<A>,
+    /// Router as specified in the bus configuration; unlike `router` it is
+    /// kept even if we are the router ourselves
+    pub(self) bus_router: Option<A>,
+    pub(self) role: BusRole,
+    pub(self) integrity: Option<IntegrityMode>,
+    pub(self) headers: bool,
+    pub(self) sequenced: bool,
+    /// Sequence numbers of the next messages sent to each of the peers
+    pub(self) send_seq: HashMap<A, u64>,
+    /// Expected sequence numbers of the next messages from each of the peers
+    pub(self) recv_seq: HashMap<A, u64>,
+    /// Sequence numbers of the last messages from each of the peers returned
+    /// by [`Controller::recv_poll`], used for checking FIFO order
+    #[cfg(debug_assertions)]
+    pub(self) fifo_seq: HashMap<A, u64>,
+    pub(self) dedup: bool,
+    /// Latest envelope version supported by this service
+    pub(self) envelope_version: u8,
+    /// Envelope versions used by the peers in the messages they have sent
+    pub(self) peer_versions: HashMap<A, u8>,
+    /// Hashes of the last messages sent to each of the destinations
+    pub(self) last_sent: HashMap<A, u64>,
+    /// Maximal number of the sent messages kept for retransmission
+    pub(self) retransmit_buffer: Option<usize>,
+    /// Last sent messages kept for retransmission, with their destinations,
+    /// headers and payloads
+    pub(self) sent_buffer: VecDeque<(A, Header, Vec<u8>)>,
+    /// Identity under which this service is known on the bus
+    pub(self) identity: A,
+    pub(self) stats: BusStats,
+    pub(self) sizes: MessageSizes,
+    /// Destinations of the messages dropped at high water mark, which are
+    /// not yet reported to the handler
+    pub(self) dropped: Vec<A>,
+    pub(self) slow_consumer: Option<SlowConsumerConfig>,
+    /// Times of the recent sends to each of the destinations rejected due to
+    /// the high water mark
+    pub(self) blocked: HashMap<A, VecDeque<Instant>>,
+    /// Detected slow consumers, which are not yet reported to the handler
+    pub(self) slow: Vec<A>,
+    pub(self) fragmentation: Option<FragmentConfig>,
+    #[cfg(feature = "compression")]
+    pub(self) compress_over: Option<usize>,
+    #[cfg(feature = "compression")]
+    pub(self) max_decompressed_size: usize,
+    /// Identifier of the next fragmented message
+    pub(self) fragment_id: u64,
+    pub(self) reassembler: Option<Reassembler<A>>,
+    /// Weighted round-robin state for each of the destinations used with
+    /// [`Controller::send_balanced`]: current weight and number of messages
+    /// sent
+    pub(self) balance: HashMap<A, (i64, u64)>,
+    /// Routes for the messages which are not sent via the bus router
+    pub(self) routes: RoutingTable<A>,
+    /// Paused bus is excluded from polling and can't be used for sending
+    pub(self) paused: bool,
+    pub(self) circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Circuit breakers of the destinations to which sends have failed
+    pub(self) breakers: HashMap<A, CircuitBreaker>,
+    /// Peers disconnected from the bus with [`Controller::disconnect_peer`]
+    pub(self) evicted: HashSet<A>,
+    /// Bus id in text form, used for error reporting
+    pub(self) name: String,
+    pub(self) rewriter: Option<AddressRewriter<A>>,
+    /// Transformations applied to the serialized messages before sending
+    pub(self) send_transforms: Vec<BusTransform<A>>,
+    /// Whether the bus socket type allows sending messages
+    pub(self) sendable: bool,
+    /// Frames of a received message batch which were not processed yet
+    pub(self) batch: VecDeque<(A, A, Vec<u8>)>,
+    /// Whether latency probes are processed on the bus
+    pub(self) latency_probes: bool,
+    /// Nonce of the last sent latency probe
+    pub(self) ping_nonce: u64,
+    /// Time when the reply to the last sent latency probe was received
+    pub(self) pong: Option<Instant>,
+    /// Capacity of the idempotency key store
+    pub(self) idempotency_window: Option<usize>,
+    /// Recently executed idempotency keys with their sources, in the order of
+    /// execution
+    pub(self) executed: VecDeque<(A, Vec<u8>)>,
+    /// Whether undecodable messages are skipped
+    pub(self) skip_undecodable: bool,
+    /// Deadline of the request which is currently processed by the handler
+    pub(self) deadline: Option<Instant>,
+    /// Deadline of the handler initialization together with the configured
+    /// initialization timeout, present while [`Handler::on_ready`] runs
+    pub(self) init_deadline: Option<(Instant, Duration)>,
+    /// Address to which the response to the request which is currently
+    /// processed by the handler must be sent
+    pub(self) reply_to: Option<A>,
+    /// Socket receiving connection events from the bus socket, present for
+    /// the buses which connect to a remote endpoint
+    pub(self) monitor: Option<zmq::Socket>,
+    /// Whether the connection to the remote endpoint was established
+    pub(self) connected: bool,
+    /// Whether the socket announces its identity on connection
+    /// (`ZMQ_PROBE_ROUTER`)
+    pub(self) probe_router: bool,
+    /// Whether the remote endpoint knows identity of this service, i.e. it
+    /// has sent at least one message since the last connection
+    pub(self) announced: bool,
+    /// Recent connection events received from the monitor socket
+    pub(self) events: Vec<ConnEvent>,
+    pub(self) context: Option<Box<dyn Any + Send>>,
+    #[cfg(feature = "wire-trace")]
+    pub(self) on_raw_send: Option<RawTap<A>>,
+    #[cfg(feature = "wire-trace")]
+    pub(self) on_raw_recv: Option<RawTap<A>>,
 }
 
 impl<A> Endpoint<A>
 where
     A: ServiceAddress,
 {
-    pub(self) fn send_to<R>(&mut self, source: A, dest: A, request: R) -> Result<(), Error<A>>
+    pub(self) fn send_to<R>(
+        &mut self,
+        source: A,
+        dest: A,
+        request: R,
+        header: Option<Header>,
+    ) -> Result<(), Error<A>>
     where
         R: Request,
     {
-        let data = request.serialize();
-        let router = match self.router {
+        self.send_payload(source, dest, request.serialize(), &request, header)
+    }
+
+    /// Sends already serialized request `data`; `request` is used only for
+    /// logging
+    pub(self) fn send_payload(
+        &mut self,
+        source: A,
+        dest: A,
+        data: Vec<u8>,
+        request: &dyn Display,
+        header: Option<Header>,
+    ) -> Result<(), Error<A>> {
+        self.check_sendable(&dest)?;
+        let len = data.len();
+        let retransmission = header.as_ref().map(|header| header.retransmitted).unwrap_or_default();
+        let dedup_hash = if self.dedup && source == self.identity && !retransmission {
+            let mut hasher = DefaultHasher::new();
+            data.hash(&mut hasher);
+            let hash = hasher.finish();
+            if self.last_sent.get(&dest) == Some(&hash) {
+                trace!("Suppressing {} to {} identical to the previous message", request, dest);
+                self.stats.suppressed += 1;
+                return Ok(());
+            }
+            Some(hash)
+        } else {
+            None
+        };
+        let data = self.encode_payload(&source, &dest, data, request, header)?;
+        let frames = self.fragment_payload(data);
+        let src = source.clone();
+        let dst = dest.clone();
+        // Routing decisions compare addresses as they are seen on the wire
+        let (source, dest) = self.rewrite(Direction::Outbound, source, dest);
+        let router = self.next_hop(&source, &dest, request);
+        let (source, router, dest): (Vec<u8>, Vec<u8>, Vec<u8>) =
+            (source.into(), router.into(), dest.into());
+        let sent_bytes = frames.iter().map(|frame| frame.len() as u64).sum::<u64>();
+        let res = match frames.as_slice() {
+            [frame] => self.session.send_routed_message(&source, &router, &dest, frame).map(|_| ()),
+            // Fragments are sent as a single multipart message, which is
+            // queued atomically, so a failed send never leaves a partial set
+            // of fragments on the wire
+            frames => {
+                let mut parts = vec![router.as_slice(), source.as_slice(), dest.as_slice()];
+                parts.extend(frames.iter().map(Vec::as_slice));
+                self.session.as_socket().send_multipart(parts, 0).map_err(transport::Error::from)
+            }
+        };
+        if let Err(err) = res {
+            return Err(self.send_failed(src, dst, err));
+        }
+        if let Some(breaker) = self.breakers.get_mut(&dst) {
+            breaker.on_success();
+        }
+        if let Some(hash) = dedup_hash {
+            self.last_sent.insert(dst, hash);
+        }
+        self.stats.sent += 1;
+        self.announced = true;
+        self.stats.sent_bytes += sent_bytes;
+        self.sizes.sent.record(len);
+        Ok(())
+    }
+
+    /// Sends multiple messages to `dest` as a single multipart ZMQ message,
+    /// so they are delivered together without being interleaved with other
+    /// messages. Duplicate suppression does not apply to the batched
+    /// messages.
+    pub(self) fn send_batch<R>(
+        &mut self,
+        source: A,
+        dest: A,
+        requests: &[R],
+    ) -> Result<(), Error<A>>
+    where
+        R: Request,
+    {
+        self.check_sendable(&dest)?;
+        let mut frames = vec![];
+        let mut lens = vec![];
+        for request in requests {
+            let data = request.serialize();
+            lens.push(data.len());
+            let data = self.encode_payload(&source, &dest, data, request, None)?;
+            frames.extend(self.fragment_payload(data));
+        }
+        let src = source.clone();
+        let dst = dest.clone();
+        let (source, dest) = self.rewrite(Direction::Outbound, source, dest);
+        let router =
+            self.next_hop(&source, &dest, &format!("batch of {} requests", requests.len()));
+        let (source, router, dest): (Vec<u8>, Vec<u8>, Vec<u8>) =
+            (source.into(), router.into(), dest.into());
+        let mut parts = vec![router.as_slice(), source.as_slice(), dest.as_slice()];
+        parts.extend(frames.iter().map(Vec::as_slice));
+        if let Err(err) = self.session.as_socket().send_multipart(parts, 0) {
+            return Err(self.send_failed(src, dst, transport::Error::from(err)));
+        }
+        if let Some(breaker) = self.breakers.get_mut(&dst) {
+            breaker.on_success();
+        }
+        self.stats.sent += requests.len() as u64;
+        self.announced = true;
+        self.stats.sent_bytes += frames.iter().map(|frame| frame.len() as u64).sum::<u64>();
+        for len in lens {
+            self.sizes.sent.record(len);
+        }
+        Ok(())
+    }
+
+    /// Checks whether messages can be sent to `dest` over the bus
+    pub(self) fn check_sendable(&self, dest: &A) -> Result<(), Error<A>> {
+        if !self.sendable {
+            return Err(Error::BusNotSendable(self.name.clone()));
+        }
+        if self.paused {
+            return Err(Error::BusPaused);
+        }
+        if self.circuit_state(dest) == CircuitState::Open {
+            return Err(Error::CircuitOpen(dest.clone()));
+        }
+        if self.evicted.contains(dest) {
+            return Err(Error::PeerDisconnected(dest.clone()));
+        }
+        Ok(())
+    }
+
+    /// Stops exchanging messages with the `peer` and forgets all state kept
+    /// for it
+    pub(self) fn evict(&mut self, peer: A) {
+        let routes = self.routes.remove_next_hop(&peer);
+        debug!("Disconnecting {} from bus {}, {} routes removed", peer, self.name, routes);
+        self.send_seq.remove(&peer);
+        self.recv_seq.remove(&peer);
+        self.peer_versions.remove(&peer);
+        self.last_sent.remove(&peer);
+        self.balance.remove(&peer);
+        self.breakers.remove(&peer);
+        self.blocked.remove(&peer);
+        self.evicted.insert(peer);
+    }
+
+    /// Prepares serialized message for sending: compresses it, prepends
+    /// message header and applies integrity protection, as configured for
+    /// the bus
+    pub(self) fn encode_payload(
+        &mut self,
+        source: &A,
+        dest: &A,
+        mut data: Vec<u8>,
+        request: &dyn Display,
+        header: Option<Header>,
+    ) -> Result<Vec<u8>, Error<A>> {
+        let retransmission = header.as_ref().map(|header| header.retransmitted).unwrap_or_default();
+        // Retransmitted messages are taken from the retransmission buffer
+        // already transformed and compressed
+        if !retransmission {
+            for transform in &self.send_transforms {
+                data = transform(data)?;
+            }
+        }
+        #[cfg(feature = "compression")]
+        if let (Some(threshold), false) = (self.compress_over, retransmission) {
+            let len = data.len();
+            let (packed, compressed) = super::compression::compress(data, threshold);
+            if compressed {
+                self.stats.uncompressed_bytes += len as u64;
+                self.stats.compressed_bytes += packed.len() as u64 - 1;
+            }
+            data = packed;
+        }
+        if self.headers {
+            let mut header = header.unwrap_or_default();
+            header.stamp();
+            if self.sequenced && header.seq.is_none() && source == &self.identity {
+                let seq = self.send_seq.entry(dest.clone()).or_insert(0);
+                header.seq = Some(*seq);
+                *seq += 1;
+            }
+            if let (Some(capacity), Some(_), false) =
+                (self.retransmit_buffer, header.seq, retransmission)
+            {
+                self.sent_buffer.push_back((dest.clone(), header.clone(), data.clone()));
+                if self.sent_buffer.len() > capacity {
+                    self.sent_buffer.pop_front();
+                }
+            }
+            let version = self
+                .peer_versions
+                .get(dest)
+                .map(|peer| *peer.min(&self.envelope_version))
+                .unwrap_or(self.envelope_version);
+            data = header.seal(version, data)?;
+        } else if header.is_some() {
+            warn!("Message header for {} is ignored since the bus has headers disabled", request);
+        }
+        if let Some(integrity) = self.integrity {
+            integrity.seal(&mut data);
+        }
+        #[cfg(feature = "wire-trace")]
+        if let Some(ref mut tap) = self.on_raw_send {
+            tap(source, dest, &data);
+        }
+        Ok(data)
+    }
+
+    /// Returns next hop for the message from `source` to `dest`
+    pub(self) fn next_hop(&self, source: &A, dest: &A, request: &dyn Display) -> A {
+        match self.router {
             None => {
                 trace!("Sending {} from {} to {} directly", request, source, dest,);
-                dest.clone()
+                self.routes.next_hop(dest)
             }
-            Some(ref router) if &source == router => {
+            Some(ref router) if source == router => {
                 trace!("Routing {} from {} to {}", request, source, dest,);
-                dest.clone()
+                self.routes.next_hop(dest)
             }
             Some(ref router) => {
                 trace!("Sending {} from {} to {} via router {}", request, source, dest, router,);
                 router.clone()
             }
+        }
+    }
+
+    /// Splits encoded message into frames if fragmentation is enabled for the
+    /// bus
+    pub(self) fn fragment_payload(&mut self, data: Vec<u8>) -> Vec<Vec<u8>> {
+        match self.fragmentation {
+            Some(config) => {
+                let id = self.fragment_id;
+                self.fragment_id = self.fragment_id.wrapping_add(1);
+                config.fragment(id, data)
+            }
+            None => vec![data],
+        }
+    }
+
+    /// Accounts failure to send message to `dest`, returning the error
+    pub(self) fn send_failed(&mut self, source: A, dest: A, err: transport::Error) -> Error<A> {
+        self.stats.send_errors += 1;
+        if let transport::Error::Zmq(zmq::Error::EAGAIN) = err {
+            // Outgoing queue has reached its high water mark
+            self.stats.dropped += 1;
+            self.dropped.push(dest.clone());
+            self.track_blocked(&dest);
+        }
+        if let Some(ref config) = self.circuit_breaker {
+            if self.breakers.entry(dest.clone()).or_default().on_failure(config) {
+                warn!("Circuit for {} on bus {} is open", dest, self.name);
+            }
+        }
+        Error::Send(source, dest, err)
+    }
+
+    /// Sends message consisting of multiple frames, preserving frame
+    /// boundaries. Frames are sent as-is: neither message header nor integrity
+    /// protection are applied.
+    pub(self) fn send_multipart(
+        &mut self,
+        source: A,
+        dest: A,
+        frames: &[Vec<u8>],
+    ) -> Result<(), Error<A>> {
+        if !self.sendable {
+            return Err(Error::BusNotSendable(self.name.clone()));
+        }
+        if self.paused {
+            return Err(Error::BusPaused);
+        }
+        let (src, dst) = self.rewrite(Direction::Outbound, source.clone(), dest.clone());
+        let router = match self.router {
+            Some(ref router) if &src != router => router.clone(),
+            _ => self.routes.next_hop(&dst),
         };
-        let src = source.clone();
-        let dst = dest.clone();
+        trace!(
+            "Sending {}-frame multipart message from {} to {} via {}",
+            frames.len(),
+            source,
+            dest,
+            router
+        );
+        let src: Vec<u8> = src.into();
+        let dst: Vec<u8> = dst.into();
+        let hop: Vec<u8> = router.into();
+        let mut parts = vec![hop.as_slice(), src.as_slice(), dst.as_slice()];
+        parts.extend(frames.iter().map(Vec::as_slice));
         self.session
-            .send_routed_message(&source.into(), &router.into(), &dest.into(), &data)
-            .map_err(|err| Error::Send(src, dst, err))?;
+            .as_socket()
+            .send_multipart(parts, 0)
+            .map_err(|err| Error::Send(source, dest, transport::Error::from(err)))?;
+        self.stats.sent += 1;
+        self.announced = true;
+        Ok(())
+    }
+
+    /// Translates message source and destination addresses with the address
+    /// rewriter, if any
+    pub(self) fn rewrite(&self, direction: Direction, source: A, dest: A) -> (A, A) {
+        match self.rewriter {
+            Some(ref rewriter) => (rewriter(direction, source), rewriter(direction, dest)),
+            None => (source, dest),
+        }
+    }
+
+    /// Receives single-frame routed message, returning its source,
+    /// destination and data. Returns `None` for the empty probe messages sent
+    /// by the sockets with `ZMQ_PROBE_ROUTER` when they connect and for the
+    /// latency probes (if enabled), which are processed by the endpoint
+    /// itself.
+    pub(self) fn recv_routed_frame(&mut self) -> Result<Option<(A, A, Vec<u8>)>, Error<A>> {
+        if self.batch.is_empty() {
+            self.read_frames()?;
+        }
+        Ok(self.batch.pop_front())
+    }
+
+    /// Reads single message from the bus socket, queueing its frames for
+    /// [`Endpoint::recv_routed_frame`]. If latency probes are enabled for the
+    /// bus, answers them and records replies to them instead of queueing.
+    pub(self) fn read_frames(&mut self) -> Result<(), Error<A>> {
+        let mut multipart = match self.recv_socket()? {
+            Some(multipart) => multipart.into_iter(),
+            None => return Ok(()),
+        };
+        // Skipping previous hop data since we do not need them
+        let _ = multipart.next();
+        let source = match multipart.next() {
+            Some(source) if !source.is_empty() => source,
+            _ => {
+                trace!("Discarding router probe message");
+                return Ok(());
+            }
+        };
+        let dest = multipart
+            .next()
+            .ok_or(transport::Error::FrameBroken("message destination is absent"))?;
+        let msg =
+            multipart.next().ok_or(transport::Error::FrameBroken("message data is absent"))?;
+        let (source, dest) = self.rewrite(Direction::Inbound, A::from(source), A::from(dest));
+        if self.latency_probes && multipart.len() == 0 {
+            if let Some((probe, nonce)) = Probe::parse(&msg) {
+                return self.process_probe(source, dest, probe, nonce, msg);
+            }
+        }
+        self.batch.push_back((source.clone(), dest.clone(), msg));
+        // Further frames are the rest of messages sent as a batch
+        self.batch.extend(multipart.map(|frame| (source.clone(), dest.clone(), frame)));
+        Ok(())
+    }
+
+    /// Receives multipart message from the bus socket. Returns `None` if the
+    /// socket has no message despite being reported as readable by a poll
+    /// (ZMQ file descriptors are edge-triggered and may wake up spuriously),
+    /// or if the receive timeout has elapsed.
+    fn recv_socket(&self) -> Result<Option<Vec<Vec<u8>>>, Error<A>> {
+        let socket = self.session.as_socket();
+        match socket.recv_multipart(0) {
+            Ok(multipart) => Ok(Some(multipart)),
+            Err(zmq::Error::EAGAIN) => {
+                // Reading `ZMQ_EVENTS` re-arms the edge-triggered file
+                // descriptor, so the next poll reports messages arriving later
+                let events = socket.get_events()?;
+                trace!(
+                    "No message on bus {} after a wakeup (readable: {})",
+                    self.name,
+                    events.contains(zmq::POLLIN)
+                );
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Echoes latency probes addressed to this service, forwards probes
+    /// addressed to other services if this service is the bus router, and
+    /// records replies to the probes sent by this service
+    fn process_probe(
+        &mut self,
+        source: A,
+        dest: A,
+        probe: Probe,
+        nonce: u64,
+        msg: Vec<u8>,
+    ) -> Result<(), Error<A>> {
+        if dest != self.identity {
+            if self.role == BusRole::Hub {
+                trace!("Forwarding latency probe from {} to {}", source, dest);
+                self.send_multipart(source, dest, &[msg])?;
+            }
+            return Ok(());
+        }
+        match probe {
+            Probe::Ping => {
+                trace!("Replying to latency probe from {}", source);
+                self.send_multipart(dest, source, &[Probe::Pong.encode(nonce)])
+            }
+            Probe::Pong if nonce == self.ping_nonce => {
+                self.pong = Some(Instant::now());
+                Ok(())
+            }
+            // Late reply to a probe which has timed out
+            Probe::Pong => Ok(()),
+        }
+    }
+
+    /// Sends latency probe to `dest` and waits for the reply, returning the
+    /// round-trip time. Messages received while waiting are queued for the
+    /// later processing.
+    pub(self) fn ping(&mut self, dest: A) -> Result<Duration, Error<A>> {
+        self.ping_nonce = self.ping_nonce.wrapping_add(1);
+        self.pong = None;
+        let start = Instant::now();
+        self.send_multipart(self.identity.clone(), dest, &[Probe::Ping.encode(self.ping_nonce)])?;
+        loop {
+            if let Some(pong) = self.pong.take() {
+                return Ok(pong.duration_since(start));
+            }
+            let remaining = PING_TIMEOUT
+                .checked_sub(start.elapsed())
+                .ok_or(Error::Transport(transport::Error::TimedOut))?;
+            if self.session.as_socket().poll(zmq::POLLIN, remaining.as_millis() as i64)? > 0 {
+                self.read_frames()?;
+            }
+        }
+    }
+
+    /// Takes all frames queued on the bus socket without blocking, returning
+    /// their source and raw data
+    pub(self) fn drain_raw(&mut self) -> Result<Vec<(A, Vec<u8>)>, Error<A>> {
+        let mut frames =
+            self.batch.drain(..).map(|(source, _, data)| (source, data)).collect::<Vec<_>>();
+        loop {
+            let multipart = match self.session.as_socket().recv_multipart(zmq::DONTWAIT) {
+                Ok(multipart) => multipart,
+                Err(zmq::Error::EAGAIN) => return Ok(frames),
+                Err(err) => return Err(err.into()),
+            };
+            let mut multipart = multipart.into_iter().skip(1);
+            let source = match multipart.next() {
+                Some(source) if !source.is_empty() => A::from(source),
+                // Router probe
+                _ => continue,
+            };
+            // Skipping destination
+            frames.extend(multipart.skip(1).map(|data| (source.clone(), data)));
+        }
+    }
+
+    /// Receives message consisting of multiple frames, returning its source,
+    /// destination and all payload frames
+    pub(self) fn recv_multipart(&mut self) -> Result<(A, A, Vec<Vec<u8>>), Error<A>> {
+        let mut multipart = self.session.as_socket().recv_multipart(0)?.into_iter();
+        // Skipping previous hop data since we do not need them
+        let _ = multipart.next();
+        let source = multipart
+            .next()
+            .ok_or(transport::Error::FrameBroken("multipart message source is absent"))?;
+        let dest = multipart
+            .next()
+            .ok_or(transport::Error::FrameBroken("multipart message destination is absent"))?;
+        self.stats.received += 1;
+        let (source, dest) = self.rewrite(Direction::Inbound, A::from(source), A::from(dest));
+        Ok((source, dest, multipart.collect()))
+    }
+
+    /// Registers send to `dest` rejected due to the high water mark, detecting
+    /// whether `dest` is a slow consumer
+    pub(self) fn track_blocked(&mut self, dest: &A) {
+        let config = match self.slow_consumer {
+            Some(config) => config,
+            None => return,
+        };
+        let now = Instant::now();
+        let blocked = self.blocked.entry(dest.clone()).or_default();
+        blocked.push_back(now);
+        while matches!(blocked.front(), Some(time) if now.duration_since(*time) > config.window) {
+            blocked.pop_front();
+        }
+        if blocked.len() >= config.threshold {
+            blocked.clear();
+            self.slow.push(dest.clone());
+        }
+    }
+
+    /// Receives events from the monitor socket, waiting for the first one at
+    /// most `timeout` milliseconds (`-1` for no limit), and records them into
+    /// the connection event log. Returns whether any events were received.
+    pub(self) fn drain_monitor(&mut self, timeout: i64) -> Result<bool, zmq::Error> {
+        let mut timeout = timeout;
+        let mut received = false;
+        loop {
+            let monitor = match self.monitor {
+                Some(ref monitor) => monitor,
+                None => return Ok(received),
+            };
+            if monitor.poll(zmq::POLLIN, timeout)? == 0 {
+                return Ok(received);
+            }
+            let event = monitor.recv_bytes(0)?;
+            let addr = if monitor.get_rcvmore()? { monitor.recv_bytes(0)? } else { vec![] };
+            received = true;
+            timeout = 0;
+            if let Some(event) = ConnEvent::from_monitor(&event, &addr) {
+                self.record_event(event);
+            }
+        }
+    }
+
+    /// Adds event to the connection event log, which keeps at most
+    /// [`super::CONN_EVENT_LOG_LEN`] recent events
+    pub(self) fn record_event(&mut self, event: ConnEvent) {
+        trace!("Connection event: {}", event);
+        match event.kind {
+            ConnEventKind::Connected => {
+                self.connected = true;
+                self.announced = self.probe_router;
+            }
+            ConnEventKind::Disconnected => {
+                self.connected = false;
+                self.announced = false;
+            }
+            _ => {}
+        }
+        if self.events.len() >= super::CONN_EVENT_LOG_LEN {
+            self.events.remove(0);
+        }
+        self.events.push(event);
+    }
+
+    /// Picks destination from the pool of weighted destinations using smooth
+    /// weighted round-robin, so each destination is picked proportionally to
+    /// its weight and picks of the same destination are interleaved with the
+    /// others
+    pub(self) fn pick_balanced(&mut self, pool: &[(A, u32)]) -> Option<A> {
+        let total = pool.iter().map(|(_, weight)| *weight as i64).sum::<i64>();
+        let mut chosen: Option<(&A, i64)> = None;
+        for (dest, weight) in pool.iter().filter(|(_, weight)| *weight > 0) {
+            let (current, _) = self.balance.entry(dest.clone()).or_default();
+            *current += *weight as i64;
+            if chosen.map(|(_, max)| *current > max).unwrap_or(true) {
+                chosen = Some((dest, *current));
+            }
+        }
+        let (dest, _) = chosen?;
+        let (current, _) = self.balance.entry(dest.clone()).or_default();
+        *current -= total;
+        Some(dest.clone())
+    }
+
+    /// Resends messages to `dest` with sequence numbers in the `missing`
+    /// range which are still kept in the retransmission buffer, returning the
+    /// number of resent messages
+    pub(self) fn retransmit(&mut self, dest: A, missing: Range<u64>) -> Result<usize, Error<A>> {
+        let messages = self
+            .sent_buffer
+            .iter()
+            .filter(|(to, header, _)| {
+                to == &dest && header.seq.map(|seq| missing.contains(&seq)).unwrap_or_default()
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let count = messages.len();
+        for (_, mut header, payload) in messages {
+            trace!("Retransmitting message #{:?} to {}", header.seq, dest);
+            header.retransmitted = true;
+            let source = self.identity.clone();
+            self.send_payload(source, dest.clone(), payload, &"retransmission", Some(header))?;
+        }
+        Ok(count)
+    }
+
+    /// Returns maximal size of the decompressed messages if compression is
+    /// enabled for the bus
+    #[cfg(feature = "compression")]
+    pub(self) fn decompression_limit(&self) -> Option<usize> {
+        self.compress_over.map(|_| self.max_decompressed_size)
+    }
+
+    /// Limits blocking operations on the bus socket to the time remaining
+    /// until the end of the handler initialization, failing with
+    /// [`Error::InitTimeout`] once it is exhausted
+    pub(self) fn limit_to_init_deadline(&self) -> Result<(), Error<A>> {
+        let (deadline, timeout) = match self.init_deadline {
+            None => return Ok(()),
+            Some(init_deadline) => init_deadline,
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(Error::InitTimeout(timeout));
+        }
+        let millis = remaining.as_millis().clamp(1, i32::MAX as u128) as i32;
+        let socket = self.session.as_socket();
+        socket.set_rcvtimeo(millis)?;
+        socket.set_sndtimeo(millis)?;
         Ok(())
     }
 
+    /// Registers execution of a request with the idempotency `key` from
+    /// `source`. Returns `false` if the key was already executed.
+    pub(self) fn register_key(&mut self, source: &A, key: &[u8]) -> bool {
+        let capacity = match self.idempotency_window {
+            Some(capacity) => capacity,
+            None => return true,
+        };
+        if self.executed.iter().any(|(src, k)| src == source && k.as_slice() == key) {
+            return false;
+        }
+        self.executed.push_back((source.clone(), key.to_vec()));
+        if self.executed.len() > capacity {
+            self.executed.pop_front();
+        }
+        true
+    }
+
+    /// Returns state of the circuit breaker for `dest`
+    pub(self) fn circuit_state(&self, dest: &A) -> CircuitState {
+        match (&self.circuit_breaker, self.breakers.get(dest)) {
+            (Some(config), Some(breaker)) => breaker.state(config),
+            _ => CircuitState::Closed,
+        }
+    }
+
+    /// Registers sequence number of a message received from `peer`, returning
+    /// the expected sequence number if it does not match
+    pub(self) fn check_seq(&mut self, peer: &A, seq: u64) -> Option<u64> {
+        let expected = self.recv_seq.insert(peer.clone(), seq + 1);
+        expected.filter(|expected| *expected != seq)
+    }
+
+    /// Asserts that the message with sequence number `seq` from `source` is
+    /// processed after all previous messages from the same source. Sequence
+    /// starting over from zero is treated as a restart of the source.
+    #[cfg(debug_assertions)]
+    pub(self) fn assert_fifo(&mut self, source: &A, seq: u64) {
+        if let Some(last) = self.fifo_seq.insert(source.clone(), seq) {
+            assert!(
+                seq == 0 || seq > last,
+                "message #{} from {} on bus {} is processed after message #{}",
+                seq,
+                source,
+                self.name,
+                last
+            );
+        }
+    }
+
+    /// Changes identity of this service on the bus. Since the service may
+    /// become (or stop being) the bus router, routing is re-evaluated with
+    /// [`Endpoint::update_router_for_identity`].
     #[inline]
     pub(self) fn set_identity(&mut self, identity: A) -> Result<(), Error<A>> {
-        self.session.set_identity(&identity.into()).map_err(Error::from)
+        self.session.set_identity(&identity.clone().into()).map_err(Error::from)?;
+        self.update_router_for_identity(&identity);
+        self.identity = identity;
+        Ok(())
+    }
+
+    /// Recomputes whether messages are sent directly or via the bus router
+    /// for the service having `identity`
+    pub(self) fn update_router_for_identity(&mut self, identity: &A) {
+        let (router, role) = match self.bus_router {
+            Some(ref router) if router == identity => (None, BusRole::Hub),
+            Some(ref router) => (Some(router.clone()), BusRole::Leaf),
+            None => (None, BusRole::Direct),
+        };
+        if role != self.role {
+            debug!("Bus role changed from {} to {} after identity change", self.role, role);
+        }
+        self.router = router;
+        self.role = role;
     }
 }
 
@@ -118,35 +1117,214 @@ where
     where
         R: Request,
     {
-        let session = self.0.get_mut(&bus_id).ok_or(Error::UnknownBusId(bus_id.to_string()))?;
-        session.send_to(source, dest, request)
+        self.endpoint_mut(bus_id)?.send_to(source, dest, request, None)
     }
 
-    pub fn set_identity(
+    /// Sends request with the provided message header. The header is used
+    /// only if the service bus has headers enabled (see
+    /// [`crate::esb::BusConfig::headers`]).
+    pub fn send_with_header<R>(
         &mut self,
         bus_id: B,
-        identity: B::Address,
-    ) -> Result<(), Error<B::Address>> {
-        self.0
-            .get_mut(&bus_id)
-            .ok_or(Error::UnknownBusId(bus_id.to_string()))?
-            .set_identity(identity)
+        source: B::Address,
+        dest: B::Address,
+        request: R,
+        header: Header,
+    ) -> Result<(), Error<B::Address>>
+    where
+        R: Request,
+    {
+        self.endpoint_mut(bus_id)?.send_to(source, dest, request, Some(header))
     }
-}
 
-#[derive(Getters)]
-pub struct Controller<B, R, H>
-where
-    R: Request,
-    B: BusId,
-    H: Handler<B, Request = R>,
-    Error<B::Address>: From<H::Error>,
-{
-    senders: EndpointList<B>,
-    unmarshaller: Unmarshaller<R>,
-    handler: H,
-    api_type: zmqsocket::ZmqType,
-}
+    /// Sends request serialized in advance, avoiding repeated serialization
+    /// when the same request is sent to many destinations or resent. The
+    /// prepared message is not consumed and may be reused.
+    pub fn send_prepared(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        dest: B::Address,
+        prepared: &PreparedMessage,
+    ) -> Result<(), Error<B::Address>> {
+        self.endpoint_mut(bus_id)?.send_payload(source, dest, prepared.to_bytes(), prepared, None)
+    }
+
+    /// Sends multiple requests to `dest` as a single ZMQ multipart message, so
+    /// they are delivered as an indivisible unit, see
+    /// [`Controller::send_batch`]
+    pub fn send_batch<R>(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        dest: B::Address,
+        requests: &[R],
+    ) -> Result<(), Error<B::Address>>
+    where
+        R: Request,
+    {
+        self.endpoint_mut(bus_id)?.send_batch(source, dest, requests)
+    }
+
+    /// Sends message consisting of multiple frames, preserving frame
+    /// boundaries; useful for interoperation with non-Rust ZMQ peers using
+    /// multipart conventions, or for passing metadata in a separate frame.
+    /// Frames are sent as-is, without message header or integrity protection.
+    pub fn send_multipart(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        dest: B::Address,
+        frames: &[Vec<u8>],
+    ) -> Result<(), Error<B::Address>> {
+        self.endpoint_mut(bus_id)?.send_multipart(source, dest, frames)
+    }
+
+    /// Changes identity of this service on the `bus_id` service bus. Routing
+    /// is re-evaluated: if the new identity matches the bus router, the
+    /// service starts to act as the router and sends messages directly;
+    /// if it no longer matches, messages start to go via the router.
+    pub fn set_identity(
+        &mut self,
+        bus_id: B,
+        identity: B::Address,
+    ) -> Result<(), Error<B::Address>> {
+        self.0
+            .get_mut(&bus_id)
+            .ok_or(Error::UnknownBusId(bus_id.to_string()))?
+            .set_identity(identity)
+    }
+
+    /// Returns identity under which this service is known on the `bus_id`
+    /// service bus
+    pub fn identity(&self, bus_id: B) -> Result<B::Address, Error<B::Address>> {
+        Ok(self.endpoint(bus_id)?.identity.clone())
+    }
+
+    /// Provides access to the user context attached to the service bus.
+    ///
+    /// The context is type-erased: each bus may keep a single value of an
+    /// arbitrary type, which lives as long as the bus itself. It is up to the
+    /// handler to use the same type when storing and retrieving the context;
+    /// for the common case use typed [`EndpointList::set_context`],
+    /// [`EndpointList::context`] and [`EndpointList::context_typed_mut`].
+    pub fn context_mut(
+        &mut self,
+        bus_id: B,
+    ) -> Result<&mut Option<Box<dyn Any + Send>>, Error<B::Address>> {
+        Ok(&mut self.endpoint_mut(bus_id)?.context)
+    }
+
+    /// Attaches user context to the service bus, replacing the previous one
+    pub fn set_context<T>(&mut self, bus_id: B, context: T) -> Result<(), Error<B::Address>>
+    where
+        T: Any + Send,
+    {
+        *self.context_mut(bus_id)? = Some(Box::new(context));
+        Ok(())
+    }
+
+    /// Returns deadline of the request currently processed by the handler on
+    /// the `bus_id` service bus, if the request sender has set one (see
+    /// [`Header::deadline`])
+    pub fn deadline(&self, bus_id: B) -> Option<Instant> { self.0.get(&bus_id)?.deadline }
+
+    /// Returns time remaining until the deadline of the request currently
+    /// processed by the handler on the `bus_id` service bus. Handlers may use
+    /// it to bound their own work or to propagate the deadline to the
+    /// downstream requests with [`Header::with_deadline`].
+    pub fn remaining_deadline(&self, bus_id: B) -> Option<Duration> {
+        Some(self.deadline(bus_id)?.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns address to which the response to the request currently
+    /// processed by the handler on the `bus_id` service bus must be sent,
+    /// if the request sender has set one (see [`Header::reply_to`]).
+    /// Otherwise the response goes to the request source.
+    pub fn reply_to(&self, bus_id: B) -> Option<B::Address> {
+        self.0.get(&bus_id)?.reply_to.clone()
+    }
+
+    /// Returns user context attached to the service bus, if it is present and
+    /// has type `T`
+    pub fn context<T>(&self, bus_id: B) -> Option<&T>
+    where
+        T: Any,
+    {
+        self.0.get(&bus_id)?.context.as_ref()?.downcast_ref()
+    }
+
+    /// Returns mutable user context attached to the service bus, if it is
+    /// present and has type `T`
+    pub fn context_typed_mut<T>(&mut self, bus_id: B) -> Option<&mut T>
+    where
+        T: Any,
+    {
+        self.0.get_mut(&bus_id)?.context.as_mut()?.downcast_mut()
+    }
+
+    pub(self) fn endpoint(&self, bus_id: B) -> Result<&Endpoint<B::Address>, Error<B::Address>> {
+        self.0.get(&bus_id).ok_or(Error::UnknownBusId(bus_id.to_string()))
+    }
+
+    pub(self) fn endpoint_mut(
+        &mut self,
+        bus_id: B,
+    ) -> Result<&mut Endpoint<B::Address>, Error<B::Address>> {
+        let endpoint = self.0.get_mut(&bus_id).ok_or(Error::UnknownBusId(bus_id.to_string()))?;
+        endpoint.limit_to_init_deadline()?;
+        Ok(endpoint)
+    }
+}
+
+pub struct Controller<B, R, H>
+where
+    R: Request,
+    B: BusId,
+    H: Handler<B, Request = R>,
+    Error<B::Address>: From<H::Error>,
+{
+    senders: EndpointList<B>,
+    unmarshaller: Unmarshaller<R>,
+    handler: H,
+    api_type: zmqsocket::ZmqType,
+    context: Option<zmq::Context>,
+    max_buses: Option<usize>,
+    catch_panics: bool,
+    init_timeout: Option<Duration>,
+    envelope_version: u8,
+    /// Service buses in the order of their registration, used for round-robin
+    /// polling
+    bus_order: Vec<B>,
+    /// Position in `bus_order` from which the next poll starts
+    rr_cursor: usize,
+    /// Access control lists of the service buses
+    acls: HashMap<B, AccessControl<B::Address, R>>,
+    recv_transforms: Vec<Box<dyn RecvTransform<B>>>,
+    send_transforms: Vec<TransformBinder<B>>,
+    /// Human-readable names of the service addresses
+    aliases: HashMap<String, B::Address>,
+    /// Time spent by the handler processing requests of each type
+    handle_latency: HashMap<u16, LatencyHistogram>,
+    /// Number of requests dispatched to the handler or the workers
+    #[cfg(feature = "node")]
+    dispatched: u64,
+    /// Configurations of the lazy service buses which were not used yet
+    lazy_buses: HashMap<B, BusConfig<B::Address>>,
+    /// Messages read from the service buses ahead of their dispatching
+    lookahead: HashMap<B, Lookahead<B::Address, R>>,
+    #[cfg(feature = "node")]
+    worker_threads: Option<usize>,
+    /// Worker threads processing requests, if a worker was set
+    #[cfg(feature = "node")]
+    workers: Option<WorkerPool<B, R>>,
+    watchdog: Option<Watchdog>,
+    /// Socket included into each poll, waking the controller up on interrupt
+    interrupt: zmq::Socket,
+    interrupt_handle: InterruptHandle,
+    #[cfg(feature = "node")]
+    shutdown_signal: ShutdownSignal,
+}
 
 impl<B, R, H> Controller<B, R, H>
 where
@@ -160,164 +1338,2267 @@ where
         handler: H,
         api_type: zmqsocket::ZmqType,
     ) -> Result<Self, Error<B::Address>> {
+        Self::with_unmarshaller(service_bus, handler, api_type, R::create_unmarshaller())
+    }
+
+    /// Constructs controller decoding received messages with the provided
+    /// `unmarshaller` instead of the default one created by
+    /// [`Request::create_unmarshaller`]. Allows to register custom type
+    /// handlers or to inject unmarshaller failing deterministically in tests.
+    pub fn with_unmarshaller(
+        service_bus: HashMap<B, BusConfig<B::Address>>,
+        handler: H,
+        api_type: zmqsocket::ZmqType,
+        unmarshaller: Unmarshaller<R>,
+    ) -> Result<Self, Error<B::Address>> {
+        Self::construct(service_bus, handler, api_type, ControllerConfig::default(), unmarshaller)
+    }
+
+    /// Constructs controller which creates sockets for all service buses
+    /// specified with locators using the provided ZMQ `context` instead of the
+    /// default global one. This allows to tune context parameters (like the
+    /// number of IO threads, see [`crate::esb::ContextConfig`]) and to share the
+    /// same context between multiple controllers.
+    pub fn with_context(
+        service_bus: HashMap<B, BusConfig<B::Address>>,
+        handler: H,
+        api_type: zmqsocket::ZmqType,
+        context: zmq::Context,
+    ) -> Result<Self, Error<B::Address>> {
+        let config = ControllerConfig { context: Some(context), ..default!() };
+        Self::with_config(service_bus, handler, api_type, config)
+    }
+
+    /// Constructs controller with the provided controller-wide configuration
+    pub fn with_config(
+        service_bus: HashMap<B, BusConfig<B::Address>>,
+        handler: H,
+        api_type: zmqsocket::ZmqType,
+        config: ControllerConfig,
+    ) -> Result<Self, Error<B::Address>> {
+        Self::construct(service_bus, handler, api_type, config, R::create_unmarshaller())
+    }
+
+    fn construct(
+        service_bus: HashMap<B, BusConfig<B::Address>>,
+        handler: H,
+        api_type: zmqsocket::ZmqType,
+        config: ControllerConfig,
+        unmarshaller: Unmarshaller<R>,
+    ) -> Result<Self, Error<B::Address>> {
+        let envelope_version = config.envelope_version.unwrap_or(ENVELOPE_VERSION);
+        if envelope_version == 0 || envelope_version > ENVELOPE_VERSION {
+            return Err(Error::Header(format!(
+                "envelope version {} is not supported; supported versions are 1 to {}",
+                envelope_version, ENVELOPE_VERSION
+            )));
+        }
+        let context = config.context.clone().unwrap_or_else(|| zmqsocket::ZMQ_CONTEXT.clone());
+        let (interrupt, interrupt_handle) = InterruptHandle::pair(&context)?;
         let endpoints = EndpointList::new();
-        let unmarshaller = R::create_unmarshaller();
-        let mut me = Self { senders: endpoints, unmarshaller, handler, api_type };
+        let mut me = Self {
+            senders: endpoints,
+            unmarshaller,
+            handler,
+            api_type,
+            context: config.context,
+            max_buses: config.max_buses,
+            catch_panics: config.catch_panics,
+            init_timeout: config.init_timeout,
+            envelope_version,
+            bus_order: vec![],
+            rr_cursor: 0,
+            acls: HashMap::new(),
+            recv_transforms: vec![],
+            send_transforms: vec![],
+            aliases: HashMap::new(),
+            handle_latency: HashMap::new(),
+            #[cfg(feature = "node")]
+            dispatched: 0,
+            lookahead: HashMap::new(),
+            lazy_buses: HashMap::new(),
+            #[cfg(feature = "node")]
+            worker_threads: config.worker_threads,
+            #[cfg(feature = "node")]
+            workers: None,
+            watchdog: config.watchdog.map(Watchdog::spawn),
+            interrupt,
+            interrupt_handle,
+            #[cfg(feature = "node")]
+            shutdown_signal: ShutdownSignal::new(),
+        };
         for (id, config) in service_bus {
             me.add_service_bus(id, config)?;
         }
         Ok(me)
     }
 
+    /// Constructs controller like [`Controller::with_config`], but instead of
+    /// failing if some of the service buses can't be added, returns the
+    /// controller with the buses which were added successfully together with
+    /// the errors for the failed ones. The caller may decide whether the
+    /// controller is usable, allowing services to run degraded (for instance
+    /// without an optional telemetry bus).
+    pub fn with_partial(
+        service_bus: HashMap<B, BusConfig<B::Address>>,
+        handler: H,
+        api_type: zmqsocket::ZmqType,
+        config: ControllerConfig,
+    ) -> Result<(Self, Vec<(B, Error<B::Address>)>), Error<B::Address>> {
+        let mut me = Self::with_config(HashMap::new(), handler, api_type, config)?;
+        let mut failed = vec![];
+        for (id, config) in service_bus {
+            if let Err(err) = me.add_service_bus(id, config) {
+                warn!("Unable to add service bus {}: {}", id, err);
+                failed.push((id, err));
+            }
+        }
+        Ok((me, failed))
+    }
+
+    /// Replaces handler of the controller, keeping all service buses with
+    /// their sockets, identities, routing and queued messages intact. Allows
+    /// live upgrades of the business logic without dropping connections.
+    ///
+    /// The new handler is not notified with [`Handler::on_ready`]; if it needs
+    /// initialization, it must be done before the replacement.
+    pub fn replace_handler<H2>(self, new_handler: H2) -> Controller<B, R, H2>
+    where
+        H2: Handler<B, Request = R>,
+        Error<B::Address>: From<H2::Error>,
+    {
+        Controller {
+            senders: self.senders,
+            unmarshaller: self.unmarshaller,
+            handler: new_handler,
+            api_type: self.api_type,
+            context: self.context,
+            max_buses: self.max_buses,
+            catch_panics: self.catch_panics,
+            init_timeout: self.init_timeout,
+            envelope_version: self.envelope_version,
+            bus_order: self.bus_order,
+            rr_cursor: self.rr_cursor,
+            acls: self.acls,
+            recv_transforms: self.recv_transforms,
+            send_transforms: self.send_transforms,
+            aliases: self.aliases,
+            handle_latency: self.handle_latency,
+            #[cfg(feature = "node")]
+            dispatched: self.dispatched,
+            lookahead: self.lookahead,
+            lazy_buses: self.lazy_buses,
+            #[cfg(feature = "node")]
+            worker_threads: self.worker_threads,
+            #[cfg(feature = "node")]
+            workers: self.workers,
+            watchdog: self.watchdog,
+            interrupt: self.interrupt,
+            interrupt_handle: self.interrupt_handle,
+            #[cfg(feature = "node")]
+            shutdown_signal: self.shutdown_signal,
+        }
+    }
+
     pub fn add_service_bus(
         &mut self,
         id: B,
-        config: BusConfig<B::Address>,
+        mut config: BusConfig<B::Address>,
     ) -> Result<(), Error<B::Address>> {
+        // Lazy buses count towards the limit once registered, so the limit
+        // can't be bypassed by deferring socket creation
+        let registered = self.senders.0.contains_key(&id) || self.lazy_buses.contains_key(&id);
+        match self.max_buses {
+            Some(max) if self.senders.0.len() + self.lazy_buses.len() >= max && !registered => {
+                return Err(Error::BusLimitReached(max))
+            }
+            _ => {}
+        }
+        if config.lazy {
+            debug!("Deferring creation of service bus {} until its first use", id);
+            config.lazy = false;
+            self.lazy_buses.insert(id, config);
+            return Ok(());
+        }
+        let identity = config.identity.unwrap_or_else(|| self.handler.identity());
+        let binds = matches!(
+            self.api_type,
+            zmqsocket::ZmqType::Pull
+                | zmqsocket::ZmqType::Rep
+                | zmqsocket::ZmqType::Pub
+                | zmqsocket::ZmqType::RouterBind
+        );
+        let mut monitor = None;
+        let reconnect = (config.reconnect_ivl, config.reconnect_ivl_max);
+        let hwm = config.hwm;
+        let immediate = config.immediate;
+        let probe_router = config.probe_router;
         let session = match config.carrier {
             zmqsocket::Carrier::Locator(locator) => {
                 debug!(
                     "Creating ESB session for service {} located at {} with identity '{}'",
-                    &id,
-                    &locator,
-                    self.handler.identity()
+                    &id, &locator, identity
                 );
-                let session = session::Raw::with_zmq_unencrypted(
-                    self.api_type,
-                    &locator,
-                    None,
-                    Some(&self.handler.identity().into()),
-                )?;
-                session
+                let context =
+                    self.context.clone().unwrap_or_else(|| zmqsocket::ZMQ_CONTEXT.clone());
+                let socket = context.socket(self.api_type.socket_type())?;
+                socket.set_identity(&identity.clone().into())?;
+                set_reconnect_ivl(&socket, reconnect)?;
+                set_hwm(&socket, hwm)?;
+                if immediate {
+                    socket.set_immediate(true)?;
+                }
+                if !binds {
+                    // Monitor must be attached before connecting, otherwise
+                    // the connection event may be missed
+                    monitor = Some(monitor_socket(&context, &socket)?);
+                    if probe_router {
+                        socket.set_probe_router(true)?;
+                    }
+                }
+                for locator in iter::once(locator).chain(config.extra_locators) {
+                    let endpoint = locator.zmq_socket_string();
+                    if binds {
+                        socket.bind(&endpoint)?;
+                    } else {
+                        socket.connect(&endpoint)?;
+                    }
+                }
+                if self.api_type == zmqsocket::ZmqType::Sub {
+                    socket.set_subscribe(&[])?;
+                }
+                session::Raw::from_zmq_socket_unencrypted(self.api_type, socket)
             }
             zmqsocket::Carrier::Socket(socket) => {
                 debug!("Creating ESB session for service {}", &id);
+                // For already connected sockets applies to reconnections only
+                set_reconnect_ivl(&socket, reconnect)?;
+                set_hwm(&socket, hwm)?;
+                if immediate {
+                    socket.set_immediate(true)?;
+                }
                 session::Raw::from_zmq_socket_unencrypted(self.api_type, socket)
             }
         };
         if !config.queued {
             session.as_socket().set_router_mandatory(true)?;
         }
-        let router = match config.router {
-            Some(router) if router == self.handler.identity() => None,
-            router => router,
+        let mut endpoint = Endpoint {
+            session,
+            router: None,
+            bus_router: config.router,
+            role: BusRole::Direct,
+            integrity: config.integrity,
+            headers: config.headers,
+            sequenced: config.sequenced,
+            send_seq: default!(),
+            recv_seq: default!(),
+            #[cfg(debug_assertions)]
+            fifo_seq: default!(),
+            dedup: config.dedup,
+            envelope_version: self.envelope_version,
+            peer_versions: default!(),
+            last_sent: default!(),
+            retransmit_buffer: config.retransmit_buffer,
+            sent_buffer: default!(),
+            identity,
+            stats: BusStats::default(),
+            sizes: MessageSizes::default(),
+            dropped: vec![],
+            slow_consumer: config.slow_consumer,
+            blocked: default!(),
+            slow: vec![],
+            fragmentation: config.fragmentation,
+            #[cfg(feature = "compression")]
+            compress_over: config.compress_over,
+            #[cfg(feature = "compression")]
+            max_decompressed_size: config.max_decompressed_size,
+            fragment_id: 0,
+            reassembler: config.fragmentation.map(Reassembler::new),
+            balance: default!(),
+            routes: RoutingTable::new(),
+            paused: false,
+            circuit_breaker: config.circuit_breaker,
+            breakers: default!(),
+            evicted: default!(),
+            name: id.to_string(),
+            rewriter: None,
+            send_transforms: vec![],
+            sendable: is_sendable(&self.api_type),
+            batch: default!(),
+            latency_probes: config.latency_probes,
+            ping_nonce: 0,
+            pong: None,
+            idempotency_window: config.idempotency_window,
+            executed: default!(),
+            skip_undecodable: config.skip_undecodable,
+            deadline: None,
+            init_deadline: None,
+            reply_to: None,
+            monitor,
+            events: vec![],
+            connected: binds,
+            probe_router,
+            announced: binds,
+            context: None,
+            #[cfg(feature = "wire-trace")]
+            on_raw_send: None,
+            #[cfg(feature = "wire-trace")]
+            on_raw_recv: None,
         };
-        self.senders.0.insert(id, Endpoint { session, router });
+        endpoint.update_router_for_identity(&endpoint.identity.clone());
+        for bind in &self.send_transforms {
+            endpoint.send_transforms.push(bind(id));
+        }
+        if self.senders.0.insert(id, endpoint).is_none() {
+            self.bus_order.push(id);
+        }
         Ok(())
     }
 
-    pub fn send_to(
+    /// Creates socket for the lazy service bus (see [`BusConfig::lazy`]) if
+    /// it was not used yet. Controller establishes lazy buses automatically
+    /// when they are used for sending or polling with its methods; however
+    /// the bus must be established explicitly before the handler may send
+    /// messages to it through [`EndpointList`] or before it is included into
+    /// the run loop polling.
+    pub fn establish_bus(&mut self, bus_id: B) -> Result<(), Error<B::Address>> {
+        match self.lazy_buses.remove(&bus_id) {
+            Some(config) => {
+                debug!("Establishing lazy service bus {} on its first use", bus_id);
+                self.add_service_bus(bus_id, config)
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn senders(&self) -> &EndpointList<B> { &self.senders }
+
+    pub fn unmarshaller(&self) -> &Unmarshaller<R> { &self.unmarshaller }
+
+    pub fn handler(&self) -> &H { &self.handler }
+
+    pub fn api_type(&self) -> &zmqsocket::ZmqType { &self.api_type }
+
+    /// Decodes request from raw message data (without message header or
+    /// integrity protection) with the same unmarshaller which is used for the
+    /// messages received from the service buses. Useful for inspecting frames
+    /// captured elsewhere.
+    pub fn decode(&self, data: &[u8]) -> Result<R, Error<B::Address>> {
+        Ok((&*self.unmarshaller.unmarshall(Cursor::new(data))?).clone())
+    }
+
+    /// Returns handle which may be used by other threads to interrupt blocking
+    /// poll of the service buses, making it return immediately without
+    /// messages. Useful for waking the run loop up for shutdown or
+    /// reconfiguration.
+    pub fn interrupt_handle(&self) -> InterruptHandle { self.interrupt_handle.clone() }
+
+    /// Makes requests addressed to this service to be processed by `worker`
+    /// on a pool of [`ControllerConfig::worker_threads`] threads instead of
+    /// the controller handler, which still receives all other callbacks.
+    /// Replaces the previously set worker, waiting for it to process all
+    /// queued requests.
+    ///
+    /// Handler deadlines, latency tracking and panic isolation do not apply
+    /// to the requests processed by the workers.
+    #[cfg(feature = "node")]
+    pub fn set_worker<W>(&mut self, worker: W) -> Result<(), Error<B::Address>>
+    where
+        W: Worker<B, Request = R>,
+        R: Send + 'static,
+        B: Send + 'static,
+        B::Address: Send + 'static,
+    {
+        let count = self.worker_threads.unwrap_or(1).max(1);
+        debug!("Spawning {} ESB worker threads", count);
+        self.workers = None;
+        self.workers = Some(WorkerPool::spawn(count, worker, self.interrupt_handle.clone())?);
+        Ok(())
+    }
+
+    /// Returns signal which may be used to shut down the controller run loop.
+    /// The signal is checked after each portion of received messages is
+    /// processed.
+    #[cfg(feature = "node")]
+    pub fn shutdown_signal(&self) -> ShutdownSignal { self.shutdown_signal.clone() }
+
+    /// Sends request with the provided message header, which is used only if
+    /// the service bus has headers enabled (see
+    /// [`crate::esb::BusConfig::headers`])
+    pub fn send_with_header(
         &mut self,
         bus_id: B,
         dest: B::Address,
         request: R,
+        header: Header,
     ) -> Result<(), Error<B::Address>> {
-        self.senders.send_to(bus_id, self.handler.identity(), dest, request)
+        self.establish_bus(bus_id)?;
+        let source = self.senders.identity(bus_id)?;
+        self.senders.send_with_header(bus_id, source, dest, request, header)
     }
 
-    pub fn recv_poll(&mut self) -> Result<Vec<(B, B::Address, H::Request)>, Error<B::Address>> {
-        let mut vec = vec![];
-        for bus_id in self.poll()? {
-            let sender = self.senders.0.get_mut(&bus_id).expect("must exist, just indexed");
-
-            let routed_frame = sender.session.recv_routed_message()?;
-            let request = (&*self.unmarshaller.unmarshall(Cursor::new(routed_frame.msg))?).clone();
-            let source = B::Address::from(routed_frame.src);
-
-            vec.push((bus_id, source, request));
-        }
-
-        Ok(vec)
+    /// Sends request to the router of the service bus itself, for instance
+    /// for management purposes, so it is processed by the router and not
+    /// routed onward. Fails with [`Error::NoRouterConfigured`] if the bus has
+    /// no router.
+    pub fn send_to_router(&mut self, bus_id: B, request: R) -> Result<(), Error<B::Address>> {
+        self.establish_bus(bus_id)?;
+        let router = self
+            .senders
+            .endpoint(bus_id)?
+            .bus_router
+            .clone()
+            .ok_or_else(|| Error::NoRouterConfigured(bus_id.to_string()))?;
+        self.send_to(bus_id, router, request)
     }
-}
 
-#[cfg(feature = "node")]
-impl<B, R, H> TryService for Controller<B, R, H>
-where
-    R: Request,
-    B: BusId,
-    H: Handler<B, Request = R>,
-    Error<B::Address>: From<H::Error>,
-{
-    type ErrorType = Error<B::Address>;
+    /// Sends request serialized in advance with [`PreparedMessage::with`]
+    pub fn send_prepared(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        prepared: &PreparedMessage,
+    ) -> Result<(), Error<B::Address>> {
+        self.establish_bus(bus_id)?;
+        let source = self.senders.identity(bus_id)?;
+        self.senders.send_prepared(bus_id, source, dest, prepared)
+    }
 
-    fn try_run_loop(mut self) -> Result<(), Self::ErrorType> {
-        self.handler.on_ready(&mut self.senders)?;
+    /// Sends request retrying on recoverable errors (see
+    /// [`Error::is_recoverable`]) according to the retry policy. Other
+    /// errors, like unroutable destination, are not retried. On failure
+    /// returns [`Error::SendFailed`] with the number of attempts made and the
+    /// error of the last attempt.
+    ///
+    /// The request is serialized once and the calling thread is blocked for
+    /// the backoff time between the attempts.
+    pub fn send_to_retry(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        request: R,
+        policy: RetryPolicy,
+    ) -> Result<(), Error<B::Address>> {
+        let prepared = PreparedMessage::with(&request);
+        let mut attempts = 0;
         loop {
-            match self.run() {
-                Ok(_) => trace!("request processing complete"),
-                Err(err) => {
-                    error!("ESB request processing error: {}", err);
-                    self.handler.handle_err(&mut self.senders, err)?;
+            attempts += 1;
+            match self.send_prepared(bus_id, dest.clone(), &prepared) {
+                Ok(()) => return Ok(()),
+                Err(err) if err.is_recoverable() && attempts < policy.max_attempts => {
+                    let delay = policy.delay(attempts);
+                    debug!(
+                        "Sending {} to {} has failed ({}), retrying in {:?}",
+                        prepared, dest, err, delay
+                    );
+                    thread::sleep(delay);
                 }
+                Err(err) => return Err(Error::SendFailed(attempts, Box::new(err))),
             }
         }
     }
-}
 
-impl<B, R, H> Controller<B, R, H>
-where
-    R: Request,
-    B: BusId,
-    H: Handler<B, Request = R>,
-    Error<B::Address>: From<H::Error>,
-{
-    #[cfg(feature = "node")]
-    fn run(&mut self) -> Result<(), Error<B::Address>> {
-        for bus_id in self.poll()? {
-            let sender = self.senders.0.get_mut(&bus_id).expect("must exist, just indexed");
+    /// Sends request to one of the equivalent destinations from the `pool`,
+    /// picked by weighted round-robin: each destination receives share of the
+    /// requests proportional to its weight. Destinations with zero weight are
+    /// never picked. Returns the picked destination.
+    ///
+    /// Number of requests sent to each destination is available via
+    /// [`Controller::balanced_counts`].
+    pub fn send_balanced(
+        &mut self,
+        bus_id: B,
+        pool: &[(B::Address, u32)],
+        request: R,
+    ) -> Result<B::Address, Error<B::Address>> {
+        self.establish_bus(bus_id)?;
+        let dest =
+            self.senders.endpoint_mut(bus_id)?.pick_balanced(pool).ok_or(Error::EmptyPool)?;
+        self.send_to(bus_id, dest.clone(), request)?;
+        if let Some((_, sent)) = self.senders.endpoint_mut(bus_id)?.balance.get_mut(&dest) {
+            *sent += 1;
+        }
+        Ok(dest)
+    }
 
-            let routed_frame = sender.session.recv_routed_message()?;
-            let request = (&*self.unmarshaller.unmarshall(Cursor::new(routed_frame.msg))?).clone();
-            let source = B::Address::from(routed_frame.src);
-            let dest = B::Address::from(routed_frame.dst);
+    /// Returns number of requests sent by [`Controller::send_balanced`] over
+    /// the service bus to each of the destinations
+    pub fn balanced_counts(
+        &self,
+        bus_id: B,
+    ) -> Result<HashMap<B::Address, u64>, Error<B::Address>> {
+        Ok(self
+            .senders
+            .endpoint(bus_id)?
+            .balance
+            .iter()
+            .map(|(dest, (_, sent))| (dest.clone(), *sent))
+            .collect())
+    }
 
-            if dest == self.handler.identity() {
-                // We are the destination
-                debug!("{} -> {}: {}", source, dest, request);
+    /// Sends request with the given priority: within a batch of messages
+    /// received by a controller, messages with higher priority are processed
+    /// first. Requires service bus with headers enabled.
+    pub fn send_with_priority(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        request: R,
+        priority: u8,
+    ) -> Result<(), Error<B::Address>> {
+        self.send_with_header(bus_id, dest, request, Header::with_priority(priority))
+    }
 
-                self.handler.handle(&mut self.senders, bus_id, source, request)?;
-            } else {
-                // Need to route
-                trace!("Routing {} from {} to {}", request, source, dest);
-                self.senders.send_to(bus_id, source, dest, request)?
-            }
-        }
+    /// Sends request which must be dropped by the routers if not delivered
+    /// within `ttl`. Requires service bus with headers enabled.
+    pub fn send_with_ttl(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        request: R,
+        ttl: Duration,
+    ) -> Result<(), Error<B::Address>> {
+        self.send_with_header(bus_id, dest, request, Header::with_ttl(ttl))
+    }
+
+    /// Sends request which must be executed by `dest` at most once for the
+    /// idempotency `key`, so it may be safely retried. Requires service bus
+    /// with headers enabled.
+    pub fn send_idempotent(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        request: R,
+        key: impl Into<Vec<u8>>,
+    ) -> Result<(), Error<B::Address>> {
+        self.send_with_header(bus_id, dest, request, Header::with_idempotency_key(key))
+    }
+
+    /// Sends request which response must be sent to `reply_to` instead of
+    /// this service (see [`EndpointList::reply_to`]). Requires service bus
+    /// with headers enabled.
+    pub fn send_with_reply_to(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        request: R,
+        reply_to: B::Address,
+    ) -> Result<(), Error<B::Address>> {
+        self.send_with_header(bus_id, dest, request, Header::with_reply_to(reply_to))
+    }
+
+    /// Sends request which must be processed within `deadline`; routers drop
+    /// the request once the deadline is exhausted. Requires service bus with
+    /// headers enabled.
+    pub fn send_with_deadline(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        request: R,
+        deadline: Duration,
+    ) -> Result<(), Error<B::Address>> {
+        self.send_with_header(bus_id, dest, request, Header::with_deadline(deadline))
+    }
 
+    /// Registers callback receiving raw data of each message sent to the
+    /// service bus, exactly as it goes to the wire, together with the message
+    /// source and destination
+    #[cfg(feature = "wire-trace")]
+    pub fn on_raw_send(
+        &mut self,
+        bus_id: B,
+        tap: impl FnMut(&B::Address, &B::Address, &[u8]) + Send + 'static,
+    ) -> Result<(), Error<B::Address>> {
+        self.senders.endpoint_mut(bus_id)?.on_raw_send = Some(Box::new(tap));
         Ok(())
     }
 
-    fn poll(&mut self) -> Result<Vec<B>, Error<B::Address>> {
-        let mut index = vec![];
-        let mut items = self
-            .senders
-            .0
-            .iter()
-            .map(|(service, sender)| {
-                index.push(service);
-                sender.session.as_socket().as_poll_item(zmq::POLLIN | zmq::POLLERR)
-            })
-            .collect::<Vec<_>>();
+    /// Sets function translating source and destination addresses of the
+    /// messages passing the service bus boundary, for instance by a gateway
+    /// between two address namespaces. Replaces previously set rewriter.
+    ///
+    /// Addresses of the received messages are rewritten with
+    /// [`Direction::Inbound`] right after they are read from the bus, before
+    /// any other processing; thus access control, sequencing, comparison of
+    /// the destination with the service identity and routing decisions, as
+    /// well as the handler, see only the rewritten addresses. Addresses of
+    /// the sent messages are rewritten with [`Direction::Outbound`] before the
+    /// next hop is selected, so routing compares the rewritten addresses with
+    /// the router and routing table; the next hop address itself is not
+    /// rewritten.
+    pub fn set_address_rewriter(
+        &mut self,
+        bus_id: B,
+        rewriter: impl Fn(Direction, B::Address) -> B::Address + Send + 'static,
+    ) -> Result<(), Error<B::Address>> {
+        self.senders.endpoint_mut(bus_id)?.rewriter = Some(Box::new(rewriter));
+        Ok(())
+    }
 
-        trace!("Awaiting for ESB request from {} service buses...", items.len());
-        let _ = zmq::poll(&mut items, -1)?;
+    /// Adds transformation applied to the data of all messages received from
+    /// the service buses before they are unmarshalled. Transformations are
+    /// applied in the order they were added; a failed transformation fails
+    /// the receive operation like an unmarshalling error does.
+    pub fn add_recv_transform(&mut self, transform: impl RecvTransform<B>) {
+        self.recv_transforms.push(Box::new(transform));
+    }
 
-        let service_buses = items
-            .iter()
-            .enumerate()
-            .filter_map(
-                |(i, item)| {
-                    if item.get_revents().is_empty() {
-                        None
-                    } else {
-                        Some(*index[i])
-                    }
-                },
-            )
+    /// Adds transformation applied to the serialized data of all messages
+    /// sent to the service buses, including the messages sent by the handler
+    /// through [`EndpointList`]. Transformations are applied in the order
+    /// they were added, so the matching [`RecvTransform`]s must be added by
+    /// the receiving services in the reverse order.
+    pub fn add_send_transform(&mut self, transform: impl SendTransform<B>)
+    where
+        B: Send + 'static,
+    {
+        let transform = Arc::new(transform);
+        let bind: TransformBinder<B> = Box::new(move |bus_id| {
+            let transform = transform.clone();
+            Box::new(move |data: Vec<u8>| transform.transform(bus_id, data))
+                as BusTransform<B::Address>
+        });
+        for (bus_id, endpoint) in &mut self.senders.0 {
+            endpoint.send_transforms.push(bind(*bus_id));
+        }
+        self.send_transforms.push(bind);
+    }
+
+    /// Sets access control list for the service bus: each message received
+    /// from the bus, which is going to be dispatched to the handler or routed,
+    /// is first checked with `acl`, and if it is not permitted it is dropped
+    /// and reported to [`Handler::on_access_denied`]. Replaces previously set
+    /// access control list of the bus.
+    pub fn set_access_control(
+        &mut self,
+        bus_id: B,
+        acl: impl Fn(&B::Address, &R) -> bool + Send + 'static,
+    ) -> Result<(), Error<B::Address>> {
+        self.senders.endpoint(bus_id)?;
+        self.acls.insert(bus_id, Box::new(acl));
+        Ok(())
+    }
+
+    /// Removes access control list of the service bus, permitting all
+    /// messages
+    pub fn clear_access_control(&mut self, bus_id: B) -> Result<(), Error<B::Address>> {
+        self.senders.endpoint(bus_id)?;
+        self.acls.remove(&bus_id);
+        Ok(())
+    }
+
+    /// Registers callback receiving raw data of each message received from
+    /// the service bus, exactly as it came from the wire, together with the
+    /// message source and destination
+    #[cfg(feature = "wire-trace")]
+    pub fn on_raw_recv(
+        &mut self,
+        bus_id: B,
+        tap: impl FnMut(&B::Address, &B::Address, &[u8]) + Send + 'static,
+    ) -> Result<(), Error<B::Address>> {
+        self.senders.endpoint_mut(bus_id)?.on_raw_recv = Some(Box::new(tap));
+        Ok(())
+    }
+
+    /// Blocks until the bus socket establishes connection to the remote
+    /// endpoint, or until `timeout` elapses. Returns whether the connection
+    /// is established; buses which bind their socket are always considered
+    /// connected.
+    ///
+    /// Since some socket types silently drop messages sent before the
+    /// connection is established, this allows deterministic startup ordering.
+    /// Connection monitoring is not available for buses created from
+    /// externally provided sockets, which results in
+    /// [`Error::MonitorUnavailable`].
+    pub fn wait_connected(
+        &mut self,
+        bus_id: B,
+        timeout: Duration,
+    ) -> Result<bool, Error<B::Address>> {
+        let endpoint = self.senders.endpoint_mut(bus_id)?;
+        endpoint.drain_monitor(0)?;
+        if endpoint.connected {
+            return Ok(true);
+        }
+        if endpoint.monitor.is_none() {
+            return Err(Error::MonitorUnavailable(bus_id.to_string()));
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !endpoint.drain_monitor(remaining.as_millis() as i64)? {
+                debug!("Service bus {} is not connected after {:?}", bus_id, timeout);
+                return Ok(false);
+            }
+            if endpoint.connected {
+                debug!("Service bus {} is connected", bus_id);
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Returns recent connection lifecycle events of the service bus (at most
+    /// [`super::CONN_EVENT_LOG_LEN`] of them, oldest first), helping to find
+    /// out whether the underlying transport has flapped. Events are tracked
+    /// only for the buses connecting to a remote endpoint; for the other
+    /// buses the log is empty.
+    pub fn recent_events(&mut self, bus_id: B) -> Result<&[ConnEvent], Error<B::Address>> {
+        let endpoint = self.senders.endpoint_mut(bus_id)?;
+        endpoint.drain_monitor(0)?;
+        Ok(&endpoint.events)
+    }
+
+    /// Adds route on the service bus: messages which are not sent via the bus
+    /// router and have destination address with the first `levels` equal to
+    /// `prefix` (see [`ServiceAddress::route_prefix`]) are sent to `next_hop`
+    pub fn add_route(
+        &mut self,
+        bus_id: B,
+        prefix: B::Address,
+        levels: usize,
+        next_hop: B::Address,
+    ) -> Result<(), Error<B::Address>> {
+        self.senders.endpoint_mut(bus_id)?.routes.insert(prefix, levels, next_hop);
+        Ok(())
+    }
+
+    /// Removes route previously added with [`Controller::add_route`]
+    pub fn remove_route(
+        &mut self,
+        bus_id: B,
+        prefix: &B::Address,
+        levels: usize,
+    ) -> Result<(), Error<B::Address>> {
+        self.senders.endpoint_mut(bus_id)?.routes.remove(prefix, levels);
+        Ok(())
+    }
+
+    /// Resends messages sent to `dest` over the service bus which have
+    /// sequence numbers in the `missing` range and are still kept in the
+    /// retransmission buffer (see [`BusConfig::retransmit_buffer`]). Returns
+    /// number of the resent messages, which may be less than requested if
+    /// some of them have already left the buffer.
+    pub fn retransmit(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        missing: Range<u64>,
+    ) -> Result<usize, Error<B::Address>> {
+        self.senders.endpoint_mut(bus_id)?.retransmit(dest, missing)
+    }
+
+    /// Pauses the service bus: it is excluded from polling, so incoming
+    /// messages accumulate in ZMQ queues, and sending to it fails with
+    /// [`Error::BusPaused`]. Unlike removing the bus, its socket and buffered
+    /// messages are kept, so the bus can be temporarily quiesced, for
+    /// instance while a downstream service restarts.
+    pub fn pause_bus(&mut self, bus_id: B) -> Result<(), Error<B::Address>> {
+        debug!("Pausing service bus {}", bus_id);
+        self.senders.endpoint_mut(bus_id)?.paused = true;
+        Ok(())
+    }
+
+    /// Resumes service bus paused with [`Controller::pause_bus`]; the messages
+    /// accumulated while it was paused are processed by the next polls
+    pub fn resume_bus(&mut self, bus_id: B) -> Result<(), Error<B::Address>> {
+        debug!("Resuming service bus {}", bus_id);
+        self.senders.endpoint_mut(bus_id)?.paused = false;
+        Ok(())
+    }
+
+    /// Disconnects `peer` from the `bus_id` service bus, optionally sending it
+    /// a final `notice` request first.
+    ///
+    /// ZMQ sockets can't close a connection initiated by the remote side;
+    /// thus "disconnect" means that the controller stops exchanging messages
+    /// with the peer: further messages from it are dropped, sends to it fail
+    /// with [`Error::PeerDisconnected`], routes via it are removed and all
+    /// state kept for it (sequence numbers, circuit breaker etc) is
+    /// forgotten. The peer may be admitted back with
+    /// [`Controller::readmit_peer`].
+    pub fn disconnect_peer(
+        &mut self,
+        bus_id: B,
+        peer: B::Address,
+        notice: Option<R>,
+    ) -> Result<(), Error<B::Address>> {
+        if let Some(notice) = notice {
+            if let Err(err) = self.send_to(bus_id, peer.clone(), notice) {
+                warn!("Unable to notify {} about disconnection: {}", peer, err);
+            }
+        }
+        self.senders.endpoint_mut(bus_id)?.evict(peer);
+        Ok(())
+    }
+
+    /// Admits back `peer` disconnected with [`Controller::disconnect_peer`].
+    /// Returns `false` if the peer was not disconnected.
+    pub fn readmit_peer(
+        &mut self,
+        bus_id: B,
+        peer: &B::Address,
+    ) -> Result<bool, Error<B::Address>> {
+        Ok(self.senders.endpoint_mut(bus_id)?.evicted.remove(peer))
+    }
+
+    /// Returns endpoint the `bus_id` service bus socket was last bound or
+    /// connected to (`ZMQ_LAST_ENDPOINT`), or `None` if there is no such
+    /// endpoint. For buses bound to an ephemeral port (like `tcp://*:0`) the
+    /// endpoint contains the port assigned by the OS, so the service may
+    /// advertise it.
+    pub fn bound_endpoint(&self, bus_id: B) -> Result<Option<String>, Error<B::Address>> {
+        let endpoint = self.senders.endpoint(bus_id)?.session.as_socket().get_last_endpoint()?;
+        Ok(endpoint.ok().filter(|endpoint| !endpoint.is_empty()))
+    }
+
+    /// Checks whether the remote endpoint of the `bus_id` service bus knows
+    /// identity of this service and thus is able to route messages to it.
+    /// ROUTER socket learns identity of the connected service only once it
+    /// sends a message (or a probe, see [`BusConfig::probe_router`]); thus the
+    /// service is announced after it has sent at least one message since the
+    /// last connection. Services bound to the bus socket are always
+    /// announced.
+    ///
+    /// Helps to diagnose services which send messages fine but never receive
+    /// replies.
+    pub fn is_announced(&self, bus_id: B) -> Result<bool, Error<B::Address>> {
+        Ok(self.senders.endpoint(bus_id)?.announced)
+    }
+
+    /// Measures round-trip latency to `dest` over the service bus by sending
+    /// `samples` probe messages one after another and waiting for the reply
+    /// to each of them at most 5 seconds.
+    ///
+    /// Probes are answered by the controller of the destination service
+    /// automatically, without involving its handler, and are forwarded by
+    /// the bus routers; the destination must be served by a controller
+    /// supporting the probes. Probes are sent without message header and
+    /// integrity protection and use reserved message type `0xFFFF`. Messages
+    /// received while waiting for the replies are kept and processed later
+    /// as usual.
+    ///
+    /// Probes must be enabled with [`BusConfig::latency_probes`] on the bus
+    /// by all the services involved; otherwise [`Error::ProbesDisabled`] is
+    /// returned.
+    pub fn ping_rtt(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        samples: usize,
+    ) -> Result<RttStats, Error<B::Address>> {
+        self.establish_bus(bus_id)?;
+        let endpoint = self.senders.endpoint_mut(bus_id)?;
+        if !endpoint.latency_probes {
+            return Err(Error::ProbesDisabled(bus_id.to_string()));
+        }
+        let rtts =
+            (0..samples).map(|_| endpoint.ping(dest.clone())).collect::<Result<Vec<_>, _>>()?;
+        Ok(RttStats::with(rtts))
+    }
+
+    /// Returns role of this service on the service bus: whether it acts as
+    /// the bus router (hub), sends messages via a router (leaf) or the bus
+    /// has no router at all
+    pub fn bus_role(&self, bus_id: B) -> Result<BusRole, Error<B::Address>> {
+        Ok(self.senders.endpoint(bus_id)?.role)
+    }
+
+    /// Returns address of the router through which messages are sent over the
+    /// service bus. Returns `None` if the bus has no router, if this service
+    /// is the router itself (so messages are sent directly), or if there is
+    /// no bus with the given id.
+    pub fn bus_router(&self, bus_id: B) -> Option<B::Address> {
+        self.senders.endpoint(bus_id).ok()?.router.clone()
+    }
+
+    /// Checks whether `request` will be split into fragments when sent over
+    /// the `bus_id` service bus (see [`BusConfig::fragmentation`]). The check
+    /// relies on [`Request::serialized_size`] and does not account message
+    /// header and integrity protection overhead.
+    pub fn will_fragment(&self, bus_id: B, request: &R) -> Result<bool, Error<B::Address>> {
+        Ok(match self.senders.endpoint(bus_id)?.fragmentation {
+            Some(ref config) => request.serialized_size() > config.threshold,
+            None => false,
+        })
+    }
+
+    /// Returns histograms of sizes of the serialized requests sent to and
+    /// received from the service bus, helping to spot buses carrying
+    /// unexpectedly large messages and to size high water marks
+    pub fn size_histogram(&self, bus_id: B) -> Result<MessageSizes, Error<B::Address>> {
+        Ok(self.senders.endpoint(bus_id)?.sizes)
+    }
+
+    /// Returns histogram of the time spent by the handler processing requests
+    /// of `request_type` (see
+    /// [`internet2::presentation::TypedEnum::get_type`]) in the controller run
+    /// loop, allowing to distinguish slow handlers from slow transport
+    pub fn handle_latency(&self, request_type: u16) -> Option<LatencyHistogram> {
+        self.handle_latency.get(&request_type).copied()
+    }
+
+    /// Returns message counters for each of the service buses
+    pub fn bus_stats(&self) -> HashMap<B, BusStats> {
+        self.senders.0.iter().map(|(bus_id, endpoint)| (*bus_id, endpoint.stats)).collect()
+    }
+
+    /// Renders message counters of all service buses (see
+    /// [`Controller::bus_stats`]) in Prometheus text exposition format, with
+    /// `bus` label containing the bus id, followed by the histograms of the
+    /// request processing time (see [`Controller::handle_latency`]) with
+    /// `type` label containing the request type. The output may be served
+    /// as-is from a `/metrics` HTTP endpoint.
+    #[cfg(feature = "prometheus")]
+    pub fn metrics_prometheus(&self) -> String {
+        let mut out = super::prometheus::render(&self.bus_stats());
+        out += &super::prometheus::render_latency(&self.handle_latency);
+        out
+    }
+
+    pub fn send_to(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        request: R,
+    ) -> Result<(), Error<B::Address>> {
+        self.establish_bus(bus_id)?;
+        let source = self.senders.identity(bus_id)?;
+        self.senders.send_to(bus_id, source, dest, request)
+    }
+
+    /// Returns state of the circuit breaker for the destination `dest` on the
+    /// `bus_id` service bus (see [`BusConfig::circuit_breaker`])
+    pub fn circuit_state(
+        &self,
+        bus_id: B,
+        dest: &B::Address,
+    ) -> Result<CircuitState, Error<B::Address>> {
+        Ok(self.senders.endpoint(bus_id)?.circuit_state(dest))
+    }
+
+    /// Sends multiple requests to `dest` as a single ZMQ multipart message, so
+    /// they are delivered as an indivisible unit, not interleaved with the
+    /// messages of other senders. The receiving controller returns all
+    /// requests of the batch from a single [`Controller::recv_poll`] call and
+    /// dispatches them within the same run loop iteration.
+    ///
+    /// Each request is encoded as configured for the bus (with message
+    /// header, integrity protection etc), but duplicate suppression is not
+    /// applied. Routing controllers forward batched requests one by one.
+    pub fn send_batch(
+        &mut self,
+        bus_id: B,
+        dest: B::Address,
+        requests: &[R],
+    ) -> Result<(), Error<B::Address>> {
+        self.establish_bus(bus_id)?;
+        let source = self.senders.identity(bus_id)?;
+        self.senders.send_batch(bus_id, source, dest, requests)
+    }
+
+    /// Registers human-readable `name` for the service address `addr`, which
+    /// can be used with [`Controller::send_to_alias`]. Returns address which
+    /// was previously registered under the same name, if any.
+    pub fn register_alias(&mut self, name: &str, addr: B::Address) -> Option<B::Address> {
+        self.aliases.insert(name.to_owned(), addr)
+    }
+
+    /// Removes alias registered with [`Controller::register_alias`]
+    pub fn unregister_alias(&mut self, name: &str) -> Option<B::Address> {
+        self.aliases.remove(name)
+    }
+
+    /// Returns service address registered under the alias `name`
+    pub fn resolve_alias(&self, name: &str) -> Result<B::Address, Error<B::Address>> {
+        self.aliases.get(name).cloned().ok_or_else(|| Error::UnknownAlias(name.to_owned()))
+    }
+
+    /// Sends request to the service known under the alias `name` (see
+    /// [`Controller::register_alias`])
+    pub fn send_to_alias(
+        &mut self,
+        bus_id: B,
+        name: &str,
+        request: R,
+    ) -> Result<(), Error<B::Address>> {
+        let dest = self.resolve_alias(name)?;
+        self.send_to(bus_id, dest, request)
+    }
+
+    /// Polls service buses and receives messages queued on them, returning
+    /// message bus, source and decoded request. In debug builds panics if
+    /// messages from a source on a sequenced bus are returned out of order.
+    pub fn recv_poll(&mut self) -> Result<Vec<(B, B::Address, H::Request)>, Error<B::Address>> {
+        Ok(self
+            .recv_poll_routed()?
+            .into_iter()
+            .map(|(bus_id, source, _, request)| (bus_id, source, request))
+            .collect())
+    }
+
+    /// Runs at most `n` iterations of the controller loop, each polling the
+    /// service buses and dispatching or routing all messages received, like
+    /// [`TryService::try_run_loop`] does. Stops earlier once there are no
+    /// messages queued on any of the buses, without waiting for new ones.
+    /// Returns number of the iterations actually run.
+    ///
+    /// Unlike the run loop, errors are returned to the caller rather than
+    /// passed to [`Handler::handle_err`], and [`Handler::on_ready`] is not
+    /// called. This allows deterministic control over the controller in tests
+    /// and interleaving it with other work by an external scheduler.
+    #[cfg(feature = "node")]
+    pub fn run_n(&mut self, n: usize) -> Result<usize, Error<B::Address>> {
+        for iteration in 0..n {
+            if !self.run_batch(0)? {
+                return Ok(iteration);
+            }
+        }
+        Ok(n)
+    }
+
+    /// Keeps polling service buses and dispatching or routing received
+    /// messages, like [`Controller::run_n`], until no message arrives for
+    /// `quiet_for` or `max_wait` elapses. Returns whether the buses became
+    /// quiet before the deadline.
+    ///
+    /// Intended for integration tests which need to wait until all in-flight
+    /// messages have settled before making assertions.
+    #[cfg(feature = "node")]
+    pub fn drain_until_idle(
+        &mut self,
+        quiet_for: Duration,
+        max_wait: Duration,
+    ) -> Result<bool, Error<B::Address>> {
+        let start = Instant::now();
+        let deadline = start + max_wait;
+        let mut last_activity = start;
+        loop {
+            let now = Instant::now();
+            if now.duration_since(last_activity) >= quiet_for {
+                return Ok(true);
+            }
+            if now >= deadline {
+                debug!("Service buses are not idle after {:?}", max_wait);
+                return Ok(false);
+            }
+            let wait = (last_activity + quiet_for).min(deadline).saturating_duration_since(now);
+            if self.run_batch(wait.as_millis() as i64)? {
+                last_activity = Instant::now();
+            }
+        }
+    }
+
+    /// Polls service buses like [`Self::recv_poll`], but keeps reading
+    /// messages from the buses which are ready until their cumulative size
+    /// reaches `max_bytes`; the rest of the messages remain queued for the
+    /// next poll. Blocks only until the first messages arrive.
+    ///
+    /// Returns received messages and the part of the budget which was not
+    /// used. Since messages are read whole, the budget may be exceeded by the
+    /// last message read, in which case the remaining budget is zero.
+    pub fn recv_poll_budget(
+        &mut self,
+        max_bytes: usize,
+    ) -> Result<(Vec<(B, B::Address, H::Request)>, usize), Error<B::Address>> {
+        let mut remaining = max_bytes;
+        let mut vec = vec![];
+        let mut ready = self.poll(-1)?;
+        while !ready.is_empty() {
+            for bus_id in ready {
+                if remaining == 0 {
+                    return Ok((vec, remaining));
+                }
+                self.pet_watchdog();
+                let before = self.senders.endpoint(bus_id)?.stats.received_bytes;
+                let received = self.recv_routed(bus_id)?;
+                let used = self.senders.endpoint(bus_id)?.stats.received_bytes - before;
+                remaining = remaining.saturating_sub(used as usize);
+                if let Some((source, _, request, _)) = received {
+                    vec.push((bus_id, source, request));
+                }
+            }
+            let buses = self
+                .bus_order
+                .iter()
+                .copied()
+                .filter(|bus_id| !self.senders.0[bus_id].paused)
+                .collect::<Vec<_>>();
+            ready = self.poll_buses(&buses, 0)?.into_iter().map(|i| buses[i]).collect();
+        }
+        Ok((vec, remaining))
+    }
+
+    /// Takes all messages currently queued on all service buses without
+    /// blocking, returning their bus, source and raw data. Messages are
+    /// neither decoded nor dispatched to the handler, so the method may be
+    /// used for capturing messages in flight for debugging or for migrating
+    /// queued work to another process on a controlled shutdown.
+    ///
+    /// Messages which were already received from the socket but not decoded
+    /// yet are included before the ones still queued on the socket. Requests
+    /// read ahead (see [`Controller::peek_ahead`]) are already decoded, so
+    /// their raw data are not available and they are NOT included: they stay
+    /// queued for dispatching and must be taken with
+    /// [`Controller::take_ahead`] before draining if they have to be
+    /// migrated as well.
+    pub fn drain_raw(&mut self) -> Result<Vec<(B, B::Address, Vec<u8>)>, Error<B::Address>> {
+        let mut vec = vec![];
+        if !is_receivable(&self.api_type) {
+            return Ok(vec);
+        }
+        for bus_id in self.bus_order.clone() {
+            let frames = self.senders.endpoint_mut(bus_id)?.drain_raw()?;
+            vec.extend(frames.into_iter().map(|(source, data)| (bus_id, source, data)));
+        }
+        Ok(vec)
+    }
+
+    /// Reads up to `n` messages (but no more than [`MAX_LOOKAHEAD`]) from the
+    /// service bus without dispatching them, returning all requests read
+    /// ahead so far. Only the messages already queued on the bus are read,
+    /// so fewer than `n` requests may be returned. Allows inspecting upcoming
+    /// requests, for instance to coalesce duplicate updates with
+    /// [`Controller::take_ahead`] or [`Controller::retain_ahead`].
+    ///
+    /// Requests read ahead are dispatched before any other messages from the
+    /// same bus, preserving their order, but messages from other buses may be
+    /// dispatched before them. Header checks, like integrity and sequence
+    /// gap detection, are done once the message is read ahead rather than
+    /// when it is dispatched.
+    pub fn peek_ahead(&mut self, bus_id: B, n: usize) -> Result<&[R], Error<B::Address>> {
+        self.establish_bus(bus_id)?;
+        let n = n.min(MAX_LOOKAHEAD);
+        while self.lookahead.get(&bus_id).map(|ahead| ahead.requests.len()).unwrap_or_default() < n
+        {
+            let endpoint = self.senders.endpoint(bus_id)?;
+            let queued = !endpoint.batch.is_empty()
+                || endpoint.session.as_socket().poll(zmq::POLLIN, 0)? > 0;
+            if !queued {
+                break;
+            }
+            if let Some((source, dest, request, header)) = self.read_routed(bus_id)? {
+                let ahead = self.lookahead.entry(bus_id).or_default();
+                ahead.requests.push(request);
+                ahead.routing.push((source, dest, header));
+            }
+        }
+        Ok(self.lookahead.get(&bus_id).map(|ahead| ahead.requests.as_slice()).unwrap_or(&[]))
+    }
+
+    /// Removes first `n` requests read ahead from the service bus with
+    /// [`Controller::peek_ahead`], returning them together with their source
+    /// addresses. Removed requests are not dispatched.
+    pub fn take_ahead(&mut self, bus_id: B, n: usize) -> Vec<(B::Address, R)> {
+        let ahead = match self.lookahead.get_mut(&bus_id) {
+            Some(ahead) => ahead,
+            None => return vec![],
+        };
+        let n = n.min(ahead.requests.len());
+        ahead.routing.drain(..n).map(|(source, ..)| source).zip(ahead.requests.drain(..n)).collect()
+    }
+
+    /// Keeps only the requests read ahead from the service bus with
+    /// [`Controller::peek_ahead`] for which `f` returns `true`; the rest are
+    /// not dispatched. Returns number of the removed requests.
+    pub fn retain_ahead(&mut self, bus_id: B, mut f: impl FnMut(&R) -> bool) -> usize {
+        let ahead = match self.lookahead.get_mut(&bus_id) {
+            Some(ahead) => ahead,
+            None => return 0,
+        };
+        let len = ahead.requests.len();
+        let (requests, routing) = ahead
+            .requests
+            .drain(..)
+            .zip(ahead.routing.drain(..))
+            .filter(|(request, _)| f(request))
+            .unzip();
+        ahead.requests = requests;
+        ahead.routing = routing;
+        len - ahead.requests.len()
+    }
+
+    /// Feeds recorded envelopes into the handler as if they were received from
+    /// the `bus_id` service bus, without polling any sockets. Envelopes
+    /// addressed to other services are skipped, since replay never routes
+    /// messages. Returns number of envelopes dispatched to the handler.
+    ///
+    /// This allows to deterministically reproduce handler behaviour from a
+    /// captured message trace.
+    pub fn replay(
+        &mut self,
+        bus_id: B,
+        envelopes: impl IntoIterator<Item = Envelope<B::Address>>,
+    ) -> Result<usize, Error<B::Address>> {
+        let identity = self.senders.identity(bus_id)?;
+        let mut count = 0usize;
+        for envelope in envelopes {
+            let request = self.decode(&envelope.payload)?;
+            if envelope.dest != identity {
+                trace!(
+                    "Skipping replay of {} from {} to {}: not addressed to us",
+                    request,
+                    envelope.source,
+                    envelope.dest
+                );
+                continue;
+            }
+            debug!("Replaying {} -> {}: {}", envelope.source, envelope.dest, request);
+            self.handler.handle(&mut self.senders, bus_id, envelope.source, request)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Polls all service buses and returns received messages together with
+    /// both their source and destination addresses. Unlike [`Self::run`],
+    /// messages are not dispatched or routed, so the caller (for instance a
+    /// transparent proxy) may decide whether the message is addressed to it or
+    /// must be forwarded.
+    pub fn recv_poll_routed(
+        &mut self,
+    ) -> Result<Vec<(B, B::Address, B::Address, H::Request)>, Error<B::Address>> {
+        let mut vec = vec![];
+        for bus_id in self.poll(-1)? {
+            self.pet_watchdog();
+            for (source, dest, request, header) in self.recv_routed_batch(bus_id)? {
+                // Debug builds check that messages from each source are
+                // returned in the order they were sent
+                #[cfg(debug_assertions)]
+                if let Some(seq) = header
+                    .as_ref()
+                    .filter(|header| !header.retransmitted)
+                    .and_then(|header| header.seq)
+                {
+                    self.senders.endpoint_mut(bus_id)?.assert_fifo(&source, seq);
+                }
+                #[cfg(not(debug_assertions))]
+                let _ = header;
+                vec.push((bus_id, source, dest, request));
+            }
+        }
+
+        Ok(vec)
+    }
+
+    /// Receives single message consisting of multiple frames from the service
+    /// bus, returning its source, destination and all payload frames with
+    /// their boundaries preserved. Blocks until a message arrives; the message
+    /// is not dispatched to the handler.
+    pub fn recv_multipart(
+        &mut self,
+        bus_id: B,
+    ) -> Result<(B::Address, B::Address, Vec<Vec<u8>>), Error<B::Address>> {
+        if !is_receivable(&self.api_type) {
+            return Err(Error::BusNotReceivable(bus_id.to_string()));
+        }
+        self.establish_bus(bus_id)?;
+        self.senders.endpoint_mut(bus_id)?.recv_multipart()
+    }
+
+    /// Polls only the specified service buses, leaving messages on the other
+    /// buses queued in ZMQ to be read later, and returns received messages
+    /// with their source and destination addresses. Messages are not
+    /// dispatched or routed.
+    ///
+    /// Useful for phased protocols which need to temporarily focus on some
+    /// buses (for instance during a synchronous handshake) without
+    /// decommissioning the others. If `timeout` is `None`, waits until some
+    /// message arrives; otherwise may return empty list when the timeout
+    /// elapses. Paused buses (see [`Controller::pause_bus`]) are skipped.
+    pub fn poll_only(
+        &mut self,
+        buses: &[B],
+        timeout: Option<Duration>,
+    ) -> Result<Vec<(B, B::Address, B::Address, H::Request)>, Error<B::Address>> {
+        for bus_id in buses {
+            self.establish_bus(*bus_id)?;
+            self.senders.endpoint(*bus_id)?;
+            if !is_receivable(&self.api_type) {
+                return Err(Error::BusNotReceivable(bus_id.to_string()));
+            }
+        }
+        let buses = buses
+            .iter()
+            .copied()
+            .filter(|bus_id| !self.senders.0[bus_id].paused)
             .collect::<Vec<_>>();
+        let buses = buses.as_slice();
+        let timeout = timeout.map(|t| t.as_millis() as i64).unwrap_or(-1);
+        let mut vec = vec![];
+        for i in self.poll_buses(buses, timeout)? {
+            let bus_id = buses[i];
+            for (source, dest, request, _) in self.recv_routed_batch(bus_id)? {
+                vec.push((bus_id, source, dest, request));
+            }
+        }
+        Ok(vec)
+    }
+}
+
+#[cfg(feature = "node")]
+impl<B, R, H> TryService for Controller<B, R, H>
+where
+    R: Request,
+    B: BusId,
+    H: Handler<B, Request = R>,
+    Error<B::Address>: From<H::Error>,
+{
+    type ErrorType = Error<B::Address>;
+    type ShutdownReport = ShutdownReport<B>;
+
+    fn shutdown_signal(&self) -> Option<ShutdownSignal> { Some(self.shutdown_signal.clone()) }
+
+    fn try_run_loop(mut self) -> Result<Self::ShutdownReport, Self::ErrorType> {
+        let start = Instant::now();
+        // Otherwise each iteration of the loop would fail right away
+        self.check_receivable()?;
+        self.init()?;
+        while !self.shutdown_signal.is_triggered() {
+            match self.run() {
+                Ok(_) => trace!("request processing complete"),
+                Err(err) => {
+                    error!("ESB request processing error: {}", err);
+                    self.handler.handle_err(&mut self.senders, err)?;
+                }
+            }
+        }
+        let buses = self.bus_stats();
+        Ok(ShutdownReport {
+            reason: ShutdownReason::Requested,
+            uptime: start.elapsed(),
+            processed: self.dispatched,
+            buses,
+        })
+    }
+}
+
+impl<B, R, H> Controller<B, R, H>
+where
+    R: Request,
+    B: BusId,
+    H: Handler<B, Request = R>,
+    Error<B::Address>: From<H::Error>,
+{
+    #[cfg(feature = "node")]
+    fn run(&mut self) -> Result<(), Error<B::Address>> { self.run_batch(-1).map(|_| ()) }
+
+    /// Calls [`Handler::on_ready`] enforcing the initialization timeout (see
+    /// [`crate::esb::ControllerConfig::init_timeout`]) as a single deadline
+    /// over the whole initialization
+    #[cfg(feature = "node")]
+    fn init(&mut self) -> Result<(), Error<B::Address>> {
+        let timeout = match self.init_timeout {
+            None => return Ok(self.handler.on_ready(&mut self.senders)?),
+            Some(timeout) => timeout,
+        };
+        let deadline = Instant::now() + timeout;
+        let mut timeouts = vec![];
+        for (bus_id, endpoint) in &mut self.senders.0 {
+            let socket = endpoint.session.as_socket();
+            timeouts.push((*bus_id, socket.get_rcvtimeo()?, socket.get_sndtimeo()?));
+            endpoint.init_deadline = Some((deadline, timeout));
+        }
+        let res = self.handler.on_ready(&mut self.senders);
+        for (bus_id, rcvtimeo, sndtimeo) in timeouts {
+            // The handler may have removed some of the buses
+            if let Some(endpoint) = self.senders.0.get_mut(&bus_id) {
+                endpoint.init_deadline = None;
+                let socket = endpoint.session.as_socket();
+                socket.set_rcvtimeo(rcvtimeo)?;
+                socket.set_sndtimeo(sndtimeo)?;
+            }
+        }
+        let err = match res {
+            Ok(()) => return Ok(()),
+            Err(err) => Error::from(err),
+        };
+        // Bus operations time out once the time limit is exhausted
+        let timed_out = match err {
+            Error::InitTimeout(_) => true,
+            Error::Send(_, _, transport::Error::Zmq(zmq::Error::EAGAIN))
+            | Error::Transport(transport::Error::Zmq(zmq::Error::EAGAIN)) => {
+                Instant::now() >= deadline
+            }
+            _ => false,
+        };
+        if timed_out {
+            error!("Handler initialization has not completed within {:?}", timeout);
+            return Err(Error::InitTimeout(timeout));
+        }
+        Err(err)
+    }
+
+    /// Polls service buses waiting at most `timeout` milliseconds (`-1` for
+    /// no limit), and dispatches or routes all received messages. Returns
+    /// whether any of the buses were ready for reading.
+    #[cfg(feature = "node")]
+    fn run_batch(&mut self, timeout: i64) -> Result<bool, Error<B::Address>> {
+        let ready = self.poll(timeout)?;
+        self.process_worker_commands()?;
+        if ready.is_empty() {
+            return Ok(false);
+        }
+        let mut batch = vec![];
+        for bus_id in ready {
+            self.pet_watchdog();
+            match self.recv_routed_batch(bus_id) {
+                Ok(messages) => {
+                    batch.extend(messages.into_iter().map(|(source, dest, request, header)| {
+                        (bus_id, source, dest, request, header, Instant::now())
+                    }))
+                }
+                Err(err) => {
+                    self.restore_ahead(batch.into_iter().map(
+                        |(bus_id, source, dest, request, header, _)| {
+                            (bus_id, source, dest, request, header)
+                        },
+                    ));
+                    return Err(err);
+                }
+            }
+        }
+        // Messages with higher priority are processed first; the sort is
+        // stable, so the order of messages with the same priority is kept
+        batch.sort_by_key(|(.., header, _)| {
+            Reverse(header.as_ref().map(|header| header.priority).unwrap_or_default())
+        });
+
+        let mut batch = batch.into_iter();
+        while let Some((bus_id, source, dest, request, header, received)) = batch.next() {
+            self.pet_watchdog();
+            if let Err(err) = self.dispatch_routed(bus_id, source, dest, request, header, received)
+            {
+                // Messages already read from the buses must not be lost
+                self.restore_ahead(batch.map(|(bus_id, source, dest, request, header, _)| {
+                    (bus_id, source, dest, request, header)
+                }));
+                return Err(err);
+            }
+        }
+        self.report_dropped()?;
+
+        Ok(true)
+    }
+
+    /// Dispatches single message received from the service bus to the
+    /// handler (or worker threads), or routes it if this node is not its
+    /// destination
+    #[cfg(feature = "node")]
+    fn dispatch_routed(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        dest: B::Address,
+        request: R,
+        mut header: Option<Header>,
+        received: Instant,
+    ) -> Result<(), Error<B::Address>> {
+        let permitted = self.acls.get(&bus_id).map(|acl| acl(&source, &request)).unwrap_or(true);
+        if !permitted {
+            debug!("Access denied for {} from {} on bus {}", request, source, bus_id);
+            self.handler.on_access_denied(&mut self.senders, bus_id, source, request)?;
+            return Ok(());
+        }
+        if dest == self.senders.endpoint(bus_id)?.identity {
+            // We are the destination
+            debug!("{} -> {}: {}", source, dest, request);
+            let reply_to =
+                header.as_ref().and_then(|header| header.reply_to.clone()).map(B::Address::from);
+
+            if let Some(key) = header.as_ref().and_then(|header| header.idempotency_key.as_ref()) {
+                if !self.senders.endpoint_mut(bus_id)?.register_key(&source, key) {
+                    debug!("Skipping repeated {} from {} on bus {}", request, source, bus_id);
+                    let response = self.handler.cached_response(
+                        &mut self.senders,
+                        bus_id,
+                        source.clone(),
+                        key,
+                    )?;
+                    if let Some(response) = response {
+                        let identity = self.senders.identity(bus_id)?;
+                        let dest = reply_to.unwrap_or(source);
+                        self.senders.send_to(bus_id, identity, dest, response)?;
+                    }
+                    return Ok(());
+                }
+            }
+            self.dispatched += 1;
+            if let Some(ref workers) = self.workers {
+                workers.dispatch(bus_id, source, request)?;
+                return Ok(());
+            }
+            let endpoint = self.senders.endpoint_mut(bus_id)?;
+            endpoint.deadline = header
+                .as_ref()
+                .and_then(|header| header.deadline)
+                .map(|deadline| received + Duration::from_millis(deadline));
+            endpoint.reply_to = reply_to;
+            let request_type = u16::from(request.get_type());
+            let start = Instant::now();
+            let res = if self.catch_panics {
+                self.handle_isolated(bus_id, source, request)
+            } else {
+                self.handler.handle(&mut self.senders, bus_id, source, request).map_err(Error::from)
+            };
+            self.handle_latency.entry(request_type).or_default().record(start.elapsed());
+            let endpoint = self.senders.endpoint_mut(bus_id)?;
+            endpoint.deadline = None;
+            endpoint.reply_to = None;
+            res
+        } else {
+            // Need to route
+            let spent = received.elapsed();
+            let expired = header
+                .as_mut()
+                .map(|header| header.is_expired() || !header.spend_deadline(spent))
+                .unwrap_or_default();
+            if expired {
+                debug!("Dropping expired {} from {} to {}", request, source, dest);
+                self.handler.on_undeliverable(&mut self.senders, bus_id, source, dest, request)?;
+                return Ok(());
+            }
+            trace!("Routing {} from {} to {}", request, source, dest);
+            self.senders.endpoint_mut(bus_id)?.send_to(source, dest, request, header)
+        }
+    }
+
+    /// Puts messages which were received from the service buses, but not
+    /// dispatched, back in front of the messages read ahead from their buses,
+    /// so they are dispatched first by the next run
+    fn restore_ahead(
+        &mut self,
+        messages: impl IntoIterator<Item = (B, B::Address, B::Address, R, Option<Header>)>,
+    ) {
+        let mut restored = HashMap::<B, Lookahead<B::Address, R>>::new();
+        for (bus_id, source, dest, request, header) in messages {
+            let ahead = restored.entry(bus_id).or_default();
+            ahead.requests.push(request);
+            ahead.routing.push((source, dest, header));
+        }
+        for (bus_id, mut ahead) in restored {
+            let queued = self.lookahead.entry(bus_id).or_default();
+            ahead.requests.append(&mut queued.requests);
+            ahead.routing.append(&mut queued.routing);
+            *queued = ahead;
+        }
+    }
+
+    /// Sends requests passed by the worker threads and reports their errors to
+    /// the handler
+    #[cfg(feature = "node")]
+    fn process_worker_commands(&mut self) -> Result<(), Error<B::Address>> {
+        let commands = match self.workers {
+            Some(ref workers) => workers.commands().collect::<Vec<_>>(),
+            None => return Ok(()),
+        };
+        for command in commands {
+            match command {
+                Command::Send(bus_id, dest, request) => {
+                    let source = self.senders.identity(bus_id)?;
+                    self.senders.send_to(bus_id, source, dest, request)?;
+                }
+                Command::Failed(err) => {
+                    warn!("Request processing by ESB worker has failed: {}", err);
+                    self.handler.handle_err(&mut self.senders, Error::ServiceError(err))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports messages dropped at high water mark and detected slow consumers
+    /// to the handler
+    #[cfg(feature = "node")]
+    fn report_dropped(&mut self) -> Result<(), Error<B::Address>> {
+        let dropped = self
+            .senders
+            .0
+            .iter_mut()
+            .flat_map(|(bus_id, endpoint)| {
+                endpoint.dropped.drain(..).map(move |dest| (*bus_id, dest))
+            })
+            .collect::<Vec<_>>();
+        for (bus_id, dest) in dropped {
+            self.handler.on_message_dropped(&mut self.senders, bus_id, dest)?;
+        }
+        let slow = self
+            .senders
+            .0
+            .iter_mut()
+            .flat_map(|(bus_id, endpoint)| endpoint.slow.drain(..).map(move |dest| (*bus_id, dest)))
+            .collect::<Vec<_>>();
+        for (bus_id, dest) in slow {
+            warn!("{} on bus {} is a slow consumer", dest, bus_id);
+            self.handler.on_slow_consumer(&mut self.senders, bus_id, dest)?;
+        }
+        Ok(())
+    }
+
+    /// Dispatches request to the handler catching handler panics, which are
+    /// reported to [`Handler::on_handler_panic`]
+    #[cfg(feature = "node")]
+    fn handle_isolated(
+        &mut self,
+        bus_id: B,
+        source: B::Address,
+        request: R,
+    ) -> Result<(), Error<B::Address>> {
+        let handler = &mut self.handler;
+        let senders = &mut self.senders;
+        let (src, req) = (source.clone(), request.clone());
+        match panic::catch_unwind(AssertUnwindSafe(|| handler.handle(senders, bus_id, src, req))) {
+            Ok(res) => Ok(res?),
+            Err(payload) => {
+                let panic = payload
+                    .downcast_ref::<&str>()
+                    .map(|msg| msg.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| s!("unknown panic"));
+                error!(
+                    "Handler has panicked processing {} from {} on bus {}: {}; the request is \
+                     skipped",
+                    request, source, bus_id, panic
+                );
+                self.handler.on_handler_panic(&mut self.senders, bus_id, source, request, panic)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Fails with [`Error::BusNotReceivable`] if the controller uses
+    /// send-only sockets
+    fn check_receivable(&self) -> Result<(), Error<B::Address>> {
+        if is_receivable(&self.api_type) {
+            return Ok(());
+        }
+        let buses = self.bus_order.iter().map(B::to_string).collect::<Vec<_>>();
+        Err(Error::BusNotReceivable(buses.join(", ")))
+    }
+
+    /// Polls service buses in a round-robin order: each poll starts from the
+    /// bus following the first one serviced by the previous poll, so no bus is
+    /// systematically favored when several of them are always ready. Fails
+    /// with [`Error::BusNotReceivable`] for send-only API types, which would
+    /// otherwise wait forever.
+    fn poll(&mut self, timeout: i64) -> Result<Vec<B>, Error<B::Address>> {
+        self.check_receivable()?;
+        for endpoint in self.senders.0.values_mut() {
+            endpoint.drain_monitor(0)?;
+        }
+        let count = self.bus_order.len();
+        let start = if count == 0 { 0 } else { self.rr_cursor % count };
+        let index = self.bus_order[start..]
+            .iter()
+            .chain(&self.bus_order[..start])
+            .copied()
+            .enumerate()
+            .filter(|(_, bus_id)| !self.senders.0[bus_id].paused)
+            .collect::<Vec<_>>();
+        let buses = index.iter().map(|(_, bus_id)| *bus_id).collect::<Vec<_>>();
+
+        let ready = self.poll_buses(&buses, timeout)?;
+        if let Some(first) = ready.first() {
+            self.rr_cursor = (start + index[*first].0 + 1) % count;
+        }
+        Ok(ready.into_iter().map(|i| buses[i]).collect())
+    }
+
+    /// Polls given service buses, returning indexes of the buses ready for
+    /// reading. All buses must be known to the controller.
+    fn poll_buses(&self, index: &[B], timeout: i64) -> Result<Vec<usize>, Error<B::Address>> {
+        let mut items = index
+            .iter()
+            .map(|bus_id| {
+                self.senders
+                    .0
+                    .get(bus_id)
+                    .expect("polled buses are always known")
+                    .session
+                    .as_socket()
+                    .as_poll_item(zmq::POLLIN | zmq::POLLERR)
+            })
+            .collect::<Vec<_>>();
+        items.push(self.interrupt.as_poll_item(zmq::POLLIN));
+        // Buses with unprocessed messages of a received batch or messages
+        // read ahead are ready without polling
+        let batched = index
+            .iter()
+            .map(|bus_id| !self.senders.0[bus_id].batch.is_empty() || self.has_lookahead(*bus_id))
+            .collect::<Vec<_>>();
+        let timeout = if batched.contains(&true) { 0 } else { timeout };
+
+        trace!("Awaiting for ESB request from {} service buses...", index.len());
+        if let Some(ref watchdog) = self.watchdog {
+            watchdog.idle();
+        }
+        let _ = zmq::poll(&mut items, timeout)?;
+
+        let interrupted = items.pop().map(|item| item.is_readable()).unwrap_or_default();
+        if interrupted {
+            debug!("ESB poll is interrupted");
+            while self.interrupt.recv_bytes(zmq::DONTWAIT).is_ok() {}
+        }
+
+        let ready =
+            items
+                .iter()
+                .enumerate()
+                .filter_map(|(i, item)| {
+                    if item.get_revents().is_empty() && !batched[i] {
+                        None
+                    } else {
+                        Some(i)
+                    }
+                })
+                .collect::<Vec<_>>();
+
+        trace!("Received ESB request from {} service busses...", ready.len());
+
+        Ok(ready)
+    }
+
+    #[inline]
+    fn pet_watchdog(&self) {
+        if let Some(ref watchdog) = self.watchdog {
+            watchdog.pet();
+        }
+    }
+
+    /// Reads routed message from the service bus like
+    /// [`Controller::recv_routed`], together with the rest of the messages
+    /// from the batch it was received in (see [`Controller::send_batch`])
+    fn recv_routed_batch(
+        &mut self,
+        bus_id: B,
+    ) -> Result<Vec<(B::Address, B::Address, R, Option<Header>)>, Error<B::Address>> {
+        let mut vec = vec![];
+        loop {
+            match self.recv_routed(bus_id) {
+                Ok(received) => vec.extend(received),
+                Err(err) => {
+                    // Messages received before the failure must not be lost
+                    self.restore_ahead(vec.into_iter().map(|(source, dest, request, header)| {
+                        (bus_id, source, dest, request, header)
+                    }));
+                    return Err(err);
+                }
+            }
+            if self.senders.endpoint(bus_id)?.batch.is_empty() && !self.has_lookahead(bus_id) {
+                return Ok(vec);
+            }
+        }
+    }
+
+    /// Checks whether there are messages read ahead from the service bus
+    fn has_lookahead(&self, bus_id: B) -> bool {
+        self.lookahead.get(&bus_id).map(|ahead| !ahead.requests.is_empty()).unwrap_or_default()
+    }
+
+    /// Takes next routed message of the service bus, which is either the
+    /// first message read ahead (see [`Controller::peek_ahead`]) or a message
+    /// read from the bus socket with [`Controller::read_routed`]
+    fn recv_routed(
+        &mut self,
+        bus_id: B,
+    ) -> Result<Option<(B::Address, B::Address, R, Option<Header>)>, Error<B::Address>> {
+        if let Some(ahead) =
+            self.lookahead.get_mut(&bus_id).filter(|ahead| !ahead.requests.is_empty())
+        {
+            let request = ahead.requests.remove(0);
+            let (source, dest, header) = ahead.routing.remove(0);
+            return Ok(Some((source, dest, request, header)));
+        }
+        self.read_routed(bus_id)
+    }
+
+    /// Reads single routed message from the service bus returning its source,
+    /// destination and decoded request. Returns `None` if the message was
+    /// dropped, for instance because it has failed integrity check.
+    fn read_routed(
+        &mut self,
+        bus_id: B,
+    ) -> Result<Option<(B::Address, B::Address, R, Option<Header>)>, Error<B::Address>> {
+        let sender = self.senders.endpoint_mut(bus_id)?;
+
+        let (source, dest, data) = match sender.recv_routed_frame()? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        if sender.evicted.contains(&source) {
+            trace!("Dropping message from disconnected {} on bus {}", source, bus_id);
+            return Ok(None);
+        }
+        sender.stats.received += 1;
+        sender.stats.received_bytes += data.len() as u64;
+        #[cfg(feature = "wire-trace")]
+        if let Some(ref mut tap) = sender.on_raw_recv {
+            tap(&source, &dest, &data);
+        }
+        let integrity = sender.integrity;
+        let headers = sender.headers;
+
+        let msg = match sender.reassembler {
+            None => data,
+            Some(ref mut reassembler) => match reassembler.accept(&source, data) {
+                Ok(Some(msg)) => msg,
+                Ok(None) => return Ok(None),
+                Err(()) => {
+                    warn!(
+                        "Dropping malformed fragment from {} to {} on bus {}",
+                        source, dest, bus_id
+                    );
+                    self.handler.on_corrupt_frame(&mut self.senders, bus_id, source)?;
+                    return Ok(None);
+                }
+            },
+        };
+        let msg = match integrity {
+            None => msg,
+            Some(integrity) => match integrity.open(msg) {
+                Some(msg) => msg,
+                None => {
+                    warn!("Dropping corrupted frame from {} to {} on bus {}", source, dest, bus_id);
+                    self.handler.on_corrupt_frame(&mut self.senders, bus_id, source)?;
+                    return Ok(None);
+                }
+            },
+        };
+        let (header, msg) = if headers {
+            let version = Header::version(&msg)?;
+            let endpoint = self.senders.endpoint_mut(bus_id)?;
+            if version == 0 || version > endpoint.envelope_version {
+                warn!(
+                    "Dropping message from {} on bus {} with unsupported envelope version {}",
+                    source, bus_id, version
+                );
+                self.handler.on_version_mismatch(&mut self.senders, bus_id, source, version)?;
+                return Ok(None);
+            }
+            endpoint.peer_versions.insert(source.clone(), version);
+            let (header, msg) = Header::open(msg)?;
+            (Some(header), msg)
+        } else {
+            (None, msg)
+        };
+        #[cfg(feature = "compression")]
+        let msg = match self.senders.endpoint(bus_id)?.decompression_limit() {
+            None => msg,
+            Some(max_size) => match super::compression::decompress(msg, max_size) {
+                Some(msg) => msg,
+                None => {
+                    warn!(
+                        "Dropping malformed compressed message from {} on bus {}",
+                        source, bus_id
+                    );
+                    self.handler.on_corrupt_frame(&mut self.senders, bus_id, source)?;
+                    return Ok(None);
+                }
+            },
+        };
+        if let Some(seq) =
+            header.as_ref().filter(|header| !header.retransmitted).and_then(|header| header.seq)
+        {
+            let endpoint = self.senders.endpoint_mut(bus_id)?;
+            let gap = if endpoint.sequenced && dest == endpoint.identity {
+                endpoint.check_seq(&source, seq)
+            } else {
+                None
+            };
+            if let Some(expected) = gap {
+                warn!(
+                    "Sequence gap in messages from {} on bus {}: expected {}, got {}",
+                    source, bus_id, expected, seq
+                );
+                self.handler.on_sequence_gap(
+                    &mut self.senders,
+                    bus_id,
+                    source.clone(),
+                    expected,
+                    seq,
+                )?;
+                if seq > expected {
+                    self.handler.on_gap_detected(
+                        &mut self.senders,
+                        bus_id,
+                        source.clone(),
+                        expected..seq,
+                    )?;
+                }
+            }
+        }
+        let endpoint = self.senders.endpoint_mut(bus_id)?;
+        endpoint.sizes.received.record(msg.len());
+        let skip_undecodable = endpoint.skip_undecodable;
+        let mut msg = msg;
+        for transform in &self.recv_transforms {
+            msg = transform.transform(bus_id, msg)?;
+        }
+        let request = match self.decode(&msg) {
+            Ok(request) => request,
+            Err(err) if skip_undecodable => {
+                warn!(
+                    "Skipping undecodable message from {} to {} on bus {}: {}",
+                    source, dest, bus_id, err
+                );
+                #[cfg(feature = "wire-trace")]
+                trace!("Undecodable message data: {:02x?}", msg);
+                return Ok(None);
+            }
+            Err(err) => return Err(err),
+        };
+
+        Ok(Some((source, dest, request, header)))
+    }
+}
+
+/// Typed setters for ZMQ socket options of the individual service buses
+impl<B, R, H> Controller<B, R, H>
+where
+    R: Request,
+    B: BusId,
+    H: Handler<B, Request = R>,
+    Error<B::Address>: From<H::Error>,
+{
+    fn socket(&self, bus_id: B) -> Result<&zmq::Socket, Error<B::Address>> {
+        Ok(self.senders.endpoint(bus_id)?.session.as_socket())
+    }
+
+    /// Sets high water mark for inbound messages (`ZMQ_RCVHWM`); zero means
+    /// no limit
+    pub fn set_rcvhwm(&self, bus_id: B, hwm: usize) -> Result<(), Error<B::Address>> {
+        self.socket(bus_id)?.set_rcvhwm(checked_i32("ZMQ_RCVHWM", hwm)?)?;
+        Ok(())
+    }
+
+    /// Sets high water mark for outbound messages (`ZMQ_SNDHWM`); zero means
+    /// no limit
+    pub fn set_sndhwm(&self, bus_id: B, hwm: usize) -> Result<(), Error<B::Address>> {
+        self.socket(bus_id)?.set_sndhwm(checked_i32("ZMQ_SNDHWM", hwm)?)?;
+        Ok(())
+    }
+
+    /// Sets timeout for receive operations (`ZMQ_RCVTIMEO`); `None` means
+    /// blocking until a message arrives
+    pub fn set_rcvtimeo(
+        &self,
+        bus_id: B,
+        timeout: Option<Duration>,
+    ) -> Result<(), Error<B::Address>> {
+        let value = timeout.map(|t| duration_ms("ZMQ_RCVTIMEO", t)).transpose()?.unwrap_or(-1);
+        self.socket(bus_id)?.set_rcvtimeo(value)?;
+        Ok(())
+    }
+
+    /// Sets timeout for send operations (`ZMQ_SNDTIMEO`); `None` means
+    /// blocking until the message is sent
+    pub fn set_sndtimeo(
+        &self,
+        bus_id: B,
+        timeout: Option<Duration>,
+    ) -> Result<(), Error<B::Address>> {
+        let value = timeout.map(|t| duration_ms("ZMQ_SNDTIMEO", t)).transpose()?.unwrap_or(-1);
+        self.socket(bus_id)?.set_sndtimeo(value)?;
+        Ok(())
+    }
+
+    /// Sets interval between reconnection attempts (`ZMQ_RECONNECT_IVL`);
+    /// `None` disables reconnection
+    pub fn set_reconnect_ivl(
+        &self,
+        bus_id: B,
+        interval: Option<Duration>,
+    ) -> Result<(), Error<B::Address>> {
+        let value =
+            interval.map(|t| duration_ms("ZMQ_RECONNECT_IVL", t)).transpose()?.unwrap_or(-1);
+        self.socket(bus_id)?.set_reconnect_ivl(value)?;
+        Ok(())
+    }
+
+    /// Enables or disables TCP keepalive (`ZMQ_TCP_KEEPALIVE`); `None` leaves
+    /// the operating system default
+    pub fn set_tcp_keepalive(
+        &self,
+        bus_id: B,
+        keepalive: Option<bool>,
+    ) -> Result<(), Error<B::Address>> {
+        let value = match keepalive {
+            None => -1,
+            Some(false) => 0,
+            Some(true) => 1,
+        };
+        self.socket(bus_id)?.set_tcp_keepalive(value)?;
+        Ok(())
+    }
+}
+
+/// Applies reconnection interval and its maximum (see
+/// [`BusConfig::reconnect_ivl`]), checking the values
+fn set_reconnect_ivl<A: ServiceAddress>(
+    socket: &zmq::Socket,
+    (ivl, ivl_max): (Option<Duration>, Option<Duration>),
+) -> Result<(), Error<A>> {
+    if let (Some(ivl), Some(ivl_max)) = (ivl, ivl_max) {
+        if ivl_max < ivl {
+            return Err(Error::InvalidSocketOption(
+                "ZMQ_RECONNECT_IVL_MAX",
+                format!("maximum {:?} is less than the interval {:?}", ivl_max, ivl),
+            ));
+        }
+    }
+    if let Some(ivl) = ivl {
+        socket.set_reconnect_ivl(duration_ms("ZMQ_RECONNECT_IVL", ivl)?)?;
+    }
+    if let Some(ivl_max) = ivl_max {
+        socket.set_reconnect_ivl_max(duration_ms("ZMQ_RECONNECT_IVL_MAX", ivl_max)?)?;
+    }
+    Ok(())
+}
+
+/// Applies high water mark (see [`BusConfig::hwm`]) for both directions
+fn set_hwm<A: ServiceAddress>(socket: &zmq::Socket, hwm: Option<usize>) -> Result<(), Error<A>> {
+    if let Some(hwm) = hwm {
+        socket.set_sndhwm(checked_i32("ZMQ_SNDHWM", hwm)?)?;
+        socket.set_rcvhwm(checked_i32("ZMQ_RCVHWM", hwm)?)?;
+    }
+    Ok(())
+}
+
+/// Checks whether sockets of the given type can send messages
+fn is_sendable(api_type: &zmqsocket::ZmqType) -> bool {
+    !matches!(api_type, zmqsocket::ZmqType::Pull | zmqsocket::ZmqType::Sub)
+}
+
+/// Checks whether sockets of the given type can receive messages
+fn is_receivable(api_type: &zmqsocket::ZmqType) -> bool {
+    !matches!(api_type, zmqsocket::ZmqType::Push | zmqsocket::ZmqType::Pub)
+}
+
+/// Attaches monitor to the socket, returning PAIR socket receiving connection
+/// events
+fn monitor_socket(context: &zmq::Context, socket: &zmq::Socket) -> Result<zmq::Socket, zmq::Error> {
+    static MONITOR_NO: AtomicUsize = AtomicUsize::new(0);
+    let addr = format!("inproc://esb-monitor-{}", MONITOR_NO.fetch_add(1, Ordering::Relaxed));
+    socket.monitor(&addr, ConnEvent::monitor_mask())?;
+    let monitor = context.socket(zmq::PAIR)?;
+    monitor.connect(&addr)?;
+    Ok(monitor)
+}
+
+fn checked_i32<A: ServiceAddress>(option: &'static str, value: usize) -> Result<i32, Error<A>> {
+    i32::try_from(value).map_err(|_| {
+        Error::InvalidSocketOption(
+            option,
+            format!("value {} exceeds maximum of {}", value, i32::MAX),
+        )
+    })
+}
+
+fn duration_ms<A: ServiceAddress>(
+    option: &'static str,
+    duration: Duration,
+) -> Result<i32, Error<A>> {
+    i32::try_from(duration.as_millis()).map_err(|_| {
+        Error::InvalidSocketOption(
+            option,
+            format!("duration {:?} exceeds maximum of {} ms", duration, i32::MAX),
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use internet2::Api;
+
+    use super::*;
+
+    #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+    #[display(Debug)]
+    enum Bus {
+        First,
+        Second,
+        Third,
+    }
+
+    impl BusId for Bus {
+        type Address = Addr;
+    }
+
+    #[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+    #[display(inner)]
+    struct Addr(String);
+
+    impl From<Vec<u8>> for Addr {
+        fn from(data: Vec<u8>) -> Self { Addr(String::from_utf8_lossy(&data).into_owned()) }
+    }
+
+    impl From<Addr> for Vec<u8> {
+        fn from(addr: Addr) -> Self { addr.0.into_bytes() }
+    }
+
+    impl ServiceAddress for Addr {}
+
+    #[derive(Clone, Debug, Display, Api)]
+    #[api(encoding = "strict")]
+    enum Msg {
+        #[api(type = 1)]
+        #[display("ping()")]
+        Ping,
+    }
+
+    impl Request for Msg {}
+
+    /// Handler with a given identity, which does nothing: tests read requests
+    /// with the controller polling methods instead of dispatching them
+    struct Passive(&'static str);
+
+    impl Handler<Bus> for Passive {
+        type Request = Msg;
+        type Error = Error<Addr>;
+
+        fn identity(&self) -> Addr { Addr(self.0.to_owned()) }
+
+        fn handle(
+            &mut self,
+            _endpoints: &mut EndpointList<Bus>,
+            _bus_id: Bus,
+            _source: Addr,
+            _request: Msg,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn handle_err(
+            &mut self,
+            _endpoints: &mut EndpointList<Bus>,
+            _error: Error<Addr>,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// Creates `server` and `client` controllers connected over inproc
+    /// transport on each of the `buses`
+    fn connected_pair(
+        buses: &[Bus],
+    ) -> (Controller<Bus, Msg, Passive>, Controller<Bus, Msg, Passive>) {
+        static PAIR_NO: AtomicUsize = AtomicUsize::new(0);
+        let pair_no = PAIR_NO.fetch_add(1, Ordering::Relaxed);
+        let config = ControllerConfig { context: Some(zmq::Context::new()), ..default!() };
+        let service_bus = || {
+            buses
+                .iter()
+                .map(|bus_id| {
+                    let locator = format!("inproc://esb-controller-{}-{}", pair_no, bus_id);
+                    let locator = zmqsocket::ZmqSocketAddr::from_str(&locator)
+                        .expect("inproc locator is always valid");
+                    let mut bus = BusConfig::with_locator(locator, None);
+                    bus.queued = true;
+                    (*bus_id, bus)
+                })
+                .collect::<HashMap<_, _>>()
+        };
+
+        // inproc endpoints must be bound before connecting to them
+        let server = Controller::with_config(
+            service_bus(),
+            Passive("server"),
+            zmqsocket::ZmqType::RouterBind,
+            config.clone(),
+        )
+        .unwrap();
+        let client = Controller::with_config(
+            service_bus(),
+            Passive("client"),
+            zmqsocket::ZmqType::RouterConnect,
+            config,
+        )
+        .unwrap();
+        (server, client)
+    }
+
+    #[test]
+    fn round_robin_fairness() {
+        const QUEUED: usize = 100;
+        const ITERATIONS: usize = 150;
+
+        let buses = [Bus::First, Bus::Second, Bus::Third];
+        let (mut server, mut client) = connected_pair(&buses);
+        for bus_id in &buses {
+            for _ in 0..QUEUED {
+                client.send_to(*bus_id, Addr(s!("server")), Msg::Ping).unwrap();
+            }
+        }
+        // All the messages must be queued, so the buses stay ready during the
+        // whole test
+        thread::sleep(Duration::from_millis(100));
+
+        let mut counts = HashMap::<Bus, usize>::new();
+        for _ in 0..ITERATIONS {
+            // With a single byte budget each poll reads a single message
+            let (received, _) = server.recv_poll_budget(1).unwrap();
+            assert_eq!(received.len(), 1);
+            *counts.entry(received[0].0).or_default() += 1;
+        }
+        let counts = buses
+            .iter()
+            .map(|bus_id| counts.get(bus_id).copied().unwrap_or_default())
+            .collect::<Vec<_>>();
+        let min = counts.iter().min().copied().unwrap_or_default();
+        let max = counts.iter().max().copied().unwrap_or_default();
+        assert!(max - min <= 1, "buses are not serviced equally: {:?}", counts);
+    }
+
+    #[test]
+    fn lazy_bus_limit() {
+        let config = ControllerConfig {
+            context: Some(zmq::Context::new()),
+            max_buses: Some(1),
+            ..default!()
+        };
+        let mut controller = Controller::<Bus, Msg, Passive>::with_config(
+            HashMap::new(),
+            Passive("server"),
+            zmqsocket::ZmqType::RouterBind,
+            config,
+        )
+        .unwrap();
+        let lazy_bus = |bus_id: Bus| {
+            let locator = format!("inproc://esb-lazy-limit-{}", bus_id);
+            let locator = zmqsocket::ZmqSocketAddr::from_str(&locator)
+                .expect("inproc locator is always valid");
+            let mut bus = BusConfig::with_locator(locator, None);
+            bus.lazy = true;
+            bus
+        };
+
+        controller.add_service_bus(Bus::First, lazy_bus(Bus::First)).unwrap();
+        assert!(matches!(
+            controller.add_service_bus(Bus::Second, lazy_bus(Bus::Second)),
+            Err(Error::BusLimitReached(1))
+        ));
+        controller.establish_bus(Bus::First).unwrap();
+        assert!(matches!(
+            controller.add_service_bus(Bus::Second, lazy_bus(Bus::Second)),
+            Err(Error::BusLimitReached(1))
+        ));
+    }
+
+    #[test]
+    fn spurious_wakeup() {
+        let (mut server, mut client) = connected_pair(&[Bus::First]);
+        server.set_rcvtimeo(Bus::First, Some(Duration::from_millis(0))).unwrap();
 
-        trace!("Received ESB request from {} service busses...", service_buses.len());
+        // Bus is read in the same way as after a poll reporting it readable,
+        // while there are no messages queued
+        assert!(server.recv_routed_batch(Bus::First).unwrap().is_empty());
 
-        Ok(service_buses)
+        // Messages arriving after the wakeup are still received
+        client.send_to(Bus::First, Addr(s!("server")), Msg::Ping).unwrap();
+        let received = server.recv_poll().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].1, Addr(s!("client")));
     }
 }
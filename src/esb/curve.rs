@@ -0,0 +1,107 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+/// Length of CURVE public and secret keys, in bytes
+pub const CURVE_KEY_LEN: usize = 32;
+
+/// Length of CURVE key encoded in ZMQ Z85 text format, in characters
+pub const CURVE_KEY_Z85_LEN: usize = 40;
+
+/// Errors loading CURVE keys
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum CurveKeyError {
+    /// CURVE key text has length of {0} characters, while Z85-encoded key must
+    /// be exactly 40 characters long
+    InvalidZ85Length(usize),
+
+    /// CURVE key text is not a valid Z85 string
+    InvalidZ85,
+
+    /// CURVE key has length of {0} bytes, while it must be exactly 32 bytes
+    /// long
+    InvalidLength(usize),
+}
+
+/// ZMQ CURVE key pair
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Getters)]
+pub struct CurveKeys {
+    /// Public key, which is shared with other parties
+    public_key: [u8; CURVE_KEY_LEN],
+
+    /// Secret key, which must never leave the service
+    secret_key: [u8; CURVE_KEY_LEN],
+}
+
+impl CurveKeys {
+    /// Generates new random key pair
+    ///
+    /// # Panics
+    ///
+    /// If the linked ZMQ library is built without CURVE support
+    pub fn generate() -> CurveKeys {
+        let pair = zmq::CurveKeyPair::new().expect("ZMQ library is built without CURVE support");
+        CurveKeys { public_key: pair.public_key, secret_key: pair.secret_key }
+    }
+
+    /// Constructs key pair from raw keys, checking their length
+    pub fn from_slice(public_key: &[u8], secret_key: &[u8]) -> Result<CurveKeys, CurveKeyError> {
+        Ok(CurveKeys {
+            public_key: key_from_slice(public_key)?,
+            secret_key: key_from_slice(secret_key)?,
+        })
+    }
+
+    /// Loads key pair from public and secret keys in ZMQ Z85 text format
+    pub fn from_z85(public_key: &str, secret_key: &str) -> Result<CurveKeys, CurveKeyError> {
+        Ok(CurveKeys {
+            public_key: key_from_z85(public_key)?,
+            secret_key: key_from_z85(secret_key)?,
+        })
+    }
+
+    /// Exports public and secret keys in ZMQ Z85 text format
+    pub fn to_z85(&self) -> (String, String) {
+        (key_to_z85(&self.public_key), key_to_z85(&self.secret_key))
+    }
+
+    /// Exports public key in ZMQ Z85 text format
+    pub fn public_key_z85(&self) -> String { key_to_z85(&self.public_key) }
+}
+
+impl From<zmq::CurveKeyPair> for CurveKeys {
+    fn from(pair: zmq::CurveKeyPair) -> Self {
+        CurveKeys { public_key: pair.public_key, secret_key: pair.secret_key }
+    }
+}
+
+fn key_from_slice(key: &[u8]) -> Result<[u8; CURVE_KEY_LEN], CurveKeyError> {
+    if key.len() != CURVE_KEY_LEN {
+        return Err(CurveKeyError::InvalidLength(key.len()));
+    }
+    let mut buf = [0u8; CURVE_KEY_LEN];
+    buf.copy_from_slice(key);
+    Ok(buf)
+}
+
+fn key_from_z85(key: &str) -> Result<[u8; CURVE_KEY_LEN], CurveKeyError> {
+    if key.len() != CURVE_KEY_Z85_LEN {
+        return Err(CurveKeyError::InvalidZ85Length(key.len()));
+    }
+    let data = zmq::z85_decode(key).map_err(|_| CurveKeyError::InvalidZ85)?;
+    key_from_slice(&data)
+}
+
+fn key_to_z85(key: &[u8; CURVE_KEY_LEN]) -> String {
+    zmq::z85_encode(key).expect("CURVE key length is always a multiple of four")
+}
@@ -0,0 +1,76 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::io;
+
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use super::ServiceAddress;
+use crate::rpc_connection::Request;
+
+/// Routed service bus message captured together with its source and
+/// destination addresses. Envelopes can be strict-encoded into a log and later
+/// fed back into [`super::Controller::replay`] to reproduce handler behaviour.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Envelope<A>
+where
+    A: ServiceAddress,
+{
+    /// Address of the message originator
+    pub source: A,
+
+    /// Address of the message destination
+    pub dest: A,
+
+    /// Serialized request data
+    pub payload: Vec<u8>,
+}
+
+impl<A> Envelope<A>
+where
+    A: ServiceAddress,
+{
+    /// Constructs envelope by serializing the provided request
+    pub fn with<R>(source: A, dest: A, request: &R) -> Self
+    where
+        R: Request,
+    {
+        Self { source, dest, payload: request.serialize() }
+    }
+}
+
+impl<A> StrictEncode for Envelope<A>
+where
+    A: ServiceAddress,
+{
+    fn strict_encode<E: io::Write>(&self, mut e: E) -> Result<usize, strict_encoding::Error> {
+        let source: Vec<u8> = self.source.clone().into();
+        let dest: Vec<u8> = self.dest.clone().into();
+        Ok(source.strict_encode(&mut e)?
+            + dest.strict_encode(&mut e)?
+            + self.payload.strict_encode(&mut e)?)
+    }
+}
+
+impl<A> StrictDecode for Envelope<A>
+where
+    A: ServiceAddress,
+{
+    fn strict_decode<D: io::Read>(mut d: D) -> Result<Self, strict_encoding::Error> {
+        Ok(Self {
+            source: A::from(Vec::<u8>::strict_decode(&mut d)?),
+            dest: A::from(Vec::<u8>::strict_decode(&mut d)?),
+            payload: Vec::<u8>::strict_decode(&mut d)?,
+        })
+    }
+}
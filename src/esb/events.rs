@@ -0,0 +1,102 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::time::SystemTime;
+
+/// Maximal number of connection events kept for each of the service buses
+pub const CONN_EVENT_LOG_LEN: usize = 64;
+
+/// Connection lifecycle event of the service bus socket
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[display(doc_comments)]
+pub enum ConnEventKind {
+    /// connected
+    Connected,
+
+    /// disconnected
+    Disconnected,
+
+    /// reconnection attempt
+    Reconnecting,
+
+    /// closed
+    Closed,
+
+    /// failure with error code {0}
+    Failed(u32),
+}
+
+/// Connection lifecycle event recorded for a service bus (see
+/// [`super::Controller::recent_events`])
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[display("{kind} {peer}")]
+pub struct ConnEvent {
+    /// Time when the event was registered by the controller. Since events
+    /// are collected when buses are polled, this may be later than the
+    /// event has actually happened.
+    pub time: SystemTime,
+
+    /// Type of the event
+    pub kind: ConnEventKind,
+
+    /// Address of the remote endpoint the event relates to
+    pub peer: String,
+}
+
+impl ConnEvent {
+    /// Parses ZMQ socket monitor event from its frames: event id with value
+    /// and the endpoint address. Returns `None` for the events which are not
+    /// tracked.
+    pub(super) fn from_monitor(event: &[u8], addr: &[u8]) -> Option<Self> {
+        if event.len() < 6 {
+            return None;
+        }
+        let id = u16::from_le_bytes([event[0], event[1]]);
+        let value = u32::from_le_bytes([event[2], event[3], event[4], event[5]]);
+        let kind = match id {
+            id if id == zmq::SocketEvent::CONNECTED.to_raw() => ConnEventKind::Connected,
+            id if id == zmq::SocketEvent::DISCONNECTED.to_raw() => ConnEventKind::Disconnected,
+            id if id == zmq::SocketEvent::CONNECT_RETRIED.to_raw() => ConnEventKind::Reconnecting,
+            id if id == zmq::SocketEvent::CLOSED.to_raw() => ConnEventKind::Closed,
+            id if id == zmq::SocketEvent::CLOSE_FAILED.to_raw()
+                || id == zmq::SocketEvent::HANDSHAKE_FAILED_NO_DETAIL.to_raw()
+                || id == zmq::SocketEvent::HANDSHAKE_FAILED_PROTOCOL.to_raw()
+                || id == zmq::SocketEvent::HANDSHAKE_FAILED_AUTH.to_raw() =>
+            {
+                ConnEventKind::Failed(value)
+            }
+            _ => return None,
+        };
+        Some(ConnEvent {
+            time: SystemTime::now(),
+            kind,
+            peer: String::from_utf8_lossy(addr).into_owned(),
+        })
+    }
+
+    /// Returns mask of the socket monitor events which are tracked
+    pub(super) fn monitor_mask() -> i32 {
+        [
+            zmq::SocketEvent::CONNECTED,
+            zmq::SocketEvent::DISCONNECTED,
+            zmq::SocketEvent::CONNECT_RETRIED,
+            zmq::SocketEvent::CLOSED,
+            zmq::SocketEvent::CLOSE_FAILED,
+            zmq::SocketEvent::HANDSHAKE_FAILED_NO_DETAIL,
+            zmq::SocketEvent::HANDSHAKE_FAILED_PROTOCOL,
+            zmq::SocketEvent::HANDSHAKE_FAILED_AUTH,
+        ]
+        .iter()
+        .fold(0, |mask, event| mask | event.to_raw() as i32)
+    }
+}
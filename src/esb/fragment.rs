@@ -0,0 +1,292 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+
+use super::ServiceAddress;
+
+/// Prefix of the frames carrying whole messages
+const WHOLE: u8 = 0;
+/// Prefix of the frames carrying message fragments
+const FRAGMENT: u8 = 1;
+/// Length of fragment frame prefix: marker, message id, fragment index and
+/// number of fragments
+const FRAGMENT_PREFIX_LEN: usize = 1 + 8 + 4 + 4;
+
+/// Configuration of transparent message fragmentation, see
+/// [`super::BusConfig::fragmentation`]. The same `threshold` should be used
+/// by all bus participants.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct FragmentConfig {
+    /// Maximal size of the message data sent in a single frame; larger
+    /// messages are split into fragments of this size
+    pub threshold: usize,
+
+    /// Maximal total size of incomplete messages being reassembled from a
+    /// single source; fragments exceeding the limit are dropped
+    pub max_buffer: usize,
+
+    /// Time after which incomplete message is discarded
+    pub timeout: Duration,
+}
+
+impl Default for FragmentConfig {
+    fn default() -> Self {
+        FragmentConfig {
+            threshold: 256 * 1024,
+            max_buffer: 64 * 1024 * 1024,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl FragmentConfig {
+    /// Splits message data into frames, each not exceeding the threshold plus
+    /// the frame prefix length
+    pub(super) fn fragment(&self, id: u64, data: Vec<u8>) -> Vec<Vec<u8>> {
+        if data.len() <= self.threshold {
+            let mut frame = Vec::with_capacity(data.len() + 1);
+            frame.push(WHOLE);
+            frame.extend(data);
+            return vec![frame];
+        }
+        let chunks = data.chunks(self.threshold.max(1));
+        let count = chunks.len() as u32;
+        chunks
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut frame = Vec::with_capacity(FRAGMENT_PREFIX_LEN + chunk.len());
+                frame.push(FRAGMENT);
+                frame.extend_from_slice(&id.to_be_bytes());
+                frame.extend_from_slice(&(index as u32).to_be_bytes());
+                frame.extend_from_slice(&count.to_be_bytes());
+                frame.extend_from_slice(chunk);
+                frame
+            })
+            .collect()
+    }
+
+    /// Maximal number of fragments of a message which fits into the
+    /// reassembly buffer
+    fn max_fragments(&self) -> usize { self.max_buffer / self.threshold.max(1) + 1 }
+}
+
+/// Message being reassembled from its fragments
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+    started: Instant,
+}
+
+/// Buffer reassembling messages from the fragments received from each of
+/// the sources
+pub(super) struct Reassembler<A>
+where
+    A: ServiceAddress,
+{
+    config: FragmentConfig,
+    partial: HashMap<(A, u64), Partial>,
+    /// Total size of the incomplete messages from each of the sources
+    buffered: HashMap<A, usize>,
+}
+
+impl<A> Reassembler<A>
+where
+    A: ServiceAddress,
+{
+    pub fn new(config: FragmentConfig) -> Self {
+        Reassembler { config, partial: HashMap::new(), buffered: HashMap::new() }
+    }
+
+    /// Processes frame received from `source`. Returns whole message data once
+    /// the message is complete, `Ok(None)` if more fragments are awaited or
+    /// the fragment was dropped, and `Err(())` if the frame is malformed.
+    pub fn accept(&mut self, source: &A, mut frame: Vec<u8>) -> Result<Option<Vec<u8>>, ()> {
+        self.expire();
+        match frame.first() {
+            Some(&WHOLE) => {
+                frame.remove(0);
+                return Ok(Some(frame));
+            }
+            Some(&FRAGMENT) if frame.len() >= FRAGMENT_PREFIX_LEN => {}
+            _ => return Err(()),
+        }
+        let id = u64::from_be_bytes(frame[1..9].try_into().map_err(|_| ())?);
+        let index = u32::from_be_bytes(frame[9..13].try_into().map_err(|_| ())?) as usize;
+        let count = u32::from_be_bytes(frame[13..17].try_into().map_err(|_| ())?) as usize;
+        // Fragment count comes from the remote peer and must be checked before
+        // allocating space for the fragments
+        if index >= count || count > self.config.max_fragments() {
+            return Err(());
+        }
+        let chunk = frame.split_off(FRAGMENT_PREFIX_LEN);
+
+        let buffered = self.buffered.get(source).copied().unwrap_or_default();
+        if buffered + chunk.len() > self.config.max_buffer {
+            warn!("Reassembly buffer for {} is full, dropping message fragment", source);
+            return Ok(None);
+        }
+        let key = (source.clone(), id);
+        let partial = self.partial.entry(key.clone()).or_insert_with(|| Partial {
+            fragments: vec![None; count],
+            received: 0,
+            size: 0,
+            started: Instant::now(),
+        });
+        if partial.fragments.len() != count {
+            return Err(());
+        }
+        if partial.fragments[index].is_some() {
+            // Duplicated fragment
+            return Ok(None);
+        }
+        partial.received += 1;
+        partial.size += chunk.len();
+        *self.buffered.entry(source.clone()).or_default() += chunk.len();
+        partial.fragments[index] = Some(chunk);
+        if partial.received < count {
+            return Ok(None);
+        }
+
+        let partial = self.partial.remove(&key).expect("partial message is present");
+        self.release(source, partial.size);
+        Ok(Some(partial.fragments.into_iter().flatten().flatten().collect()))
+    }
+
+    /// Discards incomplete messages which have timed out
+    fn expire(&mut self) {
+        let timeout = self.config.timeout;
+        let expired = self
+            .partial
+            .iter()
+            .filter(|(_, partial)| partial.started.elapsed() > timeout)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in expired {
+            if let Some(partial) = self.partial.remove(&key) {
+                debug!(
+                    "Discarding incomplete message with {} of {} fragments",
+                    partial.received,
+                    partial.fragments.len()
+                );
+                self.release(&key.0, partial.size);
+            }
+        }
+    }
+
+    fn release(&mut self, source: &A, size: usize) {
+        if let Some(buffered) = self.buffered.get_mut(source) {
+            *buffered = buffered.saturating_sub(size);
+            if *buffered == 0 {
+                self.buffered.remove(source);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+    #[display(inner)]
+    struct Addr(u8);
+
+    impl From<Vec<u8>> for Addr {
+        fn from(data: Vec<u8>) -> Self { Addr(data.first().copied().unwrap_or_default()) }
+    }
+
+    impl From<Addr> for Vec<u8> {
+        fn from(addr: Addr) -> Self { vec![addr.0] }
+    }
+
+    impl ServiceAddress for Addr {}
+
+    fn config(threshold: usize, max_buffer: usize) -> FragmentConfig {
+        FragmentConfig { threshold, max_buffer, ..default!() }
+    }
+
+    #[test]
+    fn small_message_is_whole() {
+        let config = config(16, 1024);
+        let frames = config.fragment(0, b"hello".to_vec());
+        assert_eq!(frames.len(), 1);
+
+        let mut reassembler = Reassembler::new(config);
+        let data = reassembler.accept(&Addr(1), frames[0].clone()).unwrap();
+        assert_eq!(data, Some(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn reassembly_in_any_order() {
+        let config = config(3, 1024);
+        let message = (0u8..10).collect::<Vec<_>>();
+        let mut frames = config.fragment(7, message.clone());
+        assert_eq!(frames.len(), 4);
+        assert!(frames.iter().all(|frame| frame.len() <= FRAGMENT_PREFIX_LEN + 3));
+        frames.reverse();
+
+        let mut reassembler = Reassembler::new(config);
+        let last = frames.pop().unwrap();
+        for frame in frames {
+            assert_eq!(reassembler.accept(&Addr(1), frame.clone()).unwrap(), None);
+            // Duplicated fragments are ignored
+            assert_eq!(reassembler.accept(&Addr(1), frame).unwrap(), None);
+        }
+        // Fragments of the same message id from other sources are independent
+        assert_eq!(reassembler.accept(&Addr(2), last.clone()).unwrap(), None);
+        assert_eq!(reassembler.accept(&Addr(1), last).unwrap(), Some(message));
+        assert_eq!(reassembler.buffered.get(&Addr(1)), None);
+    }
+
+    #[test]
+    fn malformed_frames() {
+        let mut reassembler = Reassembler::<Addr>::new(config(3, 1024));
+        assert_eq!(reassembler.accept(&Addr(1), vec![]), Err(()));
+        assert_eq!(reassembler.accept(&Addr(1), vec![2, 0]), Err(()));
+        assert_eq!(reassembler.accept(&Addr(1), vec![FRAGMENT, 0, 0]), Err(()));
+
+        let mut frame = vec![FRAGMENT];
+        frame.extend_from_slice(&0u64.to_be_bytes());
+        frame.extend_from_slice(&2u32.to_be_bytes());
+        frame.extend_from_slice(&2u32.to_be_bytes());
+        assert_eq!(reassembler.accept(&Addr(1), frame), Err(()));
+    }
+
+    #[test]
+    fn fragment_count_is_bounded() {
+        let mut reassembler = Reassembler::<Addr>::new(config(3, 1024));
+        let mut frame = vec![FRAGMENT];
+        frame.extend_from_slice(&0u64.to_be_bytes());
+        frame.extend_from_slice(&0u32.to_be_bytes());
+        frame.extend_from_slice(&u32::MAX.to_be_bytes());
+        frame.extend_from_slice(b"abc");
+        assert_eq!(reassembler.accept(&Addr(1), frame), Err(()));
+        assert!(reassembler.partial.is_empty());
+    }
+
+    #[test]
+    fn buffer_limit() {
+        let config = config(4, 10);
+        let mut frames = config.fragment(0, vec![1u8; 12]).into_iter();
+        let mut reassembler = Reassembler::new(config);
+        assert_eq!(reassembler.accept(&Addr(1), frames.next().unwrap()).unwrap(), None);
+        assert_eq!(reassembler.accept(&Addr(1), frames.next().unwrap()).unwrap(), None);
+        // Last fragment would exceed the buffer and is dropped
+        assert_eq!(reassembler.accept(&Addr(1), frames.next().unwrap()).unwrap(), None);
+        assert_eq!(reassembler.buffered.get(&Addr(1)), Some(&8));
+    }
+}
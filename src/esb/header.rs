@@ -0,0 +1,166 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use super::{Error, ServiceAddress};
+
+/// Latest version of the message envelope format (version byte followed by
+/// the [`Header`]) supported by this library. Versions start from 1.
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// Message header prepended to each message sent over service buses with
+/// enabled headers (see [`super::BusConfig::headers`]).
+///
+/// Timestamps use wall clock, since monotonic clocks can't be compared
+/// between processes; thus time-based checks are as precise as clock
+/// synchronization between the hosts running bus services.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default, StrictEncode, StrictDecode)]
+pub struct Header {
+    /// Time when the message was sent, in milliseconds since UNIX epoch. Set
+    /// by the sending controller if left zero.
+    pub timestamp: u64,
+
+    /// Message time-to-live in milliseconds. Routers drop messages which
+    /// have expired instead of forwarding them.
+    pub ttl: Option<u64>,
+
+    /// Sequence number of the message among all messages sent by the source
+    /// to the destination over the bus. Set by the sending controller on
+    /// buses with enabled sequencing (see [`super::BusConfig::sequenced`]).
+    pub seq: Option<u64>,
+
+    /// Message priority. Within a batch of received messages, controller
+    /// processes messages with higher priority first, so urgent control
+    /// messages may preempt bulk data queued ahead of them.
+    pub priority: u8,
+
+    /// Indicates that the message is a retransmission of a message which was
+    /// missed by the receiver (see [`super::BusConfig::retransmit_buffer`])
+    pub retransmitted: bool,
+
+    /// Time budget remaining for the message delivery and processing, in
+    /// milliseconds. Unlike [`Header::ttl`] it does not depend on clock
+    /// synchronization: each routing hop decrements it by the time the
+    /// message has spent there and drops the message once it is exhausted.
+    /// Time spent on the wire is not accounted.
+    pub deadline: Option<u64>,
+
+    /// Key identifying operation which must be executed at most once, even
+    /// if the message is sent multiple times (for instance when retried).
+    /// Receivers remembering recently executed keys (see
+    /// [`super::BusConfig::idempotency_window`]) skip repeated messages.
+    pub idempotency_key: Option<Vec<u8>>,
+
+    /// Address of the service which must receive the response instead of
+    /// the message source, allowing requests dispatched by a coordinator to
+    /// be answered to a separate reply collector
+    pub reply_to: Option<Vec<u8>>,
+}
+
+impl Header {
+    /// Constructs header for a message which must be dropped if not
+    /// delivered within `ttl`
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Header { ttl: Some(ttl.as_millis() as u64), ..default!() }
+    }
+
+    /// Constructs header for a message which must be processed within
+    /// `deadline`
+    pub fn with_deadline(deadline: Duration) -> Self {
+        Header { deadline: Some(deadline.as_millis() as u64), ..default!() }
+    }
+
+    /// Constructs header for a message which must be processed at most once
+    /// for the given idempotency `key`
+    pub fn with_idempotency_key(key: impl Into<Vec<u8>>) -> Self {
+        Header { idempotency_key: Some(key.into()), ..default!() }
+    }
+
+    /// Constructs header for a message which response must be sent to
+    /// `reply_to` address instead of the message source
+    pub fn with_reply_to(reply_to: impl Into<Vec<u8>>) -> Self {
+        Header { reply_to: Some(reply_to.into()), ..default!() }
+    }
+
+    /// Constructs header for a message with the given priority
+    pub fn with_priority(priority: u8) -> Self { Header { priority, ..default!() } }
+
+    /// Returns time elapsed since the message was sent
+    pub fn age(&self) -> Duration {
+        Duration::from_millis(now_millis().saturating_sub(self.timestamp))
+    }
+
+    /// Checks whether the message time-to-live has expired
+    pub fn is_expired(&self) -> bool {
+        match self.ttl {
+            Some(ttl) => self.age() > Duration::from_millis(ttl),
+            None => false,
+        }
+    }
+
+    /// Decrements remaining deadline by the time `spent` at the current hop.
+    /// Returns `false` if the deadline is exhausted.
+    pub(super) fn spend_deadline(&mut self, spent: Duration) -> bool {
+        match self.deadline {
+            Some(deadline) if deadline <= spent.as_millis() as u64 => false,
+            Some(deadline) => {
+                self.deadline = Some(deadline - spent.as_millis() as u64);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Sets timestamp to the current time unless it was already set
+    pub(super) fn stamp(&mut self) {
+        if self.timestamp == 0 {
+            self.timestamp = now_millis();
+        }
+    }
+
+    /// Prepends envelope version and strict-encoded header to the message
+    /// data
+    pub(super) fn seal<A: ServiceAddress>(
+        &self,
+        version: u8,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, Error<A>> {
+        let mut sealed = vec![version];
+        sealed.extend(self.strict_serialize().map_err(|err| Error::Header(err.to_string()))?);
+        sealed.extend(data);
+        Ok(sealed)
+    }
+
+    /// Returns envelope version of the message data
+    pub(super) fn version<A: ServiceAddress>(data: &[u8]) -> Result<u8, Error<A>> {
+        data.first().copied().ok_or_else(|| Error::Header(s!("envelope version is absent")))
+    }
+
+    /// Splits message data with supported envelope version into the header
+    /// and the rest of the message
+    pub(super) fn open<A: ServiceAddress>(data: Vec<u8>) -> Result<(Header, Vec<u8>), Error<A>> {
+        let mut cursor = std::io::Cursor::new(&data);
+        cursor.set_position(1);
+        let header =
+            Header::strict_decode(&mut cursor).map_err(|err| Error::Header(err.to_string()))?;
+        let pos = cursor.position() as usize;
+        Ok((header, data[pos..].to_vec()))
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
@@ -0,0 +1,91 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::time::Duration;
+
+/// Number of histogram buckets: a bucket for empty messages and a bucket for
+/// each power of two up to 2^31, with the last one also accounting for all
+/// larger messages
+const BUCKETS: usize = 33;
+
+/// Histogram of message sizes with power-of-two buckets
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SizeHistogram {
+    buckets: [u64; BUCKETS],
+}
+
+impl Default for SizeHistogram {
+    fn default() -> Self { SizeHistogram { buckets: [0u64; BUCKETS] } }
+}
+
+impl SizeHistogram {
+    /// Records message of `len` bytes
+    pub fn record(&mut self, len: usize) {
+        let bucket = (usize::BITS - len.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+    }
+
+    /// Returns total number of recorded messages
+    pub fn count(&self) -> u64 { self.buckets.iter().sum() }
+
+    /// Iterates over non-empty buckets, returning maximal message size in
+    /// bytes for the bucket (`None` for the last bucket, which has no upper
+    /// bound) and the number of messages in it
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<usize>, u64)> + '_ {
+        self.buckets.iter().enumerate().filter(|(_, count)| **count > 0).map(|(bucket, count)| {
+            let max = if bucket == BUCKETS - 1 { None } else { Some((1usize << bucket) - 1) };
+            (max, *count)
+        })
+    }
+}
+
+/// Histogram of request processing times with power-of-two microsecond
+/// buckets, see [`super::Controller::handle_latency`]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct LatencyHistogram {
+    micros: SizeHistogram,
+    total: Duration,
+}
+
+impl LatencyHistogram {
+    /// Records request processed within `elapsed` time
+    pub fn record(&mut self, elapsed: Duration) {
+        self.micros.record(elapsed.as_micros().min(usize::MAX as u128) as usize);
+        self.total += elapsed;
+    }
+
+    /// Returns total number of recorded requests
+    pub fn count(&self) -> u64 { self.micros.count() }
+
+    /// Returns total time spent processing the recorded requests
+    pub fn total(&self) -> Duration { self.total }
+
+    /// Iterates over non-empty buckets, returning maximal processing time for
+    /// the bucket (`None` for the last bucket, which has no upper bound) and
+    /// the number of requests in it
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.micros
+            .buckets()
+            .map(|(max, count)| (max.map(|max| Duration::from_micros(max as u64)), count))
+    }
+}
+
+/// Histograms of sizes of the serialized requests sent to and received from a
+/// service bus, see [`super::Controller::size_histogram`]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct MessageSizes {
+    /// Sizes of the sent requests
+    pub sent: SizeHistogram,
+    /// Sizes of the received requests
+    pub received: SizeHistogram,
+}
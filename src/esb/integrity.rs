@@ -0,0 +1,105 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+/// Integrity protection applied to messages sent over a service bus
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[non_exhaustive]
+pub enum IntegrityMode {
+    /// CRC32 (IEEE) checksum appended to each message. Detects accidental
+    /// corruption of the data, but not a deliberate tampering, since anybody
+    /// may recompute the checksum.
+    #[display("crc32")]
+    Crc32,
+}
+
+impl IntegrityMode {
+    /// Appends integrity data to the serialized message
+    pub(super) fn seal(self, data: &mut Vec<u8>) {
+        match self {
+            IntegrityMode::Crc32 => {
+                let checksum = crc32(data);
+                data.extend_from_slice(&checksum.to_be_bytes());
+            }
+        }
+    }
+
+    /// Verifies and strips integrity data from the received message. Returns
+    /// `None` if the message is corrupted.
+    pub(super) fn open(self, mut data: Vec<u8>) -> Option<Vec<u8>> {
+        match self {
+            IntegrityMode::Crc32 => {
+                let len = data.len().checked_sub(4)?;
+                let mut checksum = [0u8; 4];
+                checksum.copy_from_slice(&data[len..]);
+                data.truncate(len);
+                if crc32(&data) != u32::from_be_bytes(checksum) {
+                    return None;
+                }
+                Some(data)
+            }
+        }
+    }
+}
+
+/// Computes CRC32 (IEEE 802.3) checksum
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crc32_test_vectors() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let mut data = b"message".to_vec();
+        IntegrityMode::Crc32.seal(&mut data);
+        assert_eq!(data.len(), 7 + 4);
+        assert_eq!(IntegrityMode::Crc32.open(data), Some(b"message".to_vec()));
+
+        let mut data = vec![];
+        IntegrityMode::Crc32.seal(&mut data);
+        assert_eq!(IntegrityMode::Crc32.open(data), Some(vec![]));
+    }
+
+    #[test]
+    fn corruption_detected() {
+        let mut data = b"message".to_vec();
+        IntegrityMode::Crc32.seal(&mut data);
+        for pos in 0..data.len() {
+            let mut corrupted = data.clone();
+            corrupted[pos] ^= 0x01;
+            assert_eq!(IntegrityMode::Crc32.open(corrupted), None);
+        }
+    }
+
+    #[test]
+    fn truncated_message() {
+        assert_eq!(IntegrityMode::Crc32.open(vec![]), None);
+        assert_eq!(IntegrityMode::Crc32.open(vec![0u8; 3]), None);
+    }
+}
@@ -0,0 +1,48 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Handle interrupting blocking poll of a [`super::Controller`] from another
+/// thread, see [`super::Controller::interrupt_handle`]. The handle may be
+/// cloned and sent to other threads.
+#[derive(Clone)]
+pub struct InterruptHandle(Arc<Mutex<zmq::Socket>>);
+
+impl InterruptHandle {
+    /// Creates connected pair of inproc sockets: the first one is polled by
+    /// the controller, while the second one is used by the handle
+    pub(super) fn pair(context: &zmq::Context) -> Result<(zmq::Socket, Self), zmq::Error> {
+        static INTERRUPT_NO: AtomicUsize = AtomicUsize::new(0);
+        let addr =
+            format!("inproc://esb-interrupt-{}", INTERRUPT_NO.fetch_add(1, Ordering::Relaxed));
+        let receiver = context.socket(zmq::PAIR)?;
+        receiver.bind(&addr)?;
+        let sender = context.socket(zmq::PAIR)?;
+        sender.connect(&addr)?;
+        Ok((receiver, InterruptHandle(Arc::new(Mutex::new(sender)))))
+    }
+
+    /// Makes the current or the next poll of the controller return
+    /// immediately, even if no messages have arrived
+    pub fn interrupt(&self) -> Result<(), zmq::Error> {
+        let socket = self.0.lock().expect("interrupt socket lock is poisoned");
+        match socket.send(&[][..], zmq::DONTWAIT) {
+            // The controller has not consumed previous interrupts yet, so it
+            // will be woken up anyway
+            Err(zmq::Error::EAGAIN) => Ok(()),
+            res => res,
+        }
+    }
+}
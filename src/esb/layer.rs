@@ -0,0 +1,400 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Middleware layers wrapping ESB [`Handler`]s with cross-cutting behaviour
+//! (logging, metrics etc), which can be composed with [`Handler::layer`]
+//! without modifying business logic of the wrapped handler.
+
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use super::{BusId, EndpointList, Error, Handler};
+
+/// Layer decorates an inner handler, producing a new handler which delegates
+/// to the inner one adding some behaviour around it.
+pub trait Layer<H> {
+    /// Handler produced by the layer
+    type Handler;
+
+    /// Wraps `inner` handler into the layer
+    fn layer(self, inner: H) -> Self::Handler;
+}
+
+/// Layer logging each request and error processed by the inner handler
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct LoggingLayer {
+    /// Log level used for reporting processed requests
+    pub level: log::Level,
+}
+
+impl Default for LoggingLayer {
+    fn default() -> Self { LoggingLayer { level: log::Level::Debug } }
+}
+
+impl<H> Layer<H> for LoggingLayer {
+    type Handler = Logging<H>;
+
+    fn layer(self, inner: H) -> Self::Handler { Logging { inner, level: self.level } }
+}
+
+/// Handler produced by [`LoggingLayer`]
+#[derive(Getters, Clone, Debug)]
+pub struct Logging<H> {
+    inner: H,
+    level: log::Level,
+}
+
+impl<B, H> Handler<B> for Logging<H>
+where
+    B: BusId,
+    H: Handler<B>,
+    Error<B::Address>: From<H::Error>,
+{
+    type Request = H::Request;
+    type Error = H::Error;
+
+    fn identity(&self) -> B::Address { self.inner.identity() }
+
+    fn on_ready(&mut self, endpoints: &mut EndpointList<B>) -> Result<(), Self::Error> {
+        log!(self.level, "{} is ready", self.inner.identity());
+        self.inner.on_ready(endpoints)
+    }
+
+    fn handle(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        request: Self::Request,
+    ) -> Result<(), Self::Error> {
+        log!(self.level, "{} -> {} on bus {}: {}", source, self.inner.identity(), bus_id, request);
+        let res = self.inner.handle(endpoints, bus_id, source, request);
+        if let Err(ref err) = res {
+            warn!("Request processing on bus {} has failed: {}", bus_id, err);
+        }
+        res
+    }
+
+    fn handle_err(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        error: Error<B::Address>,
+    ) -> Result<(), Self::Error> {
+        log!(self.level, "Handling ESB error: {}", error);
+        self.inner.handle_err(endpoints, error)
+    }
+
+    fn on_corrupt_frame(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+    ) -> Result<(), Self::Error> {
+        log!(self.level, "Corrupted frame from {} on bus {}", source, bus_id);
+        self.inner.on_corrupt_frame(endpoints, bus_id, source)
+    }
+
+    fn on_undeliverable(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        dest: B::Address,
+        request: Self::Request,
+    ) -> Result<(), Self::Error> {
+        log!(self.level, "Undeliverable {} from {} to {} on bus {}", request, source, dest, bus_id);
+        self.inner.on_undeliverable(endpoints, bus_id, source, dest, request)
+    }
+
+    fn on_sequence_gap(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        peer: B::Address,
+        expected: u64,
+        got: u64,
+    ) -> Result<(), Self::Error> {
+        log!(
+            self.level,
+            "Sequence gap from {} on bus {}: expected {}, got {}",
+            peer,
+            bus_id,
+            expected,
+            got
+        );
+        self.inner.on_sequence_gap(endpoints, bus_id, peer, expected, got)
+    }
+
+    fn on_gap_detected(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        missing: Range<u64>,
+    ) -> Result<(), Self::Error> {
+        log!(self.level, "Missing messages {:?} from {} on bus {}", missing, source, bus_id);
+        self.inner.on_gap_detected(endpoints, bus_id, source, missing)
+    }
+
+    fn on_handler_panic(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        request: Self::Request,
+        panic: String,
+    ) -> Result<(), Self::Error> {
+        log!(
+            self.level,
+            "Handler panicked on {} from {} on bus {}: {}",
+            request,
+            source,
+            bus_id,
+            panic
+        );
+        self.inner.on_handler_panic(endpoints, bus_id, source, request, panic)
+    }
+
+    fn on_version_mismatch(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        version: u8,
+    ) -> Result<(), Self::Error> {
+        log!(
+            self.level,
+            "Unsupported envelope version {} from {} on bus {}",
+            version,
+            source,
+            bus_id
+        );
+        self.inner.on_version_mismatch(endpoints, bus_id, source, version)
+    }
+
+    fn on_message_dropped(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        dest: B::Address,
+    ) -> Result<(), Self::Error> {
+        log!(self.level, "Message to {} on bus {} was dropped at high water mark", dest, bus_id);
+        self.inner.on_message_dropped(endpoints, bus_id, dest)
+    }
+
+    fn on_slow_consumer(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        dest: B::Address,
+    ) -> Result<(), Self::Error> {
+        log!(self.level, "{} on bus {} is a slow consumer", dest, bus_id);
+        self.inner.on_slow_consumer(endpoints, bus_id, dest)
+    }
+
+    fn on_access_denied(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        request: Self::Request,
+    ) -> Result<(), Self::Error> {
+        log!(self.level, "Access denied for {} from {} on bus {}", request, source, bus_id);
+        self.inner.on_access_denied(endpoints, bus_id, source, request)
+    }
+
+    fn cached_response(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        key: &[u8],
+    ) -> Result<Option<Self::Request>, Self::Error> {
+        log!(self.level, "Repeated request from {} on bus {} is skipped", source, bus_id);
+        self.inner.cached_response(endpoints, bus_id, source, key)
+    }
+}
+
+/// Layer collecting request processing statistics of the inner handler
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct MetricsLayer;
+
+impl<H> Layer<H> for MetricsLayer {
+    type Handler = Metrics<H>;
+
+    fn layer(self, inner: H) -> Self::Handler {
+        Metrics {
+            inner,
+            requests: 0,
+            failed_requests: 0,
+            errors: 0,
+            handle_time: Duration::default(),
+        }
+    }
+}
+
+/// Handler produced by [`MetricsLayer`]
+#[derive(Getters, Clone, Debug)]
+pub struct Metrics<H> {
+    inner: H,
+    /// Number of requests processed by the inner handler
+    requests: u64,
+    /// Number of requests for which the inner handler returned error
+    failed_requests: u64,
+    /// Number of ESB errors passed to the inner handler
+    errors: u64,
+    /// Total time spent in request processing by the inner handler
+    handle_time: Duration,
+}
+
+impl<B, H> Handler<B> for Metrics<H>
+where
+    B: BusId,
+    H: Handler<B>,
+    Error<B::Address>: From<H::Error>,
+{
+    type Request = H::Request;
+    type Error = H::Error;
+
+    fn identity(&self) -> B::Address { self.inner.identity() }
+
+    fn on_ready(&mut self, endpoints: &mut EndpointList<B>) -> Result<(), Self::Error> {
+        self.inner.on_ready(endpoints)
+    }
+
+    fn handle(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        request: Self::Request,
+    ) -> Result<(), Self::Error> {
+        let start = Instant::now();
+        let res = self.inner.handle(endpoints, bus_id, source, request);
+        self.handle_time += start.elapsed();
+        self.requests += 1;
+        if res.is_err() {
+            self.failed_requests += 1;
+        }
+        res
+    }
+
+    fn handle_err(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        error: Error<B::Address>,
+    ) -> Result<(), Self::Error> {
+        self.errors += 1;
+        self.inner.handle_err(endpoints, error)
+    }
+
+    fn on_corrupt_frame(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+    ) -> Result<(), Self::Error> {
+        self.inner.on_corrupt_frame(endpoints, bus_id, source)
+    }
+
+    fn on_undeliverable(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        dest: B::Address,
+        request: Self::Request,
+    ) -> Result<(), Self::Error> {
+        self.inner.on_undeliverable(endpoints, bus_id, source, dest, request)
+    }
+
+    fn on_sequence_gap(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        peer: B::Address,
+        expected: u64,
+        got: u64,
+    ) -> Result<(), Self::Error> {
+        self.inner.on_sequence_gap(endpoints, bus_id, peer, expected, got)
+    }
+
+    fn on_gap_detected(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        missing: Range<u64>,
+    ) -> Result<(), Self::Error> {
+        self.inner.on_gap_detected(endpoints, bus_id, source, missing)
+    }
+
+    fn on_handler_panic(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        request: Self::Request,
+        panic: String,
+    ) -> Result<(), Self::Error> {
+        self.inner.on_handler_panic(endpoints, bus_id, source, request, panic)
+    }
+
+    fn on_version_mismatch(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        version: u8,
+    ) -> Result<(), Self::Error> {
+        self.inner.on_version_mismatch(endpoints, bus_id, source, version)
+    }
+
+    fn on_message_dropped(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        dest: B::Address,
+    ) -> Result<(), Self::Error> {
+        self.inner.on_message_dropped(endpoints, bus_id, dest)
+    }
+
+    fn on_slow_consumer(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        dest: B::Address,
+    ) -> Result<(), Self::Error> {
+        self.inner.on_slow_consumer(endpoints, bus_id, dest)
+    }
+
+    fn on_access_denied(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        request: Self::Request,
+    ) -> Result<(), Self::Error> {
+        self.inner.on_access_denied(endpoints, bus_id, source, request)
+    }
+
+    fn cached_response(
+        &mut self,
+        endpoints: &mut EndpointList<B>,
+        bus_id: B,
+        source: B::Address,
+        key: &[u8],
+    ) -> Result<Option<Self::Request>, Self::Error> {
+        self.inner.cached_response(endpoints, bus_id, source, key)
+    }
+}
@@ -11,12 +11,63 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+mod breaker;
+#[cfg(feature = "compression")]
+mod compression;
 mod controller;
+mod curve;
+mod envelope;
+mod events;
+mod fragment;
+mod header;
+mod histogram;
+mod integrity;
+mod interrupt;
+pub mod layer;
+mod ping;
+mod prepared;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod retry;
+mod routing;
+#[cfg(feature = "test-utils")]
+pub mod testing;
+mod transform;
+mod watchdog;
+#[cfg(feature = "node")]
+mod worker;
+mod zap;
 use std::fmt::{Debug, Display};
 use std::hash::Hash;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
 
-pub use controller::{Controller, EndpointList, Handler};
+pub use breaker::{CircuitBreakerConfig, CircuitState};
+#[cfg(feature = "wire-trace")]
+pub use controller::RawTap;
+pub use controller::{
+    AccessControl, AddressRewriter, Controller, Direction, EndpointList, Handler, MAX_LOOKAHEAD,
+};
+pub use curve::{CurveKeyError, CurveKeys, CURVE_KEY_LEN, CURVE_KEY_Z85_LEN};
+pub use envelope::Envelope;
+pub use events::{ConnEvent, ConnEventKind, CONN_EVENT_LOG_LEN};
+pub use fragment::FragmentConfig;
+pub use header::{Header, ENVELOPE_VERSION};
+pub use histogram::{LatencyHistogram, MessageSizes, SizeHistogram};
+pub use integrity::IntegrityMode;
 use internet2::{presentation, transport, zmqsocket};
+pub use interrupt::InterruptHandle;
+pub use layer::{Layer, LoggingLayer, MetricsLayer};
+pub use ping::RttStats;
+pub use prepared::PreparedMessage;
+pub use retry::RetryPolicy;
+pub use routing::RoutingTable;
+pub use transform::{RecvTransform, SendTransform};
+pub use watchdog::{Watchdog, WatchdogAction, WatchdogConfig};
+#[cfg(feature = "node")]
+pub use worker::{Outbox, Worker};
+pub use zap::{StaticAllowlist, ZapAuthenticator, ZapHandler};
 
 /// Marker traits for service bus identifiers
 pub trait BusId: Copy + Eq + Hash + Display {
@@ -29,7 +80,107 @@ where
     A: ServiceAddress,
 {
     pub carrier: zmqsocket::Carrier,
+    /// Additional locators to which the bus socket binds or connects together
+    /// with the carrier locator, making a single bus reachable over several
+    /// transports (like TCP and IPC). Applies only to buses with the carrier
+    /// specified by locator.
+    pub extra_locators: Vec<zmqsocket::ZmqSocketAddr>,
     pub router: Option<A>,
+    /// Identity of this service on the bus overriding the handler identity.
+    /// Allows a service to present different identities on different buses.
+    pub identity: Option<A>,
+    /// Integrity protection for the messages sent over the bus; the same mode
+    /// must be configured by all bus participants
+    pub integrity: Option<IntegrityMode>,
+    /// Indicates whether each message on the bus is prepended with a
+    /// [`Header`]; the same setting must be used by all bus participants
+    pub headers: bool,
+    /// Indicates whether messages sent over the bus are numbered per each
+    /// pair of services, allowing receivers to detect missed messages and
+    /// peer restarts (see [`Handler::on_sequence_gap`]). Requires `headers`.
+    pub sequenced: bool,
+    /// Indicates whether a message must not be sent if it is identical to the
+    /// previous message sent by this service to the same destination. Useful
+    /// for buses with idempotent state pushes.
+    pub dedup: bool,
+    /// Number of the last sent messages kept by the sender for retransmission
+    /// to the receivers which have missed them, for instance slow PUB/SUB
+    /// subscribers (see [`Handler::on_gap_detected`] and
+    /// [`Controller::retransmit`]). Requires `sequenced`.
+    pub retransmit_buffer: Option<usize>,
+    /// Interval between attempts to reconnect a disconnected socket
+    /// (`ZMQ_RECONNECT_IVL`); `None` keeps the ZMQ default
+    pub reconnect_ivl: Option<Duration>,
+    /// Maximum interval between reconnection attempts, up to which the
+    /// interval is exponentially backed off (`ZMQ_RECONNECT_IVL_MAX`); must
+    /// not be less than `reconnect_ivl`. `None` keeps the ZMQ default, which
+    /// disables backoff.
+    pub reconnect_ivl_max: Option<Duration>,
+    /// High water mark for both inbound and outbound messages
+    /// (`ZMQ_RCVHWM`/`ZMQ_SNDHWM`); `None` keeps the ZMQ default
+    pub hwm: Option<usize>,
+    /// Makes the socket queue messages only to the peers with completed
+    /// connections (`ZMQ_IMMEDIATE`), instead of buffering them for the
+    /// peers which are not connected yet.
+    ///
+    /// For connecting sockets, sends with no active connection fail instead
+    /// of being queued until the connection is established. ROUTER sockets
+    /// fail sends to unknown peers only if [`BusConfig::queued`] is `false`
+    /// (`ZMQ_ROUTER_MANDATORY`), otherwise such messages are silently
+    /// dropped; thus `immediate` together with non-queued mode gives
+    /// deterministic "send only if connected" behaviour.
+    pub immediate: bool,
+    /// Detection of slow consumers: destinations to which sends are
+    /// repeatedly rejected due to the high water mark (see
+    /// [`Handler::on_slow_consumer`]); `None` disables detection
+    pub slow_consumer: Option<SlowConsumerConfig>,
+    /// Transparent fragmentation of the messages exceeding the configured
+    /// threshold into multiple frames, which are reassembled by the receiver
+    /// before being dispatched; `None` disables fragmentation. Must be
+    /// enabled by all bus participants.
+    pub fragmentation: Option<FragmentConfig>,
+    /// Size threshold above which serialized messages are compressed with
+    /// LZ4 before being sent; smaller messages are sent as is. `None`
+    /// disables compression. Must be enabled by all bus participants.
+    #[cfg(feature = "compression")]
+    pub compress_over: Option<usize>,
+    /// Maximal size of the decompressed message; compressed messages which
+    /// would exceed it are dropped as malformed. Used only if compression is
+    /// enabled with `compress_over`.
+    #[cfg(feature = "compression")]
+    pub max_decompressed_size: usize,
+    /// Circuit breaking for the destinations to which sends repeatedly fail;
+    /// `None` disables circuit breaking
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Makes the connecting socket send an empty probe message on connection
+    /// (`ZMQ_PROBE_ROUTER`), so the router it connects to learns its
+    /// identity immediately and may push messages to it before it sends
+    /// anything. Applies only to buses connecting by locator; probes are
+    /// discarded by the receiving controller.
+    pub probe_router: bool,
+    /// Enables in-band latency probes (see [`Controller::ping_rtt`]) on the
+    /// bus. Single-frame messages starting with the reserved prefix
+    /// `\xFF\xFFesb-ping` (message type `0xFFFF`) are then answered or
+    /// forwarded by the controller itself and never passed to the handler,
+    /// so service requests must not use message type `0xFFFF`. Must be
+    /// enabled on all the services measuring or answering the probes, as well
+    /// as on the bus routers forwarding them. Disabled by default, so all
+    /// messages are passed to the handler as is.
+    pub latency_probes: bool,
+    /// Defers creation of the bus socket until the bus is first used for
+    /// sending or polling by the controller (see
+    /// [`Controller::establish_bus`]), reducing startup time and number of
+    /// open file descriptors for services with many optional buses
+    pub lazy: bool,
+    /// Number of recently executed idempotency keys (see
+    /// [`Header::idempotency_key`]) remembered by the controller; messages
+    /// with a remembered key are not passed to [`Handler::handle`] again.
+    /// `None` disables the check. Requires headers to be enabled.
+    pub idempotency_window: Option<usize>,
+    /// Indicates whether received messages which can't be decoded must be
+    /// logged and skipped instead of failing the receive operation. Useful
+    /// for shared buses where some frames are not addressed to this service.
+    pub skip_undecodable: bool,
     /// Indicates whether the messages must be queued, or the send function
     /// must fail immediatelly if the remote point is not avaliable
     pub queued: bool,
@@ -40,18 +191,285 @@ where
     A: ServiceAddress,
 {
     pub fn with_locator(locator: zmqsocket::ZmqSocketAddr, router: Option<A>) -> Self {
-        Self { carrier: zmqsocket::Carrier::Locator(locator), router, queued: false }
+        Self::with_carrier(zmqsocket::Carrier::Locator(locator), router)
     }
 
     pub fn with_socket(socket: zmq::Socket, router: Option<A>) -> Self {
-        Self { carrier: zmqsocket::Carrier::Socket(socket), router, queued: false }
+        Self::with_carrier(zmqsocket::Carrier::Socket(socket), router)
     }
+
+    fn with_carrier(carrier: zmqsocket::Carrier, router: Option<A>) -> Self {
+        Self {
+            carrier,
+            extra_locators: vec![],
+            router,
+            identity: None,
+            integrity: None,
+            headers: false,
+            sequenced: false,
+            dedup: false,
+            retransmit_buffer: None,
+            reconnect_ivl: None,
+            reconnect_ivl_max: None,
+            hwm: None,
+            immediate: false,
+            probe_router: false,
+            latency_probes: false,
+            lazy: false,
+            idempotency_window: None,
+            skip_undecodable: false,
+            slow_consumer: None,
+            fragmentation: None,
+            #[cfg(feature = "compression")]
+            compress_over: None,
+            #[cfg(feature = "compression")]
+            max_decompressed_size: 64 * 1024 * 1024,
+            circuit_breaker: None,
+            queued: false,
+        }
+    }
+
+    /// Parses bus configuration from URL like
+    /// `tcp://127.0.0.1:5555?router=<addr>&hwm=1000&mode=queued`, allowing to
+    /// specify service buses in environment variables or configuration files.
+    ///
+    /// Supported schemes are `tcp`, `ipc` and `inproc`. Supported query
+    /// parameters are:
+    /// - `router`: bus router address ([`BusConfig::router`]);
+    /// - `identity`: identity of this service on the bus ([`BusConfig::identity`]);
+    /// - `hwm`: high water mark ([`BusConfig::hwm`]);
+    /// - `mode`: either `queued` or `failfast` ([`BusConfig::queued`]).
+    ///
+    /// Note that `mode` is unrelated to [`BusConfig::immediate`], which has
+    /// no URL parameter.
+    pub fn from_url(s: &str) -> Result<Self, BusUrlError> {
+        let (locator, query) = match s.split_once('?') {
+            Some((locator, query)) => (locator, Some(query)),
+            None => (s, None),
+        };
+        let scheme = locator.split_once("://").map(|(scheme, _)| scheme).unwrap_or_default();
+        if !["tcp", "ipc", "inproc"].contains(&scheme) {
+            return Err(BusUrlError::UnknownScheme(scheme.to_owned()));
+        }
+        let locator = zmqsocket::ZmqSocketAddr::from_str(locator)
+            .map_err(|_| BusUrlError::InvalidLocator(locator.to_owned()))?;
+        let mut config = BusConfig::with_locator(locator, None);
+        for param in query.into_iter().flat_map(|query| query.split('&')) {
+            if param.is_empty() {
+                continue;
+            }
+            let (name, value) = param
+                .split_once('=')
+                .ok_or_else(|| BusUrlError::InvalidParameter(param.to_owned()))?;
+            let invalid = || BusUrlError::InvalidValue(name.to_owned(), value.to_owned());
+            match name {
+                "router" if !value.is_empty() => {
+                    config.router = Some(A::from(value.as_bytes().to_vec()))
+                }
+                "identity" if !value.is_empty() => {
+                    config.identity = Some(A::from(value.as_bytes().to_vec()))
+                }
+                "hwm" => config.hwm = Some(value.parse().map_err(|_| invalid())?),
+                "mode" => {
+                    config.queued = match value {
+                        "queued" => true,
+                        "failfast" => false,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "router" | "identity" => return Err(invalid()),
+                _ => return Err(BusUrlError::UnknownParameter(name.to_owned())),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Errors parsing service bus URL with [`BusConfig::from_url`]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum BusUrlError {
+    /// unknown service bus URL scheme `{0}`; supported schemes are `tcp`,
+    /// `ipc` and `inproc`
+    UnknownScheme(String),
+
+    /// invalid service bus socket locator `{0}`
+    InvalidLocator(String),
+
+    /// service bus URL parameter `{0}` must have form `name=value`
+    InvalidParameter(String),
+
+    /// unknown service bus URL parameter `{0}`
+    UnknownParameter(String),
+
+    /// invalid value `{1}` for service bus URL parameter `{0}`
+    InvalidValue(String, String),
+}
+
+/// Configuration of the ZMQ context backing service bus sockets. The context
+/// owns ZMQ IO thread pool, so it must be tuned before any socket is created
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ContextConfig {
+    /// Number of ZMQ IO threads used by the context
+    pub io_threads: i32,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self { Self { io_threads: 1 } }
+}
+
+impl ContextConfig {
+    /// Creates new ZMQ context according to the configuration. The returned
+    /// context may be cloned and shared between multiple controllers.
+    pub fn context(&self) -> Result<zmq::Context, zmq::Error> {
+        let context = zmq::Context::new();
+        context.set_io_threads(self.io_threads)?;
+        Ok(context)
+    }
+}
+
+/// Controller-wide configuration
+#[derive(Clone, Default)]
+pub struct ControllerConfig {
+    /// ZMQ context used for creating sockets of the service buses specified
+    /// with locators. If `None`, the default global context is used.
+    pub context: Option<zmq::Context>,
+
+    /// Maximum number of service buses the controller may have. Protects
+    /// from exhausting file descriptors by a runaway bus registration; `None`
+    /// means no limit. Lazy buses (see [`BusConfig::lazy`]) are counted since
+    /// their registration, even before their sockets are created.
+    pub max_buses: Option<usize>,
+
+    /// Watchdog detecting stalls of the run loop, for instance caused by a
+    /// blocked handler; `None` disables the watchdog
+    pub watchdog: Option<WatchdogConfig>,
+
+    /// Indicates whether panics in [`Handler::handle`] must be caught, so the
+    /// request which has caused the panic is reported to
+    /// [`Handler::on_handler_panic`] and skipped instead of bringing down the
+    /// whole service. Disabled by default for fail-fast behaviour.
+    pub catch_panics: bool,
+
+    /// Latest message envelope version used by the controller on buses with
+    /// headers, allowing to keep using the older format until all services
+    /// are upgraded. `None` means [`ENVELOPE_VERSION`]. When talking to a peer
+    /// which has sent messages with an older version, the controller
+    /// downgrades to that version.
+    pub envelope_version: Option<u8>,
+
+    /// Number of worker threads processing requests addressed to this
+    /// service once a [`Worker`] is set with [`Controller::set_worker`];
+    /// `None` means a single worker thread. The controller thread keeps
+    /// owning the sockets, receiving and routing messages and sending the
+    /// requests passed by the workers through their [`Outbox`].
+    ///
+    /// Requests are partitioned between the workers by their source, so the
+    /// requests from the same source are processed in order, while requests
+    /// from different sources may be processed in any order.
+    #[cfg(feature = "node")]
+    pub worker_threads: Option<usize>,
+
+    /// Maximum time [`Handler::on_ready`] may take; `None` means no limit.
+    /// The limit is a single deadline for the whole initialization: each
+    /// blocking send on the service buses may take only the time remaining
+    /// until it, and once it is exhausted bus operations fail, making the run
+    /// loop fail with [`Error::InitTimeout`]. Initialization which completes
+    /// successfully is never reported as timed out. The limit is enforced
+    /// only on the service bus operations: the handler blocked on anything
+    /// else is not interrupted.
+    pub init_timeout: Option<Duration>,
+}
+
+/// Role of the service on a specific service bus, defined by the bus router
+/// configuration
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+pub enum BusRole {
+    /// The service is the bus router, forwarding messages of other services
+    #[display("hub")]
+    Hub,
+
+    /// The service sends all its messages via the bus router
+    #[display("leaf")]
+    Leaf,
+
+    /// The bus has no router, so messages are sent to their destinations
+    /// directly
+    #[display("direct")]
+    Direct,
+}
+
+/// Configuration of slow consumer detection (see
+/// [`BusConfig::slow_consumer`])
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SlowConsumerConfig {
+    /// Number of sends to the same destination rejected due to the high
+    /// water mark after which the destination is reported as a slow consumer
+    pub threshold: usize,
+    /// Time window within which the rejected sends are counted
+    pub window: Duration,
+}
+
+/// Message counters of a single service bus
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct BusStats {
+    /// Number of messages received from the bus
+    pub received: u64,
+    /// Total size of the messages received from the bus, in bytes
+    pub received_bytes: u64,
+    /// Number of messages sent to the bus
+    pub sent: u64,
+    /// Total size of the messages sent to the bus, in bytes
+    pub sent_bytes: u64,
+    /// Number of messages which were not sent due to an error
+    pub send_errors: u64,
+    /// Total original size of the messages which were compressed before
+    /// sending (see [`BusConfig::compress_over`]), in bytes
+    pub uncompressed_bytes: u64,
+    /// Total size of the messages which were compressed before sending, after
+    /// the compression, in bytes
+    pub compressed_bytes: u64,
+    /// Number of sends skipped since the message was identical to the
+    /// previous one sent to the same destination (see
+    /// [`BusConfig::dedup`])
+    pub suppressed: u64,
+    /// Number of sends rejected since the outgoing queue has reached its high
+    /// water mark (see [`BusConfig::hwm`])
+    pub dropped: u64,
+}
+
+/// Statistics reported by [`Controller`] when its run loop is shut down
+#[cfg(feature = "node")]
+#[derive(Clone, Debug)]
+pub struct ShutdownReport<B>
+where
+    B: BusId,
+{
+    /// Reason for the shutdown
+    pub reason: crate::node::ShutdownReason,
+    /// Time the run loop was running
+    pub uptime: std::time::Duration,
+    /// Total number of requests addressed to this service which were
+    /// dispatched by the run loop to the handler (or to the workers). Unlike
+    /// the received message counters of [`ShutdownReport::buses`], it does
+    /// not include routed, skipped or rejected messages.
+    pub processed: u64,
+    /// Message counters for each of the service buses
+    pub buses: std::collections::HashMap<B, BusStats>,
 }
 
 /// Marker traits for service bus identifiers
 pub trait ServiceAddress:
     Clone + Eq + Hash + Debug + Display + Into<Vec<u8>> + From<Vec<u8>>
 {
+    /// Returns prefix of a hierarchical address (like
+    /// `region.service.instance`) consisting of its first `levels` levels,
+    /// which is used for matching routes in a [`RoutingTable`]. Addresses
+    /// with less levels must be returned as is.
+    ///
+    /// By default addresses are not hierarchical and the full address is
+    /// returned, so routes match exact addresses only.
+    fn route_prefix(&self, _levels: usize) -> Self { self.clone() }
 }
 
 /// Errors happening with RPC APIs
@@ -75,10 +493,70 @@ pub enum Error<A: ServiceAddress> {
     /// provided service bus id {0} is unknown
     UnknownBusId(String),
 
+    /// limit of {0} service buses is reached
+    BusLimitReached(usize),
+
+    /// service bus {0} has no router configured
+    NoRouterConfigured(String),
+
+    /// service bus is paused
+    BusPaused,
+
+    /// connection monitoring is not available for service bus {0}, since it
+    /// was created from an externally provided socket
+    MonitorUnavailable(String),
+
+    /// service bus {0} uses receive-only socket and can't be used for sending
+    BusNotSendable(String),
+
+    /// service bus {0} uses send-only socket and can't be used for receiving
+    BusNotReceivable(String),
+
+    /// latency probes are not enabled for service bus {0}
+    ProbesDisabled(String),
+
+    /// destination pool has no destinations with non-zero weight
+    EmptyPool,
+
+    /// no service address is registered under alias `{0}`
+    UnknownAlias(String),
+
+    /// circuit for destination {0} is open after repeated send failures
+    CircuitOpen(A),
+
+    /// peer {0} was disconnected from the service bus
+    PeerDisconnected(A),
+
+    /// handler initialization has not completed within {0:?}
+    InitTimeout(Duration),
+
+    /// malformed message header: {0}
+    Header(String),
+
+    /// invalid value for socket option {0}: {1}
+    InvalidSocketOption(&'static str, String),
+
+    /// sending has failed after {0} attempt(s). Details: {1}
+    SendFailed(usize, Box<Error<A>>),
+
     /// {0}
     ServiceError(String),
 }
 
+impl<A: ServiceAddress> Error<A> {
+    /// Checks whether the error is temporary, so the operation may succeed
+    /// if retried: outgoing queue is full or the call was interrupted
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            Error::Send(_, _, err) | Error::Transport(err) => matches!(
+                transport_error_kind(err),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            ),
+            _ => false,
+        }
+    }
+}
+
 impl<A: ServiceAddress> From<zmq::Error> for Error<A> {
     fn from(err: zmq::Error) -> Self { Error::Transport(transport::Error::from(err)) }
 }
@@ -91,3 +569,60 @@ impl<A: ServiceAddress> From<presentation::Error> for Error<A> {
         }
     }
 }
+
+/// Converts ESB error into I/O error, mapping connection-related failures onto
+/// the matching [`io::ErrorKind`]s, so the error can be bubbled up with `?`
+/// into I/O-centric APIs.
+impl<A: ServiceAddress> From<Error<A>> for io::Error {
+    fn from(err: Error<A>) -> Self {
+        let kind = match err {
+            Error::Send(_, _, ref err) | Error::Transport(ref err) => transport_error_kind(err),
+            Error::UnexpectedServerResponse | Error::Presentation(_) | Error::Header(_) => {
+                io::ErrorKind::InvalidData
+            }
+            Error::UnknownBusId(_) | Error::UnknownAlias(_) => io::ErrorKind::NotFound,
+            Error::PeerDisconnected(_) => io::ErrorKind::NotConnected,
+            Error::InitTimeout(_) => io::ErrorKind::TimedOut,
+            Error::SendFailed(_, ref err) => io::Error::from((**err).clone()).kind(),
+            Error::BusPaused | Error::CircuitOpen(_) => io::ErrorKind::WouldBlock,
+            Error::BusNotSendable(_) | Error::BusNotReceivable(_) | Error::ProbesDisabled(_) => {
+                io::ErrorKind::Unsupported
+            }
+            Error::InvalidSocketOption(..) | Error::NoRouterConfigured(_) | Error::EmptyPool => {
+                io::ErrorKind::InvalidInput
+            }
+            Error::BusLimitReached(_) | Error::MonitorUnavailable(_) | Error::ServiceError(_) => {
+                io::ErrorKind::Other
+            }
+        };
+        io::Error::new(kind, err.to_string())
+    }
+}
+
+fn transport_error_kind(err: &transport::Error) -> io::ErrorKind {
+    match err {
+        transport::Error::TimedOut => io::ErrorKind::TimedOut,
+        transport::Error::SocketIo(kind) => *kind,
+        transport::Error::Zmq(err) => zmq_error_kind(*err),
+        _ => io::ErrorKind::Other,
+    }
+}
+
+fn zmq_error_kind(err: zmq::Error) -> io::ErrorKind {
+    match err {
+        zmq::Error::EAGAIN => io::ErrorKind::WouldBlock,
+        zmq::Error::EINTR => io::ErrorKind::Interrupted,
+        zmq::Error::ETIMEDOUT => io::ErrorKind::TimedOut,
+        zmq::Error::ECONNREFUSED => io::ErrorKind::ConnectionRefused,
+        zmq::Error::ECONNRESET => io::ErrorKind::ConnectionReset,
+        zmq::Error::ECONNABORTED => io::ErrorKind::ConnectionAborted,
+        // Returned for unroutable messages when `ZMQ_ROUTER_MANDATORY` is set
+        zmq::Error::EHOSTUNREACH | zmq::Error::ENOTCONN => io::ErrorKind::NotConnected,
+        zmq::Error::EADDRINUSE => io::ErrorKind::AddrInUse,
+        zmq::Error::EADDRNOTAVAIL => io::ErrorKind::AddrNotAvailable,
+        zmq::Error::EINVAL => io::ErrorKind::InvalidInput,
+        // ZMQ context was terminated
+        zmq::Error::ETERM => io::ErrorKind::BrokenPipe,
+        _ => io::ErrorKind::Other,
+    }
+}
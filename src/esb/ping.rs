@@ -0,0 +1,99 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! In-band round-trip latency probes (see [`super::Controller::ping_rtt`])
+
+use std::convert::TryInto;
+use std::time::Duration;
+
+/// Marker starting the probe messages. Message type `0xFFFF` is reserved for
+/// the probes and must not be used by the service requests on the buses with
+/// latency probes enabled (see [`super::BusConfig::latency_probes`]).
+const PROBE_MAGIC: &[u8] = b"\xFF\xFFesb-ping";
+
+/// Length of the probe message: magic, probe kind and nonce
+const PROBE_LEN: usize = PROBE_MAGIC.len() + 1 + 8;
+
+/// Time to wait for a reply to a single probe
+pub(super) const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Kind of round-trip latency probe
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub(super) enum Probe {
+    /// Request which must be echoed back to its source
+    Ping,
+    /// Echo of a received [`Probe::Ping`]
+    Pong,
+}
+
+impl Probe {
+    /// Encodes probe message with the given `nonce`
+    pub fn encode(self, nonce: u64) -> Vec<u8> {
+        let mut data = Vec::with_capacity(PROBE_LEN);
+        data.extend_from_slice(PROBE_MAGIC);
+        data.push(match self {
+            Probe::Ping => 0,
+            Probe::Pong => 1,
+        });
+        data.extend_from_slice(&nonce.to_be_bytes());
+        data
+    }
+
+    /// Parses probe message, returning `None` if the data is not a probe
+    pub fn parse(data: &[u8]) -> Option<(Probe, u64)> {
+        if data.len() != PROBE_LEN || !data.starts_with(PROBE_MAGIC) {
+            return None;
+        }
+        let probe = match data[PROBE_MAGIC.len()] {
+            0 => Probe::Ping,
+            1 => Probe::Pong,
+            _ => return None,
+        };
+        let nonce = u64::from_be_bytes(data[PROBE_MAGIC.len() + 1..].try_into().ok()?);
+        Some((probe, nonce))
+    }
+}
+
+/// Statistics of round-trip times measured with
+/// [`super::Controller::ping_rtt`]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct RttStats {
+    /// Number of measured round trips
+    pub samples: usize,
+    /// Minimal round-trip time
+    pub min: Duration,
+    /// Median round-trip time
+    pub median: Duration,
+    /// Maximal round-trip time
+    pub max: Duration,
+    /// 99th percentile of round-trip times
+    pub p99: Duration,
+}
+
+impl RttStats {
+    /// Computes statistics of the measured round-trip times
+    pub fn with(mut rtts: Vec<Duration>) -> Self {
+        if rtts.is_empty() {
+            return RttStats::default();
+        }
+        rtts.sort_unstable();
+        let n = rtts.len();
+        RttStats {
+            samples: n,
+            min: rtts[0],
+            median: rtts[n / 2],
+            max: rtts[n - 1],
+            p99: rtts[(n * 99 + 99) / 100 - 1],
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::fmt::{self, Display, Formatter};
+
+use crate::rpc_connection::Request;
+
+/// Request serialized once for being sent multiple times, for instance when
+/// broadcasting it to many destinations or resending it on retry. See
+/// [`super::Controller::send_prepared`].
+///
+/// The message owns serialized data, so it does not borrow the original
+/// request and may be kept and reused across any number of sends.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct PreparedMessage {
+    data: Vec<u8>,
+    /// Request description used for logging
+    name: String,
+}
+
+impl PreparedMessage {
+    /// Serializes the request
+    pub fn with<R>(request: &R) -> Self
+    where
+        R: Request,
+    {
+        Self { data: request.serialize(), name: request.to_string() }
+    }
+
+    /// Returns serialized request data
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] { &self.data }
+
+    /// Returns length of the serialized request data
+    #[inline]
+    pub fn len(&self) -> usize { self.data.len() }
+
+    /// Detects whether the serialized request data are empty
+    #[inline]
+    pub fn is_empty(&self) -> bool { self.data.is_empty() }
+
+    #[inline]
+    pub(super) fn to_bytes(&self) -> Vec<u8> { self.data.clone() }
+}
+
+impl Display for PreparedMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result { f.write_str(&self.name) }
+}
@@ -0,0 +1,98 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Rendering of service bus metrics in Prometheus text exposition format
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use super::{BusId, BusStats, LatencyHistogram};
+
+/// Renders counters of the service buses in Prometheus text exposition
+/// format, labelling them with the bus id
+pub(super) fn render<B>(stats: &HashMap<B, BusStats>) -> String
+where
+    B: BusId,
+{
+    let mut buses =
+        stats.iter().map(|(bus_id, stats)| (bus_id.to_string(), stats)).collect::<Vec<_>>();
+    buses.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    // Metrics rendered for each service bus: name, help text and the counter
+    let metrics: [(&str, &str, fn(&BusStats) -> u64); 7] = [
+        ("esb_messages_received_total", "Messages received from the bus", |s| s.received),
+        ("esb_bytes_received_total", "Bytes received from the bus", |s| s.received_bytes),
+        ("esb_messages_sent_total", "Messages sent to the bus", |s| s.sent),
+        ("esb_bytes_sent_total", "Bytes sent to the bus", |s| s.sent_bytes),
+        ("esb_send_errors_total", "Failed message sends", |s| s.send_errors),
+        ("esb_messages_dropped_total", "Messages dropped at high water mark", |s| s.dropped),
+        ("esb_messages_suppressed_total", "Duplicated messages not sent", |s| s.suppressed),
+    ];
+
+    let mut out = String::new();
+    for (name, help, counter) in metrics.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (bus, stats) in &buses {
+            let _ = writeln!(out, "{}{{bus=\"{}\"}} {}", name, escape_label(bus), counter(stats));
+        }
+    }
+    out
+}
+
+/// Renders histograms of the time spent by the handler processing requests in
+/// Prometheus text exposition format, labelling them with the request type
+pub(super) fn render_latency(latency: &HashMap<u16, LatencyHistogram>) -> String {
+    let mut types = latency.iter().collect::<Vec<_>>();
+    types.sort_by_key(|(request_type, _)| **request_type);
+
+    let name = "esb_handle_duration_seconds";
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP {} Time spent by the handler processing requests", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (request_type, histogram) in types {
+        let mut cumulative = 0u64;
+        for (max, count) in histogram.buckets() {
+            let max = match max {
+                Some(max) => max,
+                // Requests from the unbounded bucket are counted in `+Inf`
+                None => continue,
+            };
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{type=\"{}\",le=\"{}\"}} {}",
+                name,
+                request_type,
+                max.as_secs_f64(),
+                cumulative
+            );
+        }
+        let count = histogram.count();
+        let _ = writeln!(out, "{}_bucket{{type=\"{}\",le=\"+Inf\"}} {}", name, request_type, count);
+        let _ = writeln!(
+            out,
+            "{}_sum{{type=\"{}\"}} {}",
+            name,
+            request_type,
+            histogram.total().as_secs_f64()
+        );
+        let _ = writeln!(out, "{}_count{{type=\"{}\"}} {}", name, request_type, count);
+    }
+    out
+}
+
+/// Escapes label value according to the exposition format
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,55 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::time::Duration;
+
+/// Policy of retrying message sends which have failed due to recoverable
+/// errors, like full outgoing queue or interrupted system call (see
+/// [`super::Error::is_recoverable`] and [`super::Controller::send_to_retry`]).
+///
+/// Delay between the attempts starts with `backoff` and doubles after each
+/// failed attempt, up to `max_backoff`.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct RetryPolicy {
+    /// Maximal number of send attempts, including the first one
+    pub max_attempts: usize,
+
+    /// Delay before the first retry
+    pub backoff: Duration,
+
+    /// Maximal delay between the attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Constructs policy making at most `max_attempts` send attempts with
+    /// the default backoff
+    pub fn with_attempts(max_attempts: usize) -> Self { RetryPolicy { max_attempts, ..default!() } }
+
+    /// Returns delay before the next attempt after `attempt` attempts have
+    /// failed
+    pub(super) fn delay(&self, attempt: usize) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1) as u32).unwrap_or(u32::MAX);
+        self.backoff.checked_mul(factor).unwrap_or(self.max_backoff).min(self.max_backoff)
+    }
+}
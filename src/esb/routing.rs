@@ -0,0 +1,90 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::ServiceAddress;
+
+/// Table of routes for messages sent directly (i.e. not via the bus router),
+/// matching destination addresses by their prefixes (see
+/// [`ServiceAddress::route_prefix`]). A message is sent to the next hop of the
+/// route with the longest matching prefix, or directly to its destination if
+/// no route matches.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RoutingTable<A>
+where
+    A: ServiceAddress,
+{
+    /// Next hops for address prefixes, grouped by the number of prefix levels
+    routes: BTreeMap<usize, HashMap<A, A>>,
+}
+
+impl<A> Default for RoutingTable<A>
+where
+    A: ServiceAddress,
+{
+    fn default() -> Self { RoutingTable { routes: default!() } }
+}
+
+impl<A> RoutingTable<A>
+where
+    A: ServiceAddress,
+{
+    /// Constructs empty routing table
+    pub fn new() -> Self { Self::default() }
+
+    /// Adds route sending messages for all destinations which have the first
+    /// `levels` of their address equal to `prefix` to `next_hop`. Returns
+    /// next hop of the replaced route, if any.
+    pub fn insert(&mut self, prefix: A, levels: usize, next_hop: A) -> Option<A> {
+        self.routes.entry(levels).or_default().insert(prefix, next_hop)
+    }
+
+    /// Removes route for the `prefix` of `levels`, returning its next hop
+    pub fn remove(&mut self, prefix: &A, levels: usize) -> Option<A> {
+        let routes = self.routes.get_mut(&levels)?;
+        let next_hop = routes.remove(prefix);
+        if routes.is_empty() {
+            self.routes.remove(&levels);
+        }
+        next_hop
+    }
+
+    /// Removes all routes with the given `next_hop`, returning number of the
+    /// removed routes
+    pub fn remove_next_hop(&mut self, next_hop: &A) -> usize {
+        let mut count = 0;
+        for routes in self.routes.values_mut() {
+            let before = routes.len();
+            routes.retain(|_, hop| hop != next_hop);
+            count += before - routes.len();
+        }
+        self.routes.retain(|_, routes| !routes.is_empty());
+        count
+    }
+
+    /// Returns next hop of the longest route matching `dest`, if any
+    pub fn lookup(&self, dest: &A) -> Option<&A> {
+        self.routes
+            .iter()
+            .rev()
+            .find_map(|(levels, routes)| routes.get(&dest.route_prefix(*levels)))
+    }
+
+    /// Returns address to which a message for `dest` must be sent: either a
+    /// next hop of the matching route or `dest` itself
+    pub fn next_hop(&self, dest: &A) -> A { self.lookup(dest).unwrap_or(dest).clone() }
+
+    /// Detects whether the table has no routes
+    pub fn is_empty(&self) -> bool { self.routes.is_empty() }
+}
@@ -0,0 +1,205 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Test harness for ESB handlers, pairing client and server controllers over
+//! inproc transport, so handlers can be integration-tested without manual
+//! socket setup.
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use internet2::zmqsocket;
+
+use super::{
+    BusConfig, BusId, Controller, ControllerConfig, EndpointList, Error, Handler, InterruptHandle,
+};
+use crate::node::{ShutdownSignal, TryService};
+use crate::rpc_connection::Request;
+
+/// Default time for awaiting the server reply
+pub const DEFAULT_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Handler of the client side of the harness. Replies are read by the harness
+/// directly, so the handler never processes requests.
+pub struct ClientHandler<B, R>
+where
+    B: BusId,
+{
+    identity: B::Address,
+    _phantom: PhantomData<R>,
+}
+
+impl<B, R> Handler<B> for ClientHandler<B, R>
+where
+    B: BusId,
+    R: Request,
+{
+    type Request = R;
+    type Error = Error<B::Address>;
+
+    fn identity(&self) -> B::Address { self.identity.clone() }
+
+    fn handle(
+        &mut self,
+        _endpoints: &mut EndpointList<B>,
+        _bus_id: B,
+        _source: B::Address,
+        _request: R,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn handle_err(
+        &mut self,
+        _endpoints: &mut EndpointList<B>,
+        _error: Error<B::Address>,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Pair of controllers connected over inproc transport: the server one runs
+/// the tested handler on a background thread, while the client one is driven
+/// by the test. The server is stopped when the harness is dropped.
+pub struct BusTestHarness<B, R>
+where
+    R: Request,
+    B: BusId,
+{
+    bus_id: B,
+    server: B::Address,
+    client: Controller<B, R, ClientHandler<B, R>>,
+    shutdown: ShutdownSignal,
+    interrupt: InterruptHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<B, R> BusTestHarness<B, R>
+where
+    R: Request + Send + 'static,
+    B: BusId + Send + 'static,
+    B::Address: Send,
+{
+    /// Starts server controller with `handler` on a background thread and
+    /// connects client controller having `client` address to it
+    pub fn with<H>(bus_id: B, handler: H, client: B::Address) -> Result<Self, Error<B::Address>>
+    where
+        H: Handler<B, Request = R> + Send + 'static,
+        Error<B::Address>: From<H::Error>,
+    {
+        static HARNESS_NO: AtomicUsize = AtomicUsize::new(0);
+        let locator = format!("inproc://esb-test-{}", HARNESS_NO.fetch_add(1, Ordering::Relaxed));
+        let locator =
+            zmqsocket::ZmqSocketAddr::from_str(&locator).expect("inproc locator is always valid");
+        let config = ControllerConfig { context: Some(zmq::Context::new()), ..default!() };
+        let server = handler.identity();
+
+        // inproc endpoint must be bound before connecting to it
+        let server_controller = Controller::with_config(
+            bus(bus_id, locator.clone()),
+            handler,
+            zmqsocket::ZmqType::RouterBind,
+            config.clone(),
+        )?;
+        let shutdown = server_controller.shutdown_signal();
+        let interrupt = server_controller.interrupt_handle();
+        let thread = thread::Builder::new()
+            .name(format!("esb-test-server-{}", bus_id))
+            .spawn(move || {
+                server_controller.run_or_panic("ESB test server");
+            })
+            .expect("unable to spawn test server thread");
+
+        let client = Controller::with_config(
+            bus(bus_id, locator),
+            ClientHandler { identity: client, _phantom: PhantomData },
+            zmqsocket::ZmqType::RouterConnect,
+            config,
+        )?;
+
+        Ok(BusTestHarness { bus_id, server, client, shutdown, interrupt, thread: Some(thread) })
+    }
+
+    /// Provides access to the client controller, for instance for sending
+    /// requests not expecting replies
+    pub fn client(&mut self) -> &mut Controller<B, R, ClientHandler<B, R>> { &mut self.client }
+
+    /// Sends request to the server and returns its reply, asserting that the
+    /// reply matches `matcher`. Waits for the reply for
+    /// [`DEFAULT_REPLY_TIMEOUT`].
+    ///
+    /// # Panics
+    ///
+    /// If no reply arrives in time or the reply does not match
+    pub fn send_and_expect(
+        &mut self,
+        request: R,
+        matcher: impl FnOnce(&R) -> bool,
+    ) -> Result<R, Error<B::Address>> {
+        self.send_and_expect_within(request, matcher, DEFAULT_REPLY_TIMEOUT)
+    }
+
+    /// Sends request to the server and returns its reply, asserting that the
+    /// reply arrives within `timeout` and matches `matcher`
+    ///
+    /// # Panics
+    ///
+    /// If no reply arrives in time or the reply does not match
+    pub fn send_and_expect_within(
+        &mut self,
+        request: R,
+        matcher: impl FnOnce(&R) -> bool,
+        timeout: Duration,
+    ) -> Result<R, Error<B::Address>> {
+        let request_str = request.to_string();
+        self.client.send_to(self.bus_id, self.server.clone(), request)?;
+        let (_, source, _, reply) = self
+            .client
+            .poll_only(&[self.bus_id], Some(timeout))?
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| panic!("no reply to {} within {:?}", request_str, timeout));
+        assert_eq!(source, self.server, "reply to {} came not from the server", request_str);
+        assert!(matcher(&reply), "unexpected reply to {}: {:?}", request_str, reply);
+        Ok(reply)
+    }
+}
+
+impl<B, R> Drop for BusTestHarness<B, R>
+where
+    R: Request,
+    B: BusId,
+{
+    fn drop(&mut self) {
+        self.shutdown.shutdown();
+        let _ = self.interrupt.interrupt();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn bus<B: BusId>(
+    bus_id: B,
+    locator: zmqsocket::ZmqSocketAddr,
+) -> HashMap<B, BusConfig<B::Address>> {
+    let mut config = BusConfig::with_locator(locator, None);
+    config.queued = true;
+    let mut buses = HashMap::new();
+    buses.insert(bus_id, config);
+    buses
+}
@@ -0,0 +1,56 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Byte-level transformations of the message data (like encryption or schema
+//! migration) applied by the controller uniformly to all messages of the
+//! service buses, without changes to the request codec or the handler.
+
+use super::{BusId, Error};
+
+/// Transformation of the data of the messages received from the service
+/// buses, applied by the controller after the message header is parsed and
+/// the payload is decompressed, right before it is unmarshalled into a
+/// request (see [`super::Controller::add_recv_transform`])
+pub trait RecvTransform<B: BusId>: Send + Sync + 'static {
+    /// Transforms message `data` received from the bus `bus_id`
+    fn transform(&self, bus_id: B, data: Vec<u8>) -> Result<Vec<u8>, Error<B::Address>>;
+}
+
+/// Transformation of the data of the messages sent to the service buses,
+/// applied by the controller to the serialized request before it is
+/// compressed and provided with message header (see
+/// [`super::Controller::add_send_transform`])
+pub trait SendTransform<B: BusId>: Send + Sync + 'static {
+    /// Transforms message `data` which is sent to the bus `bus_id`
+    fn transform(&self, bus_id: B, data: Vec<u8>) -> Result<Vec<u8>, Error<B::Address>>;
+}
+
+impl<B, F> RecvTransform<B> for F
+where
+    B: BusId,
+    F: Fn(B, Vec<u8>) -> Result<Vec<u8>, Error<B::Address>> + Send + Sync + 'static,
+{
+    fn transform(&self, bus_id: B, data: Vec<u8>) -> Result<Vec<u8>, Error<B::Address>> {
+        self(bus_id, data)
+    }
+}
+
+impl<B, F> SendTransform<B> for F
+where
+    B: BusId,
+    F: Fn(B, Vec<u8>) -> Result<Vec<u8>, Error<B::Address>> + Send + Sync + 'static,
+{
+    fn transform(&self, bus_id: B, data: Vec<u8>) -> Result<Vec<u8>, Error<B::Address>> {
+        self(bus_id, data)
+    }
+}
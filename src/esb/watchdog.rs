@@ -0,0 +1,140 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Action taken by the [`Watchdog`] when the run loop stalls
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+pub enum WatchdogAction {
+    /// Log an error with the stall details and keep waiting
+    #[display("warn")]
+    Warn,
+
+    /// Log an error and abort the process
+    #[display("abort")]
+    Abort,
+}
+
+/// Watchdog configuration, see [`super::ControllerConfig::watchdog`]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct WatchdogConfig {
+    /// Maximum time the controller may spend processing messages without
+    /// petting the watchdog
+    pub timeout: Duration,
+
+    /// Action taken when the timeout is exceeded
+    pub action: WatchdogAction,
+}
+
+/// Marker for the run loop waiting for new messages, which does not count as
+/// a stall
+const IDLE: u64 = 0;
+
+/// Background thread detecting stalled run loop, for instance a handler
+/// blocked indefinitely or stuck in an infinite loop.
+///
+/// The run loop must [`Watchdog::pet`] the watchdog before processing each
+/// message and put it [`Watchdog::idle`] when it starts waiting for new
+/// messages. If the loop stays busy for longer than the configured timeout
+/// without a pet, the watchdog takes the configured [`WatchdogAction`]. The
+/// thread is stopped once the watchdog is dropped.
+pub struct Watchdog {
+    start: Instant,
+    /// Milliseconds since `start` when the watchdog was last petted plus one,
+    /// or [`IDLE`]
+    last_pet: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Spawns watchdog thread with the given configuration
+    pub fn spawn(config: WatchdogConfig) -> Self {
+        let start = Instant::now();
+        let last_pet = Arc::new(AtomicU64::new(IDLE));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let last_pet = last_pet.clone();
+            let stop = stop.clone();
+            let watched =
+                thread::current().name().map(str::to_owned).unwrap_or_else(|| s!("<unnamed>"));
+            thread::Builder::new()
+                .name(s!("esb-watchdog"))
+                .spawn(move || watch(config, start, last_pet, stop, watched))
+                .expect("unable to spawn watchdog thread")
+        };
+        Self { start, last_pet, stop, thread: Some(thread) }
+    }
+
+    /// Notifies watchdog that the run loop is alive and starts processing
+    /// next message
+    #[inline]
+    pub fn pet(&self) {
+        let now = self.start.elapsed().as_millis() as u64 + 1;
+        self.last_pet.store(now, Ordering::Relaxed);
+    }
+
+    /// Notifies watchdog that the run loop is waiting for new messages
+    #[inline]
+    pub fn idle(&self) { self.last_pet.store(IDLE, Ordering::Relaxed); }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+fn watch(
+    config: WatchdogConfig,
+    start: Instant,
+    last_pet: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    watched: String,
+) {
+    let timeout = config.timeout.as_millis() as u64;
+    let check_interval = config.timeout / 4;
+    let mut reported = IDLE;
+    while !stop.load(Ordering::SeqCst) {
+        thread::park_timeout(check_interval);
+        let pet = last_pet.load(Ordering::Relaxed);
+        if pet == IDLE || pet == reported {
+            continue;
+        }
+        let stalled = (start.elapsed().as_millis() as u64 + 1).saturating_sub(pet);
+        if stalled <= timeout {
+            continue;
+        }
+        error!(
+            "Watchdog: run loop of thread `{}` is stalled for {:?} (timeout {:?}); a message \
+             handler is likely blocked or stuck in an infinite loop",
+            watched,
+            Duration::from_millis(stalled),
+            config.timeout
+        );
+        match config.action {
+            WatchdogAction::Warn => reported = pet,
+            WatchdogAction::Abort => {
+                error!("Watchdog: aborting the process");
+                std::process::abort();
+            }
+        }
+    }
+}
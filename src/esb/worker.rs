@@ -0,0 +1,168 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Processing of ESB requests by a pool of worker threads, see
+//! [`super::ControllerConfig::worker_threads`]
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TryIter};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use super::{BusId, Error, InterruptHandle};
+use crate::rpc_connection::Request;
+
+/// Maximal number of requests queued for a single worker thread; once it is
+/// reached, the controller run loop blocks until the worker catches up
+const WORKER_QUEUE_LEN: usize = 1024;
+
+/// Handler processing requests on worker threads (see
+/// [`super::Controller::set_worker`]).
+///
+/// Unlike [`super::Handler`], worker has no access to the service bus
+/// sockets, which are owned by the controller thread due to ZMQ thread
+/// affinity. Instead, requests are sent through the [`Outbox`], which passes
+/// them to the controller thread.
+pub trait Worker<B: BusId>: Send + Sync + 'static {
+    /// Requests processed by the worker
+    type Request: Request + Send;
+
+    /// Processes request received from the `source` over the `bus_id`
+    /// service bus. Errors are passed to [`super::Handler::handle_err`] of
+    /// the controller handler as [`Error::ServiceError`].
+    fn handle(
+        &self,
+        outbox: &Outbox<B, Self::Request>,
+        bus_id: B,
+        source: B::Address,
+        request: Self::Request,
+    ) -> Result<(), Error<B::Address>>;
+}
+
+/// Commands sent by worker threads to the controller thread
+pub(super) enum Command<B: BusId, R> {
+    /// Send request to the destination over the service bus
+    Send(B, B::Address, R),
+
+    /// Request processing by the worker has failed
+    Failed(String),
+}
+
+/// Channel through which worker threads send requests to the service buses
+pub struct Outbox<B: BusId, R> {
+    sender: Sender<Command<B, R>>,
+    interrupt: InterruptHandle,
+}
+
+impl<B: BusId, R> Outbox<B, R> {
+    /// Queues `request` to be sent to `dest` over the `bus_id` service bus by
+    /// the controller thread. Send errors are reported to the controller
+    /// handler, not to the caller.
+    pub fn send_to(
+        &self,
+        bus_id: B,
+        dest: B::Address,
+        request: R,
+    ) -> Result<(), Error<B::Address>> {
+        self.command(Command::Send(bus_id, dest, request))
+    }
+
+    fn command(&self, command: Command<B, R>) -> Result<(), Error<B::Address>> {
+        self.sender
+            .send(command)
+            .map_err(|_| Error::ServiceError(s!("controller has been stopped")))?;
+        // Wakes up the controller blocked in poll
+        self.interrupt.interrupt()?;
+        Ok(())
+    }
+}
+
+/// Pool of worker threads processing requests dispatched by the controller.
+///
+/// Requests are partitioned between the workers by their source, so the
+/// requests from the same source are processed in order by a single worker,
+/// while the order of requests from different sources is not preserved.
+pub(super) struct WorkerPool<B: BusId, R> {
+    jobs: Vec<SyncSender<(B, B::Address, R)>>,
+    commands: Receiver<Command<B, R>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl<B: BusId, R> WorkerPool<B, R> {
+    /// Spawns `count` worker threads running `worker`
+    pub(super) fn spawn<W>(
+        count: usize,
+        worker: W,
+        interrupt: InterruptHandle,
+    ) -> Result<Self, Error<B::Address>>
+    where
+        W: Worker<B, Request = R>,
+        R: Request + Send + 'static,
+        B: Send + 'static,
+        B::Address: Send + 'static,
+    {
+        let worker = Arc::new(worker);
+        let (command_sender, commands) = mpsc::channel();
+        let mut jobs = Vec::with_capacity(count);
+        let mut threads = Vec::with_capacity(count);
+        for no in 0..count {
+            let (job_sender, job_receiver) = mpsc::sync_channel(WORKER_QUEUE_LEN);
+            let outbox = Outbox { sender: command_sender.clone(), interrupt: interrupt.clone() };
+            let worker = worker.clone();
+            let thread = thread::Builder::new()
+                .name(format!("esb-worker-{}", no))
+                .spawn(move || {
+                    for (bus_id, source, request) in job_receiver {
+                        if let Err(err) = worker.handle(&outbox, bus_id, source, request) {
+                            let _ = outbox.command(Command::Failed(err.to_string()));
+                        }
+                    }
+                })
+                .map_err(|err| Error::ServiceError(format!("unable to spawn worker: {}", err)))?;
+            jobs.push(job_sender);
+            threads.push(thread);
+        }
+        Ok(WorkerPool { jobs, commands, threads })
+    }
+
+    /// Passes request to the worker selected by the request source. Fails if
+    /// the worker thread has terminated.
+    pub(super) fn dispatch(
+        &self,
+        bus_id: B,
+        source: B::Address,
+        request: R,
+    ) -> Result<(), Error<B::Address>> {
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        let no = hasher.finish() as usize % self.jobs.len();
+        self.jobs[no]
+            .send((bus_id, source, request))
+            .map_err(|_| Error::ServiceError(format!("worker thread {} has terminated", no)))
+    }
+
+    /// Takes commands sent by the workers since the previous call
+    pub(super) fn commands(&self) -> TryIter<Command<B, R>> { self.commands.try_iter() }
+}
+
+impl<B: BusId, R> Drop for WorkerPool<B, R> {
+    fn drop(&mut self) {
+        // Closing job channels makes the workers stop once they process all
+        // queued requests
+        self.jobs.clear();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,167 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! ZMQ authentication protocol (ZAP, ZMQ RFC 27) handler making authorization
+//! decisions for the clients connecting to CURVE-secured sockets
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use super::{CURVE_KEY_LEN, CURVE_KEY_Z85_LEN};
+
+/// Endpoint on which ZMQ sends authentication requests within a context
+const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+/// Supported version of ZAP
+const ZAP_VERSION: &[u8] = b"1.0";
+/// Interval of checking whether the handler must stop, in milliseconds
+const STOP_CHECK_INTERVAL: i64 = 100;
+
+/// Decides whether clients connecting to CURVE-secured sockets are allowed.
+/// Unlike a static allowlist configured with socket options, the decision is
+/// made for each connection, so it may rely on dynamic data (like a
+/// database of the registered clients).
+pub trait ZapAuthenticator: Send + 'static {
+    /// Checks whether the client with CURVE public key `client_key` is
+    /// allowed to connect to a socket with ZAP `domain`
+    fn authorize(&mut self, domain: &str, client_key: &[u8; CURVE_KEY_LEN]) -> bool;
+}
+
+/// Authenticator allowing clients with the public keys from a fixed list,
+/// regardless of the ZAP domain
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct StaticAllowlist(HashSet<[u8; CURVE_KEY_LEN]>);
+
+impl StaticAllowlist {
+    /// Constructs allowlist from the client public keys
+    pub fn with(keys: impl IntoIterator<Item = [u8; CURVE_KEY_LEN]>) -> Self {
+        StaticAllowlist(keys.into_iter().collect())
+    }
+
+    /// Adds client public key to the allowlist
+    pub fn allow(&mut self, key: [u8; CURVE_KEY_LEN]) -> bool { self.0.insert(key) }
+
+    /// Removes client public key from the allowlist
+    pub fn revoke(&mut self, key: &[u8; CURVE_KEY_LEN]) -> bool { self.0.remove(key) }
+}
+
+impl ZapAuthenticator for StaticAllowlist {
+    fn authorize(&mut self, _domain: &str, client_key: &[u8; CURVE_KEY_LEN]) -> bool {
+        self.0.contains(client_key)
+    }
+}
+
+/// Background thread serving ZAP requests of a ZMQ context with the provided
+/// [`ZapAuthenticator`]. Applies to all CURVE server sockets of the context;
+/// connections using other security mechanisms which request authentication
+/// are denied. The thread is stopped once the handler is dropped.
+pub struct ZapHandler {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ZapHandler {
+    /// Starts handling ZAP requests of the `context`, which must be the
+    /// context used by the controller (see [`super::Controller::with_context`]).
+    /// Fails if the context already has a ZAP handler.
+    pub fn start(
+        context: &zmq::Context,
+        authenticator: impl ZapAuthenticator,
+    ) -> Result<Self, zmq::Error> {
+        let socket = context.socket(zmq::REP)?;
+        socket.bind(ZAP_ENDPOINT)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name(s!("esb-zap"))
+                .spawn(move || serve(socket, authenticator, stop))
+                .expect("unable to spawn ZAP handler thread")
+        };
+        Ok(ZapHandler { stop, thread: Some(thread) })
+    }
+}
+
+impl Drop for ZapHandler {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(socket: zmq::Socket, mut authenticator: impl ZapAuthenticator, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::SeqCst) {
+        match socket.poll(zmq::POLLIN, STOP_CHECK_INTERVAL) {
+            Ok(0) => continue,
+            Ok(_) => {}
+            Err(err) => {
+                error!("ZAP handler has failed to poll its socket: {}", err);
+                return;
+            }
+        }
+        let request = match socket.recv_multipart(0) {
+            Ok(request) => request,
+            Err(err) => {
+                error!("ZAP handler has failed to receive request: {}", err);
+                return;
+            }
+        };
+        let reply = process(&request, &mut authenticator);
+        if let Err(err) = socket.send_multipart(reply, 0) {
+            error!("ZAP handler has failed to send reply: {}", err);
+            return;
+        }
+    }
+}
+
+/// Processes ZAP request, returning reply frames
+fn process(request: &[Vec<u8>], authenticator: &mut impl ZapAuthenticator) -> Vec<Vec<u8>> {
+    let request_id = request.get(1).cloned().unwrap_or_default();
+    let reply = |code: &str, text: &str, user_id: String| {
+        vec![
+            ZAP_VERSION.to_vec(),
+            request_id.clone(),
+            code.as_bytes().to_vec(),
+            text.as_bytes().to_vec(),
+            user_id.into_bytes(),
+            vec![],
+        ]
+    };
+    if request.len() < 6 || request[0] != ZAP_VERSION {
+        warn!("Malformed ZAP request");
+        return reply("500", "malformed request", s!(""));
+    }
+    let domain = String::from_utf8_lossy(&request[2]);
+    if request[5] != b"CURVE" || request.len() != 7 || request[6].len() != CURVE_KEY_LEN {
+        debug!(
+            "Denying {} connection in ZAP domain `{}`",
+            String::from_utf8_lossy(&request[5]),
+            domain
+        );
+        return reply("400", "unsupported security mechanism", s!(""));
+    }
+    let mut client_key = [0u8; CURVE_KEY_LEN];
+    client_key.copy_from_slice(&request[6]);
+    let client = zmq::z85_encode(&client_key).unwrap_or_default();
+    debug_assert_eq!(client.len(), CURVE_KEY_Z85_LEN);
+    if authenticator.authorize(&domain, &client_key) {
+        debug!("Client {} is authorized in ZAP domain `{}`", client, domain);
+        reply("200", "OK", client)
+    } else {
+        debug!("Client {} is denied in ZAP domain `{}`", client, domain);
+        reply("400", "client is not authorized", s!(""))
+    }
+}
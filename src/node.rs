@@ -12,6 +12,8 @@
 // If not, see <https://opensource.org/licenses/MIT>.
 
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 /// Trait for simpler service implementation with run loops
 pub trait Service {
@@ -27,22 +29,79 @@ pub trait TryService: Sized {
     /// is returned from the internal [`try_run_loop()`] procedure
     type ErrorType: Error;
 
+    /// Report returned by the run loop when the service is shut down cleanly
+    type ShutdownReport;
+
     /// NB: Do not reimplement this one: the function keeps in check that if the
     /// failure happens during run loop, the program will panic reporting the
     /// failure. To implement the actual run loop please provide implementation
     /// for [`try_run_loop()`]
-    fn run_or_panic(self, service_name: &str) {
+    fn run_or_panic(self, service_name: &str) -> Self::ShutdownReport {
         match self.try_run_loop() {
             Err(err) => {
                 panic!("{} run loop has failed with {}", service_name, err)
             }
-            Ok(_) => {
-                panic!("{} has failed without reporting a error", service_name)
+            Ok(report) => {
+                info!("{} has shut down", service_name);
+                report
             }
         }
     }
 
+    /// Returns signal which may be used to shut down the service run loop, if
+    /// the service supports clean shutdown
+    fn shutdown_signal(&self) -> Option<ShutdownSignal> { None }
+
+    /// Runs the service like [`TryService::run_or_panic`], but installs
+    /// SIGTERM and SIGINT handlers triggering [`TryService::shutdown_signal`],
+    /// so the service exits cleanly when stopped by an orchestrator, returning
+    /// its shutdown report.
+    ///
+    /// If the service does not support clean shutdown, or the handlers can't
+    /// be installed, a warning is logged and the service runs as usual.
+    #[cfg(feature = "signals")]
+    fn run_with_signals(self, service_name: &str) -> Self::ShutdownReport {
+        match self.shutdown_signal() {
+            None => warn!("{} does not support clean shutdown on signals", service_name),
+            Some(signal) => {
+                for sig in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+                    if let Err(err) = signal_hook::flag::register(sig, signal.0.clone()) {
+                        warn!("Unable to install handler for signal {}: {}", sig, err);
+                    }
+                }
+            }
+        }
+        self.run_or_panic(service_name)
+    }
+
     /// Main failable run loop implementation. Must produce an error of type
-    /// [`TryService::ErrorType`] or never return.
-    fn try_run_loop(self) -> Result<(), Self::ErrorType>;
+    /// [`TryService::ErrorType`], return [`TryService::ShutdownReport`] on a
+    /// clean shutdown or never return.
+    fn try_run_loop(self) -> Result<Self::ShutdownReport, Self::ErrorType>;
+}
+
+/// Reason for a clean service shutdown
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[non_exhaustive]
+pub enum ShutdownReason {
+    /// Shutdown was requested with [`ShutdownSignal`]
+    #[display("shutdown requested")]
+    Requested,
+}
+
+/// Signal requesting service run loop to terminate. The signal may be cloned
+/// and triggered from any thread; the service checks it between run loop
+/// iterations.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownSignal(Arc<AtomicBool>);
+
+impl ShutdownSignal {
+    /// Constructs new signal which is not triggered
+    pub fn new() -> Self { Self::default() }
+
+    /// Requests the service to shut down
+    pub fn shutdown(&self) { self.0.store(true, Ordering::SeqCst) }
+
+    /// Checks whether the shutdown was requested
+    pub fn is_triggered(&self) -> bool { self.0.load(Ordering::SeqCst) }
 }
@@ -0,0 +1,202 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! Asynchronous counterparts of [`PeerReceiver`] and [`PeerSender`] for use
+//! with tokio-based code. Blocking transport IO runs in dedicated threads,
+//! communicating with the async side via channels.
+
+use std::fmt::Display;
+use std::io::Cursor;
+use std::net::{Shutdown, TcpStream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+
+use futures_core::Stream;
+use futures_sink::Sink;
+use internet2::presentation::{self, Unmarshall};
+use lightning_encoding::LightningEncode;
+use tokio::sync::mpsc;
+
+use super::{Error, PeerReceiver, PeerSender};
+
+/// Maximal number of messages buffered between the async side and the IO
+/// threads in each direction. Once the limit is reached, the receiving thread
+/// stops reading from the connection and the sink is not ready for more
+/// messages until some of the buffered ones are sent.
+pub const ASYNC_CHANNEL_CAPACITY: usize = 64;
+
+/// Stream of messages received from the remote peer. Dropping the stream
+/// shuts down the reading half of the connection and stops the receiving
+/// thread.
+pub struct AsyncPeerReceiver<D> {
+    channel: mpsc::Receiver<Result<Vec<u8>, Error>>,
+    unmarshaller: D,
+    /// Connection stream used to interrupt the receiving thread
+    stream: TcpStream,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Sink for messages sent to the remote peer. The sink is flushed once the
+/// sending thread has written all the messages to the connection. A
+/// transport error happening during sending is returned by the next sink
+/// operation and closes the sink, so that the subsequent sends fail with
+/// [`Error::WriteClosed`].
+pub struct AsyncPeerSender {
+    channel: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// Results of sending the messages, reported by the sending thread
+    sent: mpsc::UnboundedReceiver<Result<(), Error>>,
+    /// Number of the messages passed to the sending thread which were not
+    /// yet reported as sent
+    in_flight: usize,
+}
+
+/// Spawns IO threads for the receiver and sender and returns their async
+/// counterparts. `stream` is the TCP stream of the connection.
+pub(super) fn spawn<D>(
+    mut receiver: PeerReceiver,
+    sender: PeerSender,
+    unmarshaller: D,
+    stream: TcpStream,
+) -> (AsyncPeerReceiver<D>, AsyncPeerSender) {
+    let (inbound_tx, inbound_rx) = mpsc::channel(ASYNC_CHANNEL_CAPACITY);
+    let reader = thread::spawn(move || loop {
+        let payload = receiver.receiver.recv_raw_message().map_err(Error::from);
+        let failed = payload.is_err();
+        if inbound_tx.blocking_send(payload).is_err() || failed {
+            trace!("Stopping async peer receiver thread");
+            break;
+        }
+    });
+
+    let (outbound_tx, mut outbound_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let (sent_tx, sent_rx) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        while let Some(data) = outbound_rx.blocking_recv() {
+            let res = sender.send_raw_message(&data).map(|_| ());
+            let failed = res.is_err();
+            if let Err(ref err) = res {
+                error!("Unable to send message to the remote peer: {}", err);
+            }
+            if sent_tx.send(res).is_err() || failed {
+                break;
+            }
+        }
+        trace!("Stopping async peer sender thread");
+    });
+
+    let receiver =
+        AsyncPeerReceiver { channel: inbound_rx, unmarshaller, stream, thread: Some(reader) };
+    let sender = AsyncPeerSender { channel: Some(outbound_tx), sent: sent_rx, in_flight: 0 };
+    (receiver, sender)
+}
+
+impl<D> Drop for AsyncPeerReceiver<D> {
+    fn drop(&mut self) {
+        // Unblocks the thread waiting either for the channel capacity or for
+        // the data from the remote peer
+        self.channel.close();
+        let _ = self.stream.shutdown(Shutdown::Read);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<D> Stream for AsyncPeerReceiver<D>
+where
+    D: Unmarshall + Unpin,
+    <D as Unmarshall>::Data: Display,
+    <D as Unmarshall>::Error: Into<presentation::Error>,
+{
+    type Item = Result<D::Data, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let me = self.get_mut();
+        let unmarshaller = &me.unmarshaller;
+        me.channel.poll_recv(cx).map(|item| {
+            item.map(|payload| {
+                let message: D::Data = unmarshaller
+                    .unmarshall(Cursor::new(payload?))
+                    .map_err(Into::<presentation::Error>::into)?;
+                debug!("Message from the remote peer: {}", message);
+                Ok(message)
+            })
+        })
+    }
+}
+
+impl AsyncPeerSender {
+    /// Collects reports of the sending thread until no more than `limit`
+    /// messages remain in flight
+    fn poll_sent(&mut self, cx: &mut Context<'_>, limit: usize) -> Poll<Result<(), Error>> {
+        while self.in_flight > limit {
+            match self.sent.poll_recv(cx) {
+                Poll::Ready(Some(Ok(()))) => self.in_flight -= 1,
+                Poll::Ready(Some(Err(err))) => {
+                    self.channel = None;
+                    self.in_flight = 0;
+                    return Poll::Ready(Err(err));
+                }
+                Poll::Ready(None) => {
+                    self.channel = None;
+                    self.in_flight = 0;
+                    return Poll::Ready(Err(Error::WriteClosed));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<M> Sink<M> for AsyncPeerSender
+where
+    M: LightningEncode + Display,
+{
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let me = self.get_mut();
+        if me.channel.is_none() {
+            return Poll::Ready(Err(Error::WriteClosed));
+        }
+        me.poll_sent(cx, ASYNC_CHANNEL_CAPACITY - 1)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: M) -> Result<(), Error> {
+        debug!("Sending LN message to the remote peer: {}", message);
+        let me = self.get_mut();
+        let data = message.lightning_serialize()?;
+        let channel = me.channel.as_ref().ok_or(Error::WriteClosed)?;
+        channel.send(data).map_err(|_| Error::WriteClosed)?;
+        me.in_flight += 1;
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.get_mut().poll_sent(cx, 0)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let me = self.get_mut();
+        match me.poll_sent(cx, 0) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(res) => {
+                // Stops the sending thread
+                me.channel = None;
+                Poll::Ready(res)
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::HashMap;
+
+use super::{Error, Peer};
+
+/// Closure handling peer messages of a specific type. Receives connection
+/// the message came from (allowing to reply), message type id and message
+/// payload following the type id.
+pub type MessageHandler<P> = Box<dyn FnMut(&mut P, u16, &[u8]) -> Result<(), Error>>;
+
+/// Dispatch table routing raw messages received from the remote peer to the
+/// handlers registered for their BOLT message type ids, so the services
+/// don't need to match on decoded message types by hand.
+///
+/// Messages of types without registered handler go to the default handler.
+/// If there is no default handler, such messages are ignored when their type
+/// is odd, while even types fail with [`Error::UnknownMessage`], following
+/// BOLT-1 "it's ok to be odd" rule.
+pub struct PeerDispatcher<P: Peer> {
+    handlers: HashMap<u16, MessageHandler<P>>,
+    default: Option<MessageHandler<P>>,
+}
+
+impl<P: Peer> Default for PeerDispatcher<P> {
+    fn default() -> Self { PeerDispatcher { handlers: HashMap::new(), default: None } }
+}
+
+impl<P: Peer> PeerDispatcher<P> {
+    /// Constructs dispatcher with no registered handlers
+    pub fn new() -> Self { Self::default() }
+
+    /// Registers `handler` for the messages with `msg_type` id, replacing the
+    /// previously registered one
+    pub fn register(
+        &mut self,
+        msg_type: u16,
+        handler: impl FnMut(&mut P, u16, &[u8]) -> Result<(), Error> + 'static,
+    ) -> &mut Self {
+        self.handlers.insert(msg_type, Box::new(handler));
+        self
+    }
+
+    /// Removes handler registered for `msg_type`. Returns `true` if the
+    /// handler was present.
+    pub fn unregister(&mut self, msg_type: u16) -> bool {
+        self.handlers.remove(&msg_type).is_some()
+    }
+
+    /// Sets handler for the messages of types without registered handlers
+    pub fn set_default(
+        &mut self,
+        handler: impl FnMut(&mut P, u16, &[u8]) -> Result<(), Error> + 'static,
+    ) -> &mut Self {
+        self.default = Some(Box::new(handler));
+        self
+    }
+
+    /// Reads single message from the connection and invokes the handler
+    /// registered for its type. Returns type id of the processed message.
+    pub fn dispatch_next(&mut self, conn: &mut P) -> Result<u16, Error> {
+        let data = conn.recv_raw_message()?;
+        if data.len() < 2 {
+            return Err(Error::MalformedMessage);
+        }
+        let msg_type = u16::from_be_bytes([data[0], data[1]]);
+        let payload = &data[2..];
+        match (self.handlers.get_mut(&msg_type), self.default.as_mut()) {
+            (Some(handler), _) | (None, Some(handler)) => {
+                trace!("Dispatching peer message of type {}", msg_type);
+                handler(conn, msg_type, payload)?;
+            }
+            (None, None) if msg_type % 2 == 1 => {
+                debug!("Ignoring peer message of unknown odd type {}", msg_type);
+            }
+            (None, None) => return Err(Error::UnknownMessage(msg_type)),
+        }
+        Ok(msg_type)
+    }
+}
@@ -0,0 +1,210 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::BTreeSet;
+
+use super::TlvStream;
+
+/// BOLT-1 `init` message type
+const BOLT_INIT_MSG_TYPE: u16 = 16;
+
+/// Bit of the `initial_routing_sync` feature, which BOLT-9 defines as
+/// optional-only: its even counterpart is never assigned
+const INITIAL_ROUTING_SYNC_BIT: u16 = 3;
+
+/// Set of BOLT-9 feature bits, advertised by peers in BOLT-1 `init` messages.
+///
+/// Each feature is represented by a pair of bits: even bit means that the
+/// feature is compulsory, odd bit – that it is optional. Named accessors
+/// operate on such pairs, identified by their even bit, so the users do not
+/// need to deal with the bit numbers.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct FeatureFlags(BTreeSet<u16>);
+
+macro_rules! features {
+    ($( $supports:ident, $set:ident, $name:literal = $bit:literal; )+) => {
+        impl FeatureFlags {
+            $(
+                #[doc = concat!(
+                    "Checks whether `", $name, "` feature is set either as compulsory or as ",
+                    "optional"
+                )]
+                pub fn $supports(&self) -> bool { self.supports($bit) }
+
+                #[doc = concat!(
+                    "Sets `", $name, "` feature as compulsory if `required` or as optional ",
+                    "otherwise"
+                )]
+                pub fn $set(&mut self, required: bool) -> &mut Self {
+                    self.set_feature($bit, required)
+                }
+            )+
+        }
+    };
+}
+
+features! {
+    supports_data_loss_protect, set_option_data_loss_protect, "option_data_loss_protect" = 0;
+    supports_upfront_shutdown_script, set_option_upfront_shutdown_script,
+        "option_upfront_shutdown_script" = 4;
+    supports_gossip_queries, set_gossip_queries, "gossip_queries" = 6;
+    supports_var_onion_optin, set_var_onion_optin, "var_onion_optin" = 8;
+    supports_gossip_queries_ex, set_gossip_queries_ex, "gossip_queries_ex" = 10;
+    supports_static_remotekey, set_option_static_remotekey, "option_static_remotekey" = 12;
+    supports_payment_secret, set_payment_secret, "payment_secret" = 14;
+    supports_basic_mpp, set_basic_mpp, "basic_mpp" = 16;
+    supports_large_channel, set_option_support_large_channel, "option_support_large_channel" = 18;
+    supports_anchor_outputs, set_option_anchor_outputs, "option_anchor_outputs" = 20;
+    supports_anchors_zero_fee_htlc_tx, set_option_anchors_zero_fee_htlc_tx,
+        "option_anchors_zero_fee_htlc_tx" = 22;
+    supports_shutdown_anysegwit, set_option_shutdown_anysegwit, "option_shutdown_anysegwit" = 26;
+}
+
+impl FeatureFlags {
+    /// Constructs empty feature set
+    pub fn new() -> Self { default!() }
+
+    /// Checks whether the given bit is set
+    pub fn is_set(&self, bit: u16) -> bool { self.0.contains(&bit) }
+
+    /// Sets the given bit
+    pub fn set(&mut self, bit: u16) -> &mut Self {
+        self.0.insert(bit);
+        self
+    }
+
+    /// Clears the given bit
+    pub fn unset(&mut self, bit: u16) -> &mut Self {
+        self.0.remove(&bit);
+        self
+    }
+
+    /// Returns numbers of all set bits in ascending order
+    pub fn bits(&self) -> impl Iterator<Item = u16> + '_ { self.0.iter().copied() }
+
+    /// Checks whether the feature identified by its even bit is set either as
+    /// compulsory or as optional
+    pub fn supports(&self, feature: u16) -> bool {
+        let even = feature & !1;
+        self.is_set(even) || self.is_set(even + 1)
+    }
+
+    /// Checks whether the feature identified by its even bit is compulsory
+    pub fn requires(&self, feature: u16) -> bool { self.is_set(feature & !1) }
+
+    /// Checks whether `initial_routing_sync` feature is set
+    pub fn supports_initial_routing_sync(&self) -> bool { self.is_set(INITIAL_ROUTING_SYNC_BIT) }
+
+    /// Sets `initial_routing_sync` feature, which can only be optional
+    pub fn set_initial_routing_sync(&mut self) -> &mut Self {
+        self.set_feature(INITIAL_ROUTING_SYNC_BIT, false)
+    }
+
+    fn set_feature(&mut self, feature: u16, required: bool) -> &mut Self {
+        let even = feature & !1;
+        let required = required && even + 1 != INITIAL_ROUTING_SYNC_BIT;
+        self.unset(even).unset(even + 1);
+        self.set(if required { even } else { even + 1 })
+    }
+
+    /// Returns compulsory features set which are not known to the local node,
+    /// given as a set of the supported features; a connection with a peer
+    /// requiring any of them must be failed
+    pub fn unknown_required(&self, known: &FeatureFlags) -> Vec<u16> {
+        self.bits().filter(|bit| bit % 2 == 0 && !known.supports(*bit)).collect()
+    }
+
+    /// Computes features common for the local and remote nodes. A common
+    /// feature is compulsory if it is required by any of the nodes.
+    pub fn negotiate(local: &FeatureFlags, remote: &FeatureFlags) -> FeatureFlags {
+        let mut common = FeatureFlags::new();
+        let features = local.bits().map(|bit| bit & !1).collect::<BTreeSet<_>>();
+        for feature in features {
+            if remote.supports(feature) {
+                common.set_feature(feature, local.requires(feature) || remote.requires(feature));
+            }
+        }
+        common
+    }
+
+    /// Decodes feature bit field in BOLT-9 format: big-endian byte array with
+    /// bit 0 being the least significant bit of the last byte
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut features = FeatureFlags::new();
+        for (index, byte) in data.iter().rev().enumerate() {
+            for shift in 0..8 {
+                if byte & (1 << shift) != 0 {
+                    features.set((index * 8 + shift) as u16);
+                }
+            }
+        }
+        features
+    }
+
+    /// Encodes features into the BOLT-9 bit field of minimal length
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let len = self.0.iter().next_back().map(|bit| *bit as usize / 8 + 1).unwrap_or_default();
+        let mut data = vec![0u8; len];
+        for bit in self.bits() {
+            data[len - 1 - bit as usize / 8] |= 1 << (bit % 8);
+        }
+        data
+    }
+
+    /// Encodes BOLT-1 `init` message advertising the features, with empty
+    /// TLV stream
+    pub fn to_init_message(&self) -> Vec<u8> {
+        let features = self.to_bytes();
+        let mut msg = Vec::with_capacity(2 + 2 + 2 + features.len());
+        msg.extend_from_slice(&BOLT_INIT_MSG_TYPE.to_be_bytes());
+        msg.extend_from_slice(&0u16.to_be_bytes());
+        msg.extend_from_slice(&(features.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&features);
+        msg
+    }
+
+    /// Parses BOLT-1 `init` message, returning union of the global and local
+    /// features it advertises. Returns `None` if the payload is not a
+    /// well-formed `init` message, including the case of malformed TLV stream
+    /// or TLV stream containing unknown even records.
+    pub fn from_init_message(payload: &[u8]) -> Option<Self> {
+        let mut cursor = payload;
+        if read_u16(&mut cursor)? != BOLT_INIT_MSG_TYPE {
+            return None;
+        }
+        let global_len = read_u16(&mut cursor)? as usize;
+        let global = read_bytes(&mut cursor, global_len)?;
+        let local_len = read_u16(&mut cursor)? as usize;
+        let local = read_bytes(&mut cursor, local_len)?;
+        let mut features = FeatureFlags::from_bytes(global);
+        features.0.extend(FeatureFlags::from_bytes(local).0);
+
+        // `networks` (1) and `remote_addr` (3) are odd; unknown even records
+        // must fail the parsing
+        TlvStream::from_bytes(cursor).and_then(|tlvs| tlvs.check_known(&[])).ok()?;
+        Some(features)
+    }
+}
+
+fn read_bytes<'a>(cursor: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if cursor.len() < len {
+        return None;
+    }
+    let (bytes, rest) = cursor.split_at(len);
+    *cursor = rest;
+    Some(bytes)
+}
+
+fn read_u16(cursor: &mut &[u8]) -> Option<u16> {
+    read_bytes(cursor, 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
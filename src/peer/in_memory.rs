@@ -0,0 +1,92 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::VecDeque;
+use std::io;
+
+use lightning_encoding::LightningEncode;
+
+use super::{Error, Peer};
+
+/// In-memory [`Peer`] test double: messages "received" from the remote peer
+/// are taken from a queue filled in advance, while the sent messages are
+/// collected for later inspection.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct InMemoryPeer {
+    incoming: VecDeque<Vec<u8>>,
+    outgoing: VecDeque<Vec<u8>>,
+    write_closed: bool,
+    read_closed: bool,
+}
+
+impl InMemoryPeer {
+    /// Constructs peer with no incoming messages
+    pub fn new() -> Self { Self::default() }
+
+    /// Constructs peer which will return the provided raw messages as the
+    /// ones received from the remote peer
+    pub fn with_incoming(incoming: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        Self { incoming: incoming.into_iter().collect(), ..default!() }
+    }
+
+    /// Queues raw message to be received from the remote peer
+    pub fn push_incoming(&mut self, data: Vec<u8>) { self.incoming.push_back(data) }
+
+    /// Queues message to be received from the remote peer
+    pub fn push_message(&mut self, message: impl LightningEncode) -> Result<(), Error> {
+        self.incoming.push_back(message.lightning_serialize()?);
+        Ok(())
+    }
+
+    /// Takes the earliest message sent to the remote peer
+    pub fn pop_sent(&mut self) -> Option<Vec<u8>> { self.outgoing.pop_front() }
+
+    /// Returns all messages sent to the remote peer which were not taken with
+    /// [`InMemoryPeer::pop_sent`]
+    pub fn sent(&self) -> &VecDeque<Vec<u8>> { &self.outgoing }
+
+    /// Returns number of queued incoming messages
+    pub fn pending_incoming(&self) -> usize { self.incoming.len() }
+}
+
+impl Peer for InMemoryPeer {
+    /// Returns next queued incoming message; once the queue is exhausted
+    /// fails with [`io::ErrorKind::UnexpectedEof`], like a connection closed
+    /// by the remote peer
+    fn recv_raw_message(&mut self) -> Result<Vec<u8>, Error> {
+        if self.read_closed {
+            return Err(Error::ReadClosed);
+        }
+        self.incoming.pop_front().ok_or(Error::Io(io::ErrorKind::UnexpectedEof))
+    }
+
+    fn send_raw_message(&mut self, data: &[u8]) -> Result<usize, Error> {
+        if self.write_closed {
+            return Err(Error::WriteClosed);
+        }
+        self.outgoing.push_back(data.to_vec());
+        Ok(data.len())
+    }
+
+    fn shutdown_write(&mut self) -> Result<(), Error> {
+        self.write_closed = true;
+        Ok(())
+    }
+
+    fn shutdown_read(&mut self) -> Result<(), Error> {
+        self.read_closed = true;
+        Ok(())
+    }
+
+    fn is_encrypted(&self) -> bool { false }
+}
@@ -0,0 +1,278 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use internet2::session::{self, LocalNode};
+
+use super::{BrontideSession, Error, PeerConnection};
+
+/// Default time limit for the BOLT-8 handshake of an accepted connection
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Slot in the connection limit of [`PeerListener`], occupied by an accepted
+/// connection from the start of its handshake until the connection (or both
+/// halves of the split connection) is dropped
+pub(super) struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn occupy(counter: Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        ConnectionSlot(counter)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) { self.0.fetch_sub(1, Ordering::SeqCst); }
+}
+
+/// Outcome of accepting single TCP connection by the acceptor thread
+enum Incoming {
+    /// Connection with successful handshake
+    Connection(BrontideSession, TcpStream, SocketAddr, Arc<ConnectionSlot>),
+    /// Connection which was rejected or has failed the handshake
+    Rejected(Error),
+    /// Failure of the listener itself
+    Failed(Error),
+}
+
+/// TCP listener accepting brontide-encrypted connections from the remote
+/// peers on behalf of the local node, which is the peer-layer analogue of the
+/// ESB controller loop.
+///
+/// The listener accepts TCP connections in a background thread and performs
+/// BOLT-8 handshake with each accepted peer in a separate worker thread,
+/// applying handshake time limit, so a slow or stalled peer can't block
+/// accepting other peers. An optional limit of the number of simultaneously
+/// open connections counts connections from the start of their handshake.
+/// Failures of individual connections do not stop the listener.
+pub struct PeerListener {
+    /// Listener socket, until it is moved to the acceptor thread
+    listener: Option<TcpListener>,
+    addr: SocketAddr,
+    local: LocalNode,
+    max_connections: Option<usize>,
+    handshake_timeout: Duration,
+    /// Number of the occupied connection slots
+    connections: Arc<AtomicUsize>,
+    incoming: Option<mpsc::Receiver<Incoming>>,
+    stop: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+impl PeerListener {
+    /// Binds the listener to the local socket address
+    pub fn bind(addr: SocketAddr, local: &LocalNode) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        debug!("Listening for peer connections on {}", addr);
+        Ok(PeerListener {
+            listener: Some(listener),
+            addr,
+            local: local.clone(),
+            max_connections: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            connections: default!(),
+            incoming: None,
+            stop: default!(),
+            acceptor: None,
+        })
+    }
+
+    /// Limits number of simultaneously open accepted connections; above the
+    /// limit new connections are closed right after they are accepted.
+    /// A connection is counted from the start of its handshake until it is
+    /// dropped; a split connection is counted until both its receiver and
+    /// sender are dropped. `None` means no limit, which is the default.
+    ///
+    /// The limit must be set before the first [`PeerListener::accept`] call.
+    pub fn max_connections(&mut self, limit: Option<usize>) -> &mut Self {
+        self.max_connections = limit;
+        self
+    }
+
+    /// Sets time limit for the handshake with an accepted peer, which is
+    /// 30 seconds by default. The limit must be set before the first
+    /// [`PeerListener::accept`] call.
+    pub fn handshake_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Returns local socket address the listener is bound to
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> { Ok(self.addr) }
+
+    /// Returns number of the accepted connections which are still open or
+    /// performing handshake
+    pub fn open_connections(&self) -> usize { self.connections.load(Ordering::SeqCst) }
+
+    /// Accepts next peer connection which has completed handshake. Errors of
+    /// the individual connections (like failed handshake or exceeded
+    /// connection limit) are returned as `Ok(Err(_))`, so the caller may
+    /// continue accepting; outer error means that the listener itself has
+    /// failed.
+    pub fn accept(&mut self) -> Result<Result<PeerConnection, Error>, Error> {
+        if self.incoming.is_none() {
+            self.start()?;
+        }
+        let incoming = self.incoming.as_ref().expect("acceptor thread is started");
+        match incoming.recv() {
+            Ok(Incoming::Connection(session, stream, remote, slot)) => {
+                let mut connection = PeerConnection::with_stream(session, stream);
+                connection.slot = Some(slot);
+                debug!("Peer connection from {} is established", remote);
+                Ok(Ok(connection))
+            }
+            Ok(Incoming::Rejected(err)) => Ok(Err(err)),
+            Ok(Incoming::Failed(err)) => Err(err),
+            Err(_) => Err(Error::Io(io::ErrorKind::BrokenPipe)),
+        }
+    }
+
+    /// Runs accept loop, passing each established peer connection to the
+    /// `handler`. Errors of the individual connections are logged and do not
+    /// stop the loop. Returns only if the listener itself has failed.
+    pub fn accept_loop(&mut self, mut handler: impl FnMut(PeerConnection)) -> Result<(), Error> {
+        loop {
+            match self.accept()? {
+                Ok(connection) => handler(connection),
+                Err(err) => warn!("Unable to accept peer connection: {}", err),
+            }
+        }
+    }
+
+    /// Starts acceptor thread
+    fn start(&mut self) -> Result<(), Error> {
+        let listener = self.listener.take().ok_or(Error::Io(io::ErrorKind::BrokenPipe))?;
+        let (sender, receiver) = mpsc::channel();
+        let acceptor = Acceptor {
+            listener,
+            local: self.local.clone(),
+            max_connections: self.max_connections,
+            handshake_timeout: self.handshake_timeout,
+            connections: self.connections.clone(),
+            stop: self.stop.clone(),
+            sender,
+        };
+        let thread =
+            thread::Builder::new().name(s!("peer-acceptor")).spawn(move || acceptor.run())?;
+        self.acceptor = Some(thread);
+        self.incoming = Some(receiver);
+        Ok(())
+    }
+}
+
+impl Drop for PeerListener {
+    fn drop(&mut self) {
+        if let Some(thread) = self.acceptor.take() {
+            self.stop.store(true, Ordering::SeqCst);
+            // Wake up the acceptor blocked in accepting connections
+            let ip = match self.addr.ip() {
+                IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                ip => ip,
+            };
+            let _ = TcpStream::connect(SocketAddr::new(ip, self.addr.port()));
+            let _ = thread.join();
+        }
+    }
+}
+
+/// State of the acceptor thread of [`PeerListener`]
+struct Acceptor {
+    listener: TcpListener,
+    local: LocalNode,
+    max_connections: Option<usize>,
+    handshake_timeout: Duration,
+    connections: Arc<AtomicUsize>,
+    stop: Arc<AtomicBool>,
+    sender: mpsc::Sender<Incoming>,
+}
+
+impl Acceptor {
+    fn run(self) {
+        loop {
+            let res = self.listener.accept();
+            if self.stop.load(Ordering::SeqCst) {
+                return;
+            }
+            let (stream, remote) = match res {
+                Ok(accepted) => accepted,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::Interrupted | io::ErrorKind::ConnectionAborted
+                    ) =>
+                {
+                    continue
+                }
+                Err(err) => {
+                    let _ = self.sender.send(Incoming::Failed(err.into()));
+                    return;
+                }
+            };
+            let incoming = match self.max_connections {
+                Some(limit) if self.connections.load(Ordering::SeqCst) >= limit => {
+                    debug!("Rejecting connection from {}: connection limit is reached", remote);
+                    Some(Incoming::Rejected(Error::ConnectionLimit(limit)))
+                }
+                _ => self.spawn_handshake(stream, remote).err().map(Incoming::Rejected),
+            };
+            if let Some(incoming) = incoming {
+                if self.sender.send(incoming).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Performs handshake with the accepted peer in a worker thread, which
+    /// holds connection slot for the duration of the handshake
+    fn spawn_handshake(&self, stream: TcpStream, remote: SocketAddr) -> Result<(), Error> {
+        trace!("Accepted TCP connection from {}", remote);
+        let slot = Arc::new(ConnectionSlot::occupy(self.connections.clone()));
+        let local = self.local.clone();
+        let timeout = self.handshake_timeout;
+        let sender = self.sender.clone();
+        thread::Builder::new().name(format!("peer-handshake-{}", remote)).spawn(move || {
+            let incoming = match handshake(stream, &local, timeout) {
+                Ok((session, stream)) => Incoming::Connection(session, stream, remote, slot),
+                Err(err) => {
+                    debug!("Handshake with {} has failed: {}", remote, err);
+                    Incoming::Rejected(err)
+                }
+            };
+            let _ = sender.send(incoming);
+        })?;
+        Ok(())
+    }
+}
+
+/// Performs BOLT-8 handshake as the responder within the `timeout`
+fn handshake(
+    stream: TcpStream,
+    local: &LocalNode,
+    timeout: Duration,
+) -> Result<(BrontideSession, TcpStream), Error> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let session = session::Raw::with_brontide_accept(stream.try_clone()?, local.private_key())?;
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    Ok((session, stream))
+}
@@ -11,14 +11,100 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/MIT>.
 
+#[cfg(feature = "async")]
+mod async_split;
+mod dispatcher;
+mod features;
+mod in_memory;
+mod listener;
 mod peer_connection;
+mod pool;
+#[cfg(feature = "tls")]
+mod tls;
+mod tlv;
 use std::fmt::{Debug, Display};
+use std::io;
 
-use internet2::presentation::{Error, TypedEnum, Unmarshall, Unmarshaller};
-pub use peer_connection::{PeerConnection, PeerReceiver, PeerSender, RecvMessage, SendMessage};
+#[cfg(feature = "async")]
+pub use async_split::{AsyncPeerReceiver, AsyncPeerSender, ASYNC_CHANNEL_CAPACITY};
+pub use dispatcher::{MessageHandler, PeerDispatcher};
+pub use features::FeatureFlags;
+pub use in_memory::InMemoryPeer;
+use internet2::presentation::{self, TypedEnum, Unmarshall, Unmarshaller};
+use internet2::transport;
+pub use listener::PeerListener;
+pub use peer_connection::{
+    BrontideSession, FtcpSession, Peer, PeerConnection, PeerReceiver, PeerSecurity, PeerSender,
+    RecvMessage, SendMessage, ZmqSession,
+};
+pub use pool::PeerPool;
+#[cfg(feature = "tls")]
+pub use tls::{TlsConfig, TlsSession, DEFAULT_TLS_HANDSHAKE_TIMEOUT};
+pub use tlv::{TlvError, TlvStream, MAX_TLV_STREAM_LEN};
 
 use crate::node::TryService;
 
+/// Errors happening during communications with the remote peer
+#[derive(Clone, Debug, Display, Error, From)]
+#[display(doc_comments)]
+#[non_exhaustive]
+pub enum Error {
+    /// {0}
+    #[from]
+    #[from(transport::Error)]
+    #[from(lightning_encoding::Error)]
+    Presentation(presentation::Error),
+
+    /// I/O error on the underlying connection stream: {0:?}
+    Io(io::ErrorKind),
+
+    /// connection with the remote peer was not established before the
+    /// deadline
+    Timeout,
+
+    /// connection is closed for writing
+    WriteClosed,
+
+    /// connection is closed for reading
+    ReadClosed,
+
+    /// operation is not supported by the connection transport: {0}
+    Unsupported(String),
+
+    /// TLS error: {0}
+    Tls(String),
+
+    /// message received from the remote peer is too short to contain message
+    /// type
+    MalformedMessage,
+
+    /// message of unknown even type {0} received from the remote peer
+    UnknownMessage(u16),
+
+    /// message frame of {total} bytes was sent partially, since the
+    /// connection stream can't accept more data without blocking; the stream
+    /// is corrupted and closed for writing
+    PartialSend {
+        /// Number of bytes of the frame written before the failure, if the
+        /// transport reports it
+        written: Option<usize>,
+        /// Size of the message frame if the transport reports written bytes,
+        /// or of the message data otherwise
+        total: usize,
+    },
+
+    /// limit of {0} simultaneous peer connections is reached
+    ConnectionLimit(usize),
+
+    /// invalid TLV stream: {0}
+    #[from]
+    Tlv(TlvError),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self { Error::Io(err.kind()) }
+}
+
 /// Trait for types handling specific LNPWP messages.
 pub trait Handler<T: TypedEnum> {
     type Error: crate::error::Error + From<Error>;
@@ -36,7 +122,7 @@ where
     H: Handler<T>,
     Unmarshaller<T>: Unmarshall,
     <Unmarshaller<T> as Unmarshall>::Data: Display + Debug,
-    <Unmarshaller<T> as Unmarshall>::Error: Into<Error>,
+    <Unmarshaller<T> as Unmarshall>::Error: Into<presentation::Error>,
 {
     receiver: PeerReceiver,
     handler: H,
@@ -49,7 +135,7 @@ where
     H: Handler<T>,
     Unmarshaller<T>: Unmarshall,
     <Unmarshaller<T> as Unmarshall>::Data: Display + Debug,
-    <Unmarshaller<T> as Unmarshall>::Error: Into<Error>,
+    <Unmarshaller<T> as Unmarshall>::Error: Into<presentation::Error>,
 {
     pub fn with(receiver: PeerReceiver, handler: H, unmarshaller: Unmarshaller<T>) -> Self {
         Self { receiver, handler, unmarshaller }
@@ -62,10 +148,13 @@ where
     H: Handler<T>,
     Unmarshaller<T>: Unmarshall,
     <Unmarshaller<T> as Unmarshall>::Data: Display + Debug,
-    <Unmarshaller<T> as Unmarshall>::Error: Into<Error>,
+    <Unmarshaller<T> as Unmarshall>::Error: Into<presentation::Error>,
 {
     type ErrorType = H::Error;
 
+    /// The run loop never terminates cleanly
+    type ShutdownReport = ();
+
     fn try_run_loop(mut self) -> Result<(), Self::ErrorType> {
         trace!("Entering event loop of the sender service");
         loop {
@@ -86,7 +175,7 @@ where
     H: Handler<T>,
     Unmarshaller<T>: Unmarshall,
     <Unmarshaller<T> as Unmarshall>::Data: Display + Debug,
-    <Unmarshaller<T> as Unmarshall>::Error: Into<Error>,
+    <Unmarshaller<T> as Unmarshall>::Error: Into<presentation::Error>,
 {
     fn run(&mut self) -> Result<(), H::Error> {
         trace!("Awaiting for peer messages...");
@@ -14,24 +14,312 @@
 //! BOLT-1. Manages state of the remote peer and handles direct communications
 //! with it. Relies on transport layer (BOLT-8-based) protocol.
 
+use std::any::Any;
 use std::fmt::Display;
-use std::io::Cursor;
+use std::io::{self, Cursor};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use amplify::Bipolar;
-use internet2::presentation::{Error, Unmarshall};
+use internet2::presentation::{self, Unmarshall};
 use internet2::session::{
-    self, Accept, Connect, LocalNode, PlainTranscoder, Session, Split, ToNodeAddr,
+    self, Accept, Connect, Encrypt, LocalNode, PlainTranscoder, Session, Split, ToNodeAddr,
+};
+use internet2::transport::{self, brontide, zmqsocket};
+use internet2::{
+    ftcp, NodeAddr, NoiseTranscoder, RemoteNodeAddr, RemoteSocketAddr, LIGHTNING_P2P_DEFAULT_PORT,
 };
-use internet2::transport::{brontide, zmqsocket};
-use internet2::{ftcp, NoiseTranscoder, LIGHTNING_P2P_DEFAULT_PORT};
 use lightning_encoding::LightningEncode;
 
+use super::listener::ConnectionSlot;
+use super::{Error, FeatureFlags};
+
+/// BOLT-1 `error` message type
+const BOLT_ERROR_MSG_TYPE: u16 = 17;
+/// BOLT-1 `ping` message type
+const BOLT_PING_MSG_TYPE: u16 = 18;
+/// BOLT-1 `pong` message type
+const BOLT_PONG_MSG_TYPE: u16 = 19;
+/// Minimal value of `num_pong_bytes` in BOLT-1 `ping` which must not be
+/// replied to
+const BOLT_PING_NO_REPLY: u16 = 65532;
+
+/// Encodes BOLT-1 `error` message for the channel with `channel_id`; all-zero
+/// channel id means the error which applies to the connection as a whole.
+/// Data exceeding maximum message field length are truncated.
+fn bolt_error_message(channel_id: [u8; 32], data: &[u8]) -> Vec<u8> {
+    let len = data.len().min(u16::MAX as usize);
+    let mut msg = Vec::with_capacity(2 + 32 + 2 + len);
+    msg.extend_from_slice(&BOLT_ERROR_MSG_TYPE.to_be_bytes());
+    msg.extend_from_slice(&channel_id);
+    msg.extend_from_slice(&(len as u16).to_be_bytes());
+    msg.extend_from_slice(&data[..len]);
+    msg
+}
+
+/// Parses BOLT-1 `ping` message and constructs `pong` message replying to it.
+/// Returns `None` if the payload is not a well-formed ping or if the ping
+/// must not be replied to.
+fn bolt_pong_reply(payload: &[u8]) -> Option<Vec<u8>> {
+    if payload.len() < 6 || u16::from_be_bytes([payload[0], payload[1]]) != BOLT_PING_MSG_TYPE {
+        return None;
+    }
+    let num_pong_bytes = u16::from_be_bytes([payload[2], payload[3]]);
+    let byteslen = u16::from_be_bytes([payload[4], payload[5]]) as usize;
+    if payload.len() < 6 + byteslen || num_pong_bytes >= BOLT_PING_NO_REPLY {
+        return None;
+    }
+    let mut msg = Vec::with_capacity(4 + num_pong_bytes as usize);
+    msg.extend_from_slice(&BOLT_PONG_MSG_TYPE.to_be_bytes());
+    msg.extend_from_slice(&num_pong_bytes.to_be_bytes());
+    msg.resize(4 + num_pong_bytes as usize, 0u8);
+    Some(msg)
+}
+
+/// Session running over plain FTCP
+pub type FtcpSession = session::Raw<PlainTranscoder, ftcp::Connection>;
+/// Session running over brontide-encrypted TCP
+pub type BrontideSession = session::Raw<NoiseTranscoder, brontide::Connection>;
+/// Session running over unencrypted ZMQ socket
+pub type ZmqSession = session::Raw<PlainTranscoder, zmqsocket::Connection>;
+
+/// Session which can be downcasted by reference to its concrete type
+trait AnySession: Session {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn as_session(&self) -> &dyn Session;
+}
+
+impl<T> AnySession for T
+where
+    T: Session + 'static,
+{
+    fn as_any(&self) -> &dyn Any { self }
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+    fn as_session(&self) -> &dyn Session { self }
+}
+
+/// Recovers concrete type of the session produced by `internet2`, failing
+/// with [`Error::Unsupported`] for the session types unknown to this crate
+fn into_any_session(session: Box<dyn Session>) -> Result<Box<dyn AnySession>, Error> {
+    let session = match session.into_any().downcast::<FtcpSession>() {
+        Ok(session) => return Ok(session),
+        Err(session) => session,
+    };
+    let session = match session.downcast::<BrontideSession>() {
+        Ok(session) => return Ok(session),
+        Err(session) => session,
+    };
+    match session.downcast::<ZmqSession>() {
+        Ok(session) => Ok(session),
+        Err(_) => Err(Error::Unsupported(s!("session of unknown type"))),
+    }
+}
+
+/// Detects whether the concrete session type encrypts transmitted data
+fn is_encrypted_session(session: &dyn Any) -> bool {
+    #[cfg(feature = "tls")]
+    if session.is::<super::TlsSession>() {
+        return true;
+    }
+    session.is::<session::Raw<NoiseTranscoder, brontide::Connection>>()
+}
+
+/// Converts error of sending message with `total` bytes of data by the
+/// `internet2` session. Brontide encrypts and writes message frames
+/// internally in a loop until they are complete, so the frame may be left
+/// written partially only if the stream can't accept more data before the
+/// write timeout elapses. Such failures are reported as
+/// [`Error::PartialSend`]: the transport does not report how much was
+/// written, so the sender must ensure with [`await_writable`] that at least
+/// some data were accepted.
+fn send_error(err: presentation::Error, total: usize, stream: bool) -> Error {
+    let blocked = match err {
+        presentation::Error::Transport(transport::Error::TimedOut) => true,
+        presentation::Error::Transport(transport::Error::SocketIo(kind)) => is_blocked(kind),
+        _ => false,
+    };
+    if blocked && stream {
+        Error::PartialSend { written: None, total }
+    } else {
+        err.into()
+    }
+}
+
+fn is_blocked(kind: io::ErrorKind) -> bool {
+    matches!(kind, io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// Waits until the TCP `stream` can accept more data, for no longer than the
+/// stream write timeout. Returns `false` if the stream stayed full, so a send
+/// would fail without writing anything and may be retried later. Streams
+/// without write timeout block in the write itself and are not polled.
+fn await_writable(stream: &TcpStream) -> Result<bool, Error> {
+    let timeout = match stream.write_timeout()? {
+        Some(timeout) => timeout.as_millis() as i64,
+        None => return Ok(true),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        let mut items = [zmq::PollItem::from_fd(stream.as_raw_fd(), zmq::POLLOUT)];
+        zmq::poll(&mut items, timeout).map_err(io::Error::from)?;
+        Ok(items[0].is_writable())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = timeout;
+        Ok(true)
+    }
+}
+
+/// Encodes `raw` message data into FTCP frame, which is used both by plain
+/// FTCP and TLS sessions
+pub(super) fn plain_frame(raw: &[u8]) -> Result<Vec<u8>, Error> {
+    if raw.len() > u16::MAX as usize {
+        return Err(Error::Io(io::ErrorKind::InvalidInput));
+    }
+    Ok(PlainTranscoder.encrypt(raw))
+}
+
+/// Writes complete message `frame` to a stream transport in a loop, tracking
+/// the number of bytes written. If the stream can't accept more data before
+/// anything was written, the send can be retried and [`Error::Io`] is
+/// returned; if the frame was left written partially, [`Error::PartialSend`]
+/// is returned.
+pub(super) fn write_frame(writer: &mut impl io::Write, frame: &[u8]) -> Result<usize, Error> {
+    let mut written = 0usize;
+    while written < frame.len() {
+        match writer.write(&frame[written..]) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+            Ok(len) => written += len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) if written > 0 && is_blocked(err.kind()) => {
+                return Err(Error::PartialSend { written: Some(written), total: frame.len() })
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(written)
+}
+
+/// Extracts IP socket address of the remote node accessible via TCP/IP; fails
+/// with [`Error::Unsupported`] naming the `operation` for other addresses
+pub(super) fn tcp_endpoint(
+    endpoint: NodeAddr,
+    operation: &str,
+) -> Result<(RemoteNodeAddr, SocketAddr), Error> {
+    let remote = match endpoint {
+        NodeAddr::Remote(remote) => remote,
+        other => return Err(Error::Unsupported(format!("{} to {}", operation, other))),
+    };
+    let inet_addr = match &remote.remote_addr {
+        RemoteSocketAddr::Ftcp(inet_addr) => inet_addr.to_string(),
+        other => return Err(Error::Unsupported(format!("{} to {}", operation, other))),
+    };
+    let socket_addr = inet_addr.parse().map_err(|_| {
+        Error::Unsupported(format!("{} to non-IP address {}", operation, inet_addr))
+    })?;
+    Ok((remote, socket_addr))
+}
+
+/// Way of securing connections with remote peers, used by
+/// [`PeerConnection::connect`] and [`PeerConnection::accept`]
+pub trait PeerSecurity {
+    /// Connects to the remote peer at `endpoint`
+    fn connect_peer(&self, endpoint: NodeAddr) -> Result<PeerConnection, Error>;
+
+    /// Accepts single connection from a remote peer at local `endpoint`
+    fn accept_peer(&self, endpoint: NodeAddr) -> Result<PeerConnection, Error>;
+}
+
+/// Local node keys secure connections with remote nodes by BOLT-8 (brontide)
+/// handshake; other endpoints are connected with the `internet2` sessions
+/// matching their address
+impl PeerSecurity for LocalNode {
+    fn connect_peer(&self, endpoint: NodeAddr) -> Result<PeerConnection, Error> {
+        // TCP stream is kept by the connection for half-close support
+        if let Ok((remote, socket_addr)) = tcp_endpoint(endpoint.clone(), "connection") {
+            debug!("Connecting to {}", socket_addr);
+            let stream = TcpStream::connect(socket_addr)?;
+            return PeerConnection::from_tcp_stream(stream, self, Some(&remote));
+        }
+        let encrypted = is_encrypted_endpoint(&endpoint);
+        let session = endpoint.connect(self)?;
+        PeerConnection::from_boxed(session, encrypted)
+    }
+
+    fn accept_peer(&self, endpoint: NodeAddr) -> Result<PeerConnection, Error> {
+        if let Ok((_, socket_addr)) = tcp_endpoint(endpoint.clone(), "connection") {
+            debug!("Accepting connection at {}", socket_addr);
+            let (stream, _) = TcpListener::bind(socket_addr)?.accept()?;
+            return PeerConnection::from_tcp_stream(stream, self, None);
+        }
+        let encrypted = is_encrypted_endpoint(&endpoint);
+        let session = endpoint.accept(self)?;
+        PeerConnection::from_boxed(session, encrypted)
+    }
+}
+
+/// Detects whether session established with the endpoint encrypts data: remote
+/// nodes are connected over TCP with brontide, while all other connections are
+/// unencrypted
+fn is_encrypted_endpoint(endpoint: &NodeAddr) -> bool {
+    matches!(
+        endpoint,
+        NodeAddr::Remote(RemoteNodeAddr { remote_addr: RemoteSocketAddr::Ftcp(_), .. })
+    )
+}
+
+/// Object-safe interface of a connection with the remote peer, abstracting over
+/// the concrete connection type. Code generic over `impl Peer` (or working
+/// with `Box<dyn Peer>`) can be tested without networking with
+/// [`super::InMemoryPeer`].
+///
+/// All peers receive and send typed messages via blanket implementations of
+/// [`RecvMessage`] and [`SendMessage`].
+pub trait Peer {
+    /// Receives next raw message from the remote peer
+    fn recv_raw_message(&mut self) -> Result<Vec<u8>, Error>;
+
+    /// Sends raw message to the remote peer, returning number of bytes sent
+    fn send_raw_message(&mut self, data: &[u8]) -> Result<usize, Error>;
+
+    /// Closes the writing half of the connection
+    fn shutdown_write(&mut self) -> Result<(), Error>;
+
+    /// Closes the reading half of the connection
+    fn shutdown_read(&mut self) -> Result<(), Error>;
+
+    /// Detects whether the connection encrypts transmitted data
+    fn is_encrypted(&self) -> bool;
+}
+
+impl<P> Peer for Box<P>
+where
+    P: Peer + ?Sized,
+{
+    fn recv_raw_message(&mut self) -> Result<Vec<u8>, Error> { (**self).recv_raw_message() }
+
+    fn send_raw_message(&mut self, data: &[u8]) -> Result<usize, Error> {
+        (**self).send_raw_message(data)
+    }
+
+    fn shutdown_write(&mut self) -> Result<(), Error> { (**self).shutdown_write() }
+
+    fn shutdown_read(&mut self) -> Result<(), Error> { (**self).shutdown_read() }
+
+    fn is_encrypted(&self) -> bool { (**self).is_encrypted() }
+}
+
 pub trait RecvMessage {
     fn recv_message<D>(&mut self, d: &D) -> Result<D::Data, Error>
     where
         D: Unmarshall,
         <D as Unmarshall>::Data: Display,
-        <D as Unmarshall>::Error: Into<Error>;
+        <D as Unmarshall>::Error: Into<presentation::Error>;
 }
 
 pub trait SendMessage {
@@ -39,63 +327,550 @@ pub trait SendMessage {
 }
 
 pub struct PeerConnection {
-    session: Box<dyn Session>,
+    session: Box<dyn AnySession>,
+    /// Handle to the TCP stream underlying the session, if known, used for
+    /// transport-level operations like half-close
+    stream: Option<TcpStream>,
+    write_closed: bool,
+    read_closed: bool,
+    auto_pong: bool,
+    /// Features sent to the remote peer in BOLT-1 `init` message
+    local_features: Option<FeatureFlags>,
+    /// Features received from the remote peer in BOLT-1 `init` message
+    remote_features: Option<FeatureFlags>,
+    /// Whether the session encrypts transmitted data
+    encrypted: bool,
+    /// Slot occupied by the connection in the connection limit of the
+    /// listener which has accepted it, freed once the connection is dropped
+    pub(super) slot: Option<Arc<ConnectionSlot>>,
+    #[cfg(feature = "wire-trace")]
+    on_raw_send: Option<Box<dyn FnMut(&[u8])>>,
+    #[cfg(feature = "wire-trace")]
+    on_raw_recv: Option<Box<dyn FnMut(&[u8])>>,
 }
 
 pub struct PeerReceiver {
     //#[cfg(not(feature = "async"))]
-    receiver: Box<dyn session::Input + Send>,
+    pub(super) receiver: Box<dyn session::Input + Send>,
     /* #[cfg(feature = "async")]
      * receiver: Box<dyn AsyncRecvFrame>, */
+    /// Connection limit slot, shared with the sending half
+    slot: Option<Arc<ConnectionSlot>>,
 }
 
 pub struct PeerSender {
     //#[cfg(not(feature = "async"))]
-    sender: Box<dyn session::Output + Send>,
+    pub(super) sender: Arc<Mutex<Box<dyn session::Output + Send>>>,
     /* #[cfg(feature = "async")]
      * sender: Box<dyn AsyncSendFrame>, */
+    /// Whether the transport allows sending from multiple handles
+    shareable: bool,
+    /// Whether messages are sent as plain FTCP frames written directly to the
+    /// TCP stream, tracking partial writes
+    plain: bool,
+    /// Whether a message was sent partially, corrupting the stream
+    corrupted: Arc<AtomicBool>,
+    /// TCP stream of the connection, if known
+    stream: Option<Arc<TcpStream>>,
+    /// Connection limit slot, shared with the receiving half
+    slot: Option<Arc<ConnectionSlot>>,
+}
+
+impl PeerSender {
+    fn with(
+        sender: Box<dyn session::Output + Send>,
+        shareable: bool,
+        plain: bool,
+        stream: Option<TcpStream>,
+        slot: Option<Arc<ConnectionSlot>>,
+    ) -> Self {
+        PeerSender {
+            sender: Arc::new(Mutex::new(sender)),
+            shareable,
+            plain,
+            corrupted: default!(),
+            stream: stream.map(Arc::new),
+            slot,
+        }
+    }
+
+    /// Returns another handle sending messages to the same remote peer, which
+    /// can be moved to a different thread, allowing multiple producers to
+    /// send messages to the peer without serializing them through a channel.
+    /// Sends from all the handles are serialized with an internal lock, so
+    /// messages are never interleaved on the wire.
+    ///
+    /// ZMQ sockets can't be used concurrently with the receiving half of the
+    /// connection, so for ZMQ-based connections [`Error::Unsupported`] is
+    /// returned.
+    pub fn try_clone(&self) -> Result<PeerSender, Error> {
+        if !self.shareable {
+            return Err(Error::Unsupported(s!(
+                "multiple senders are not supported by ZMQ-based connections"
+            )));
+        }
+        Ok(PeerSender {
+            sender: self.sender.clone(),
+            shareable: true,
+            plain: self.plain,
+            corrupted: self.corrupted.clone(),
+            stream: self.stream.clone(),
+            slot: self.slot.clone(),
+        })
+    }
+
+    /// Sends raw message data to the remote peer
+    pub(super) fn send_raw_message(&self, data: &[u8]) -> Result<usize, Error> {
+        if self.corrupted.load(Ordering::SeqCst) {
+            return Err(Error::WriteClosed);
+        }
+        let mut sender = self.sender.lock().expect("peer sender lock is poisoned");
+        let res = match self.stream {
+            Some(ref stream) if self.plain => {
+                write_frame(&mut &**stream, &plain_frame(data)?).map(|_| data.len())
+            }
+            Some(ref stream) if !await_writable(stream)? => {
+                return Err(Error::Io(io::ErrorKind::WouldBlock))
+            }
+            // Only stream transports are shareable; ZMQ sends are atomic
+            _ => sender
+                .send_raw_message(data)
+                .map_err(|err| send_error(err, data.len(), self.shareable)),
+        };
+        res.map_err(|err| {
+            if let Error::PartialSend { .. } = err {
+                self.corrupted.store(true, Ordering::SeqCst);
+            }
+            err
+        })
+    }
 }
 
 impl PeerConnection {
-    pub fn with(session: impl Session + 'static) -> Self { Self { session: Box::new(session) } }
+    pub fn with(session: impl Session + 'static) -> Self {
+        let encrypted = is_encrypted_session(&session);
+        Self::with_session(Box::new(session), encrypted)
+    }
+
+    /// Constructs peer connection from a session running over the provided
+    /// TCP `stream`. Keeping the stream handle allows transport-level
+    /// operations not exposed by the session itself, like half-close.
+    pub fn with_stream(session: impl Session + 'static, stream: TcpStream) -> Self {
+        Self { stream: Some(stream), ..Self::with(session) }
+    }
+
+    fn from_boxed(session: Box<dyn Session>, encrypted: bool) -> Result<Self, Error> {
+        Ok(Self::with_session(into_any_session(session)?, encrypted))
+    }
+
+    fn with_session(session: Box<dyn AnySession>, encrypted: bool) -> Self {
+        Self {
+            session,
+            stream: None,
+            write_closed: false,
+            read_closed: false,
+            auto_pong: false,
+            local_features: None,
+            remote_features: None,
+            encrypted,
+            slot: None,
+            #[cfg(feature = "wire-trace")]
+            on_raw_send: None,
+            #[cfg(feature = "wire-trace")]
+            on_raw_recv: None,
+        }
+    }
+
+    /// Registers callback receiving raw data of each message sent to the
+    /// remote peer by [`SendMessage::send_message`]
+    #[cfg(feature = "wire-trace")]
+    pub fn on_raw_send(&mut self, tap: impl FnMut(&[u8]) + 'static) -> &mut Self {
+        self.on_raw_send = Some(Box::new(tap));
+        self
+    }
+
+    /// Registers callback receiving raw data of each message received from
+    /// the remote peer by [`RecvMessage::recv_message`]
+    #[cfg(feature = "wire-trace")]
+    pub fn on_raw_recv(&mut self, tap: impl FnMut(&[u8]) + 'static) -> &mut Self {
+        self.on_raw_recv = Some(Box::new(tap));
+        self
+    }
+
+    /// Enables or disables automatic replies to BOLT-1 `ping` messages. When
+    /// enabled, each `ping` received by [`RecvMessage::recv_message`] is
+    /// replied with a `pong` of the requested length before the `ping` itself
+    /// is returned to the caller. The `pong` is sent in the same way as other
+    /// messages, including the raw data tap and partial send handling.
+    pub fn auto_pong(&mut self, enabled: bool) -> &mut Self {
+        self.auto_pong = enabled;
+        self
+    }
+
+    /// Sends BOLT-1 `init` message advertising the local `features` to the
+    /// remote peer. Once the `init` message of the remote peer is received
+    /// with [`RecvMessage::recv_message`], the features supported by both
+    /// sides are available via [`PeerConnection::negotiated_features`].
+    pub fn send_init(&mut self, features: FeatureFlags) -> Result<usize, Error> {
+        let sent = self.send_raw_message(&features.to_init_message())?;
+        self.local_features = Some(features);
+        Ok(sent)
+    }
+
+    /// Returns features advertised by the remote peer in its BOLT-1 `init`
+    /// message, if it was already received
+    pub fn init_features(&self) -> Option<&FeatureFlags> { self.remote_features.as_ref() }
+
+    /// Returns features supported by both local and remote peers, if `init`
+    /// messages were already exchanged (see [`FeatureFlags::negotiate`])
+    pub fn negotiated_features(&self) -> Option<FeatureFlags> {
+        Some(FeatureFlags::negotiate(self.local_features.as_ref()?, self.remote_features.as_ref()?))
+    }
+
+    /// Connects to the remote peer, securing the connection in the way defined
+    /// by `local` (see [`PeerSecurity`])
+    pub fn connect(remote: impl ToNodeAddr, local: &impl PeerSecurity) -> Result<Self, Error> {
+        let endpoint = remote
+            .to_node_addr(LIGHTNING_P2P_DEFAULT_PORT)
+            .ok_or(presentation::Error::InvalidEndpoint)?;
+        local.connect_peer(endpoint)
+    }
+
+    /// Accepts single connection from the remote peer, securing it in the way
+    /// defined by `local` (see [`PeerSecurity`])
+    pub fn accept(remote: impl ToNodeAddr, local: &impl PeerSecurity) -> Result<Self, Error> {
+        let endpoint = remote
+            .to_node_addr(LIGHTNING_P2P_DEFAULT_PORT)
+            .ok_or(presentation::Error::InvalidEndpoint)?;
+        local.accept_peer(endpoint)
+    }
+
+    /// Connects to the remote peer like [`PeerConnection::connect`], but fails
+    /// with [`Error::Timeout`] if both TCP connection and the following Noise
+    /// handshake were not completed within `timeout`.
+    ///
+    /// Only remote nodes accessible via TCP/IP are supported; for other
+    /// addresses [`Error::Unsupported`] is returned.
+    pub fn connect_timeout(
+        remote: impl ToNodeAddr,
+        local: &LocalNode,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let deadline = Instant::now() + timeout;
+        let endpoint = remote
+            .to_node_addr(LIGHTNING_P2P_DEFAULT_PORT)
+            .ok_or(presentation::Error::InvalidEndpoint)?;
+        let (remote, socket_addr) = tcp_endpoint(endpoint, "connection with timeout")?;
+        let node_id = remote.node_id;
+
+        debug!("Connecting to {} with {:?} timeout", socket_addr, timeout);
+        let stream = TcpStream::connect_timeout(&socket_addr, timeout).map_err(|err| {
+            if err.kind() == io::ErrorKind::TimedOut {
+                Error::Timeout
+            } else {
+                Error::from(err)
+            }
+        })?;
+
+        // Handshake must fit into the rest of the time budget
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+            .ok_or(Error::Timeout)?;
+        stream.set_read_timeout(Some(remaining))?;
+        stream.set_write_timeout(Some(remaining))?;
+        let session =
+            session::Raw::with_brontide(stream.try_clone()?, local.private_key(), node_id)
+                .map_err(|err| {
+                    if Instant::now() >= deadline {
+                        Error::Timeout
+                    } else {
+                        Error::from(err)
+                    }
+                })?;
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+
+        Ok(Self::with_stream(session, stream))
+    }
+
+    /// Constructs connection from TCP `stream` accepted or connected by the
+    /// caller, performing BOLT-8 (brontide) handshake over it. This allows
+    /// servers to run their own accept loop, for instance applying connection
+    /// limits or accept throttling, and hand the accepted streams over.
+    ///
+    /// If `initiator` is given, the handshake is performed as the initiator
+    /// towards the node with the given id (which is required by the
+    /// handshake); otherwise this side acts as the handshake responder, which
+    /// is the case for the streams accepted from a listener.
+    pub fn from_tcp_stream(
+        stream: TcpStream,
+        local: &LocalNode,
+        initiator: Option<&RemoteNodeAddr>,
+    ) -> Result<Self, Error> {
+        let session = match initiator {
+            Some(remote) => {
+                debug!("Performing handshake with {} over provided stream", remote);
+                session::Raw::with_brontide(
+                    stream.try_clone()?,
+                    local.private_key(),
+                    remote.node_id,
+                )?
+            }
+            None => {
+                debug!(
+                    "Responding to handshake from {:?} over provided stream",
+                    stream.peer_addr()
+                );
+                session::Raw::with_brontide_accept(stream.try_clone()?, local.private_key())?
+            }
+        };
+        Ok(Self::with_stream(session, stream))
+    }
+
+    /// Accepts next TLS-protected TCP connection from the `listener`. If
+    /// `config` specifies certificate authority, the remote peer must present
+    /// a certificate issued by it.
+    ///
+    /// Unlike [`PeerConnection::accept`] with [`super::TlsConfig`], this allows
+    /// to accept multiple connections at the same address.
+    #[cfg(feature = "tls")]
+    pub fn accept_tls(listener: &TcpListener, config: &super::TlsConfig) -> Result<Self, Error> {
+        let session = super::TlsSession::accept(listener, config)?;
+        let stream = session.try_clone_stream()?;
+        Ok(Self::with_stream(session, stream))
+    }
+
+    /// Provides access to the session underlying the connection.
+    ///
+    /// This and the following downcasting methods are an escape hatch for
+    /// transport-specific operations not exposed by the connection itself.
+    /// Concrete session types are defined by `internet2` and may change
+    /// between its versions (as well as the choice of the session type for a
+    /// given kind of connection), so code relying on them should be prepared
+    /// for the downcast to return `None` after a dependency upgrade.
+    pub fn session_ref(&self) -> &dyn Session { self.session.as_session() }
+
+    /// Returns underlying session if the connection runs over unencrypted ZMQ
+    pub fn as_zmq(&self) -> Option<&ZmqSession> { self.session.as_any().downcast_ref() }
+
+    /// Returns underlying session if the connection runs over brontide
+    pub fn as_brontide(&self) -> Option<&BrontideSession> { self.session.as_any().downcast_ref() }
+
+    /// Returns underlying session if the connection runs over plain FTCP
+    pub fn as_ftcp(&self) -> Option<&FtcpSession> { self.session.as_any().downcast_ref() }
+
+    /// Detects whether the connection encrypts transmitted data, which is the
+    /// case for brontide and TLS sessions, but not for plain FTCP and
+    /// unencrypted ZMQ. Allows to ensure that the channel is secure before
+    /// sending sensitive data.
+    #[inline]
+    pub fn is_encrypted(&self) -> bool { self.encrypted }
+
+    /// Splits the connection into asynchronous stream of messages received
+    /// from the remote peer, decoded with `unmarshaller`, and a sink for the
+    /// messages sent to it.
+    ///
+    /// Async split is supported only for connections running over a known TCP
+    /// stream (see [`PeerConnection::with_stream`]); ZMQ sessions are not
+    /// supported and result in [`Error::Unsupported`].
+    #[cfg(feature = "async")]
+    pub fn split_async<D>(
+        self,
+        unmarshaller: D,
+    ) -> Result<(super::AsyncPeerReceiver<D>, super::AsyncPeerSender), Error> {
+        let stream = match self.stream {
+            Some(ref stream) => stream.try_clone()?,
+            None => {
+                return Err(Error::Unsupported(s!(
+                    "async split requires connection over a known TCP stream"
+                )))
+            }
+        };
+        let (receiver, sender) = self.split();
+        Ok(super::async_split::spawn(receiver, sender, unmarshaller, stream))
+    }
+
+    /// Closes the writing half of the connection: the remote peer receives
+    /// EOF, while this side may still receive messages. Any further
+    /// [`SendMessage::send_message`] call fails with [`Error::WriteClosed`].
+    ///
+    /// Half-close is supported only for connections running over a known TCP
+    /// stream (see [`PeerConnection::with_stream`]); ZMQ has no notion of
+    /// half-close, so for the other transports [`Error::Unsupported`] is
+    /// returned.
+    pub fn shutdown_write(&mut self) -> Result<(), Error> {
+        self.shutdown(Shutdown::Write)?;
+        self.write_closed = true;
+        Ok(())
+    }
+
+    /// Closes the reading half of the connection. Any further
+    /// [`RecvMessage::recv_message`] call fails with [`Error::ReadClosed`].
+    ///
+    /// See [`PeerConnection::shutdown_write`] for the transports supporting
+    /// half-close.
+    pub fn shutdown_read(&mut self) -> Result<(), Error> {
+        self.shutdown(Shutdown::Read)?;
+        self.read_closed = true;
+        Ok(())
+    }
 
-    pub fn connect(remote: impl ToNodeAddr, local: &LocalNode) -> Result<Self, Error> {
-        let endpoint =
-            remote.to_node_addr(LIGHTNING_P2P_DEFAULT_PORT).ok_or(Error::InvalidEndpoint)?;
-        let session = endpoint.connect(local)?;
-        Ok(Self { session })
+    /// Closes the connection for writing if the message was sent partially,
+    /// since the stream is corrupted
+    fn check_sent(&mut self, res: Result<usize, Error>) -> Result<usize, Error> {
+        if let Err(Error::PartialSend { .. }) = res {
+            warn!("Message was sent partially; closing the connection for writing");
+            self.write_closed = true;
+        }
+        res
     }
 
-    pub fn accept(remote: impl ToNodeAddr, local: &LocalNode) -> Result<Self, Error> {
-        let endpoint =
-            remote.to_node_addr(LIGHTNING_P2P_DEFAULT_PORT).ok_or(Error::InvalidEndpoint)?;
-        let session = endpoint.accept(local)?;
-        Ok(Self { session })
+    fn shutdown(&mut self, how: Shutdown) -> Result<(), Error> {
+        let stream = self.stream.as_ref().ok_or_else(|| {
+            Error::Unsupported(s!("half-close requires connection over a known TCP stream"))
+        })?;
+        debug!("Shutting down connection to the remote peer: {:?}", how);
+        stream.shutdown(how)?;
+        Ok(())
+    }
+
+    /// Rejects connected remote peer: sends BOLT-1 `error` message with the
+    /// provided `reason` and closes the connection, so the remote side may
+    /// learn why it was disconnected. Like with
+    /// [`PeerConnection::close_with_error`], the message is sent only over
+    /// brontide sessions.
+    pub fn reject(mut self, reason: String) -> Result<(), Error> {
+        debug!("Rejecting remote peer: {}", reason);
+        self.send_bolt_error([0u8; 32], reason.as_bytes())?;
+        self.close()
+    }
+
+    /// Closes the connection, shutting down the underlying TCP stream if it
+    /// is known (see [`PeerConnection::with_stream`]); otherwise the session
+    /// is closed once dropped.
+    pub fn close(self) -> Result<(), Error> {
+        debug!("Closing connection to the remote peer");
+        if let Some(ref stream) = self.stream {
+            match stream.shutdown(Shutdown::Both) {
+                Err(err) if err.kind() != io::ErrorKind::NotConnected => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes the connection giving the remote peer a reason for the
+    /// disconnect. For brontide sessions BOLT-1 `error` message with
+    /// `channel_id` and `data` is sent before closing the connection; all-zero
+    /// `channel_id` refers to all channels with the peer. Plain and ZMQ
+    /// sessions are not used for Lightning P2P, so they are just closed.
+    pub fn close_with_error(mut self, channel_id: [u8; 32], data: &[u8]) -> Result<(), Error> {
+        debug!("Closing connection to the remote peer with error message");
+        self.send_bolt_error(channel_id, data)?;
+        self.close()
+    }
+
+    /// Sends BOLT-1 `error` message over brontide sessions which are still
+    /// open for writing, passing it through the raw data tap
+    fn send_bolt_error(&mut self, channel_id: [u8; 32], data: &[u8]) -> Result<(), Error> {
+        if self.as_brontide().is_some() && !self.write_closed {
+            Peer::send_raw_message(self, &bolt_error_message(channel_id, data))?;
+        }
+        Ok(())
     }
 }
 
-impl RecvMessage for PeerConnection {
+impl Peer for PeerConnection {
+    fn recv_raw_message(&mut self) -> Result<Vec<u8>, Error> {
+        if self.read_closed {
+            return Err(Error::ReadClosed);
+        }
+        let payload = self.session.recv_raw_message()?;
+        #[cfg(feature = "wire-trace")]
+        if let Some(ref mut tap) = self.on_raw_recv {
+            tap(&payload);
+        }
+        if let Some(features) = FeatureFlags::from_init_message(&payload) {
+            trace!("Remote peer has sent init message with features {:?}", features);
+            self.remote_features = Some(features);
+        }
+        if self.auto_pong && !self.write_closed {
+            if let Some(pong) = bolt_pong_reply(&payload) {
+                trace!("Replying to ping from the remote peer with {} bytes pong", pong.len());
+                Peer::send_raw_message(self, &pong)?;
+            }
+        }
+        Ok(payload)
+    }
+
+    fn send_raw_message(&mut self, data: &[u8]) -> Result<usize, Error> {
+        if self.write_closed {
+            return Err(Error::WriteClosed);
+        }
+        #[cfg(feature = "wire-trace")]
+        if let Some(ref mut tap) = self.on_raw_send {
+            tap(data);
+        }
+        #[cfg(feature = "tls")]
+        if let Some(session) = self.session.as_any_mut().downcast_mut::<super::TlsSession>() {
+            let res = session.send_frame(data);
+            return self.check_sent(res);
+        }
+        // Plain FTCP frames are written directly, tracking partial writes
+        if let (Some(stream), true) = (&self.stream, self.as_ftcp().is_some()) {
+            let res = plain_frame(data)
+                .and_then(|frame| write_frame(&mut &*stream, &frame))
+                .map(|_| data.len());
+            return self.check_sent(res);
+        }
+        if let Some(ref stream) = self.stream {
+            if !await_writable(stream)? {
+                return Err(Error::Io(io::ErrorKind::WouldBlock));
+            }
+        }
+        let stream = self.as_zmq().is_none();
+        let res =
+            self.session.send_raw_message(data).map_err(|err| send_error(err, data.len(), stream));
+        self.check_sent(res)
+    }
+
+    fn shutdown_write(&mut self) -> Result<(), Error> { PeerConnection::shutdown_write(self) }
+
+    fn shutdown_read(&mut self) -> Result<(), Error> { PeerConnection::shutdown_read(self) }
+
+    fn is_encrypted(&self) -> bool { PeerConnection::is_encrypted(self) }
+}
+
+impl<P> RecvMessage for P
+where
+    P: Peer + ?Sized,
+{
     fn recv_message<D>(&mut self, d: &D) -> Result<D::Data, Error>
     where
         D: Unmarshall,
         <D as Unmarshall>::Data: Display,
-        <D as Unmarshall>::Error: Into<Error>,
+        <D as Unmarshall>::Error: Into<presentation::Error>,
     {
         debug!("Awaiting incoming messages from the remote peer");
-        let payload = self.session.recv_raw_message()?;
+        let payload = self.recv_raw_message()?;
         trace!("Incoming data from the remote peer: {:?}", payload);
-        let message: D::Data = d.unmarshall(Cursor::new(payload)).map_err(Into::into)?;
+        let message: D::Data =
+            d.unmarshall(Cursor::new(payload)).map_err(Into::<presentation::Error>::into)?;
         debug!("Message from the remote peer: {}", message);
         Ok(message)
     }
 }
 
-impl SendMessage for PeerConnection {
+impl<P> SendMessage for P
+where
+    P: Peer + ?Sized,
+{
     fn send_message(&mut self, message: impl LightningEncode + Display) -> Result<usize, Error> {
         debug!("Sending LN message to the remote peer: {}", message);
         let data = &message.lightning_serialize()?;
         trace!("Lightning-encoded message representation: {:?}", data);
-        Ok(self.session.send_raw_message(data)?)
+        self.send_raw_message(data)
     }
 }
 
@@ -104,12 +879,13 @@ impl RecvMessage for PeerReceiver {
     where
         D: Unmarshall,
         <D as Unmarshall>::Data: Display,
-        <D as Unmarshall>::Error: Into<Error>,
+        <D as Unmarshall>::Error: Into<presentation::Error>,
     {
         debug!("Awaiting incoming messages from the remote peer");
         let payload = self.receiver.recv_raw_message()?;
         trace!("Incoming data from the remote peer: {:?}", payload);
-        let message: D::Data = d.unmarshall(Cursor::new(payload)).map_err(Into::into)?;
+        let message: D::Data =
+            d.unmarshall(Cursor::new(payload)).map_err(Into::<presentation::Error>::into)?;
         debug!("Message from the remote peer: {}", message);
         Ok(message)
     }
@@ -120,7 +896,7 @@ impl SendMessage for PeerSender {
         debug!("Sending LN message to the remote peer: {}", message);
         let data = &message.lightning_serialize()?;
         trace!("Lightning-encoded message representation: {:?}", data);
-        Ok(self.sender.send_raw_message(data)?)
+        self.send_raw_message(data)
     }
 }
 
@@ -134,31 +910,144 @@ impl Bipolar for PeerConnection {
     }
 
     fn split(self) -> (Self::Left, Self::Right) {
+        let stream = self.stream;
+        let slot = self.slot;
         let session = self.session.into_any();
-        let (input, output) = if let Some(_) =
+        let (input, output, shareable, plain) = if let Some(_) =
             session.downcast_ref::<session::Raw<PlainTranscoder, ftcp::Connection>>()
         {
             let session = session
                 .downcast::<session::Raw<PlainTranscoder, ftcp::Connection>>()
                 .expect("Must not fail; we just ensured that with downcast_ref");
-            (*session).split()
+            let (input, output) = (*session).split();
+            (input, output, true, true)
         } else if let Some(_) =
             session.downcast_ref::<session::Raw<NoiseTranscoder, brontide::Connection>>()
         {
             let session = session
                 .downcast::<session::Raw<NoiseTranscoder, brontide::Connection>>()
                 .expect("Must not fail; we just ensured that with downcast_ref");
-            (*session).split()
+            let (input, output) = (*session).split();
+            (input, output, true, false)
         } else if let Some(_) =
             session.downcast_ref::<session::Raw<PlainTranscoder, zmqsocket::Connection>>()
         {
             let session = session
                 .downcast::<session::Raw<PlainTranscoder, zmqsocket::Connection>>()
                 .expect("Must not fail; we just ensured that with downcast_ref");
-            (*session).split()
+            let (input, output) = (*session).split();
+            (input, output, false, false)
         } else {
+            #[cfg(feature = "tls")]
+            if session.downcast_ref::<super::TlsSession>().is_some() {
+                let session = session
+                    .downcast::<super::TlsSession>()
+                    .expect("Must not fail; we just ensured that with downcast_ref");
+                let (input, output) = (*session).split();
+                return (
+                    PeerReceiver { receiver: input, slot: slot.clone() },
+                    PeerSender::with(output, true, false, stream, slot),
+                );
+            }
             panic!("Impossible to split this type of Session")
         };
-        (PeerReceiver { receiver: input }, PeerSender { sender: output })
+        (
+            PeerReceiver { receiver: input, slot: slot.clone() },
+            PeerSender::with(output, shareable, plain, stream, slot),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Writer accepting at most `capacity` bytes, three bytes per call
+    struct SlowWriter {
+        capacity: usize,
+        data: Vec<u8>,
+    }
+
+    impl io::Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.capacity == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let len = buf.len().min(self.capacity).min(3);
+            self.capacity -= len;
+            self.data.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn write_frame_tracks_written_bytes() {
+        let frame = (0u8..10).collect::<Vec<_>>();
+
+        let mut writer = SlowWriter { capacity: 100, data: vec![] };
+        assert_eq!(write_frame(&mut writer, &frame).unwrap(), 10);
+        assert_eq!(writer.data, frame);
+
+        let mut writer = SlowWriter { capacity: 5, data: vec![] };
+        match write_frame(&mut writer, &frame) {
+            Err(Error::PartialSend { written, total }) => {
+                assert_eq!(written, Some(5));
+                assert_eq!(total, 10);
+            }
+            res => panic!("partial write is not reported: {:?}", res),
+        }
+
+        let mut writer = SlowWriter { capacity: 0, data: vec![] };
+        match write_frame(&mut writer, &frame) {
+            Err(Error::Io(io::ErrorKind::WouldBlock)) => {}
+            res => panic!("blocked write is reported as {:?}", res),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn slow_reader() {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+        use std::os::unix::net::UnixStream;
+
+        let (mut writer, mut reader) = UnixStream::pair().unwrap();
+        // Small send buffer gets full long before the whole frame is written,
+        // while nothing is read from the other side
+        let size: libc::c_int = 4096;
+        let res = unsafe {
+            libc::setsockopt(
+                writer.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_SNDBUF,
+                &size as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(res, 0);
+        writer.set_nonblocking(true).unwrap();
+
+        let frame = (0..65_536u32).map(|i| i as u8).collect::<Vec<_>>();
+        let written = match write_frame(&mut writer, &frame) {
+            Err(Error::PartialSend { written: Some(written), total }) => {
+                assert_eq!(total, frame.len());
+                assert!(written > 0 && written < total);
+                written
+            }
+            res => panic!("partial write is not reported: {:?}", res),
+        };
+
+        // Nothing was written, so the send may be retried
+        match write_frame(&mut writer, &frame) {
+            Err(Error::Io(kind)) if is_blocked(kind) => {}
+            res => panic!("blocked write is reported as {:?}", res),
+        }
+
+        drop(writer);
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, &frame[..written]);
     }
 }
@@ -0,0 +1,124 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+use std::collections::{HashMap, HashSet};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use internet2::session::{self, LocalNode};
+use internet2::NodeAddr;
+
+use super::peer_connection::tcp_endpoint;
+use super::{BrontideSession, Error, PeerConnection};
+
+/// Brontide session established in background together with its TCP stream
+type WarmSession = (BrontideSession, TcpStream);
+
+/// Pool of connections to the remote peers, which may be established ahead of
+/// time, so the first message to a peer is not delayed by the TCP connection
+/// and BOLT-8 (brontide) handshake.
+///
+/// Useful for gateways knowing their peer set in advance: connections are
+/// pre-warmed in background threads with [`PeerPool::prewarm`] and later
+/// taken from the pool with [`PeerPool::connect`], which falls back to a
+/// synchronous connection for the peers which are not warm yet.
+#[derive(Default)]
+pub struct PeerPool {
+    warm: Arc<Mutex<HashMap<NodeAddr, WarmSession>>>,
+    pending: Arc<Mutex<HashSet<NodeAddr>>>,
+}
+
+impl PeerPool {
+    /// Constructs empty pool
+    pub fn new() -> Self { Self::default() }
+
+    /// Starts establishing connections to the remote peers in background
+    /// threads, one per peer. Peers which are already warm or are being
+    /// connected are skipped. Failed connections are logged and the peer
+    /// stays cold, so it may be pre-warmed again later.
+    ///
+    /// Only remote nodes accessible via TCP/IP are supported; if any of the
+    /// addresses is of other kind, [`Error::Unsupported`] is returned before
+    /// any connection is started.
+    pub fn prewarm(&self, addrs: &[NodeAddr], local: &LocalNode) -> Result<(), Error> {
+        let endpoints = addrs
+            .iter()
+            .map(|addr| Ok((addr.clone(), tcp_endpoint(addr.clone(), "pre-warmed connection")?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        for (addr, (remote, socket_addr)) in endpoints {
+            if self.is_warm(&addr)
+                || !self.pending.lock().expect("peer pool lock is poisoned").insert(addr.clone())
+            {
+                continue;
+            }
+            let warm = self.warm.clone();
+            let pending = self.pending.clone();
+            let local = local.clone();
+            thread::Builder::new().name(format!("peer-prewarm-{}", socket_addr)).spawn(
+                move || {
+                    debug!("Pre-warming connection to {}", addr);
+                    let res =
+                        TcpStream::connect(socket_addr).map_err(Error::from).and_then(|stream| {
+                            let session = session::Raw::with_brontide(
+                                stream.try_clone()?,
+                                local.private_key(),
+                                remote.node_id,
+                            )?;
+                            Ok((session, stream))
+                        });
+                    match res {
+                        Ok(session) => {
+                            debug!("Connection to {} is warm", addr);
+                            warm.lock()
+                                .expect("peer pool lock is poisoned")
+                                .insert(addr.clone(), session);
+                        }
+                        Err(err) => warn!("Unable to pre-warm connection to {}: {}", addr, err),
+                    }
+                    pending.lock().expect("peer pool lock is poisoned").remove(&addr);
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Checks whether connection to the peer is established and ready to be
+    /// taken from the pool
+    pub fn is_warm(&self, addr: &NodeAddr) -> bool {
+        self.warm.lock().expect("peer pool lock is poisoned").contains_key(addr)
+    }
+
+    /// Checks whether connection to the peer is being established in
+    /// background
+    pub fn is_pending(&self, addr: &NodeAddr) -> bool {
+        self.pending.lock().expect("peer pool lock is poisoned").contains(addr)
+    }
+
+    /// Takes warm connection to the peer out of the pool, if there is one
+    pub fn take(&self, addr: &NodeAddr) -> Option<PeerConnection> {
+        let (session, stream) =
+            self.warm.lock().expect("peer pool lock is poisoned").remove(addr)?;
+        Some(PeerConnection::with_stream(session, stream))
+    }
+
+    /// Takes warm connection to the peer out of the pool, or connects to the
+    /// peer synchronously with [`PeerConnection::connect`] if the connection
+    /// is not warm
+    pub fn connect(&self, addr: &NodeAddr, local: &LocalNode) -> Result<PeerConnection, Error> {
+        match self.take(addr) {
+            Some(connection) => Ok(connection),
+            None => PeerConnection::connect(addr.clone(), local),
+        }
+    }
+}
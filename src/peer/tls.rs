@@ -0,0 +1,407 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! TLS-protected TCP transport for peer connections, providing standard
+//! certificate-based secure channel for the cases where brontide with its
+//! Lightning key scheme is overkill (like internal RPC).
+//!
+//! Messages are framed in the same way as by FTCP sessions and sent inside
+//! TLS session running over a TCP stream.
+
+use std::any::Any;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use internet2::session::{Decrypt, Input, Output, Session, Split};
+use internet2::transport::{self, RoutedFrame, FRAME_PREFIX_SIZE, FRAME_SUFFIX_SIZE};
+use internet2::{presentation, NodeAddr, PlainTranscoder};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, ConnectionCommon, PrivateKey, RootCertStore,
+    ServerConfig, ServerConnection, ServerName, StreamOwned,
+};
+
+use super::peer_connection::{plain_frame, tcp_endpoint, write_frame};
+use super::{Error, PeerConnection, PeerSecurity};
+
+/// Default time limit for the TLS handshake
+pub const DEFAULT_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Configuration of TLS peer connections
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TlsConfig {
+    /// DER-encoded certificate chain presented to the remote peer, starting
+    /// with the certificate of this node
+    pub cert_chain: Vec<Vec<u8>>,
+
+    /// DER-encoded private key for the first certificate in `cert_chain`
+    pub key: Vec<u8>,
+
+    /// DER-encoded certificate of the authority verifying the remote peer.
+    /// Required for connecting; when accepting connections, its presence
+    /// enables client authentication, so only clients with certificates
+    /// issued by this authority are accepted.
+    pub ca: Option<Vec<u8>>,
+
+    /// Name the certificate of the remote peer must be issued for when
+    /// connecting. If not given, the IP address of the remote peer is used.
+    pub server_name: Option<String>,
+
+    /// Time limit for establishing TLS session, including the TCP connection
+    /// for the outgoing connections
+    pub handshake_timeout: Duration,
+}
+
+impl TlsConfig {
+    /// Constructs configuration with the default handshake timeout of
+    /// [`DEFAULT_TLS_HANDSHAKE_TIMEOUT`]
+    pub fn with(cert_chain: Vec<Vec<u8>>, key: Vec<u8>, ca: Option<Vec<u8>>) -> Self {
+        TlsConfig {
+            cert_chain,
+            key,
+            ca,
+            server_name: None,
+            handshake_timeout: DEFAULT_TLS_HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    fn cert_chain(&self) -> Vec<Certificate> {
+        self.cert_chain.iter().cloned().map(Certificate).collect()
+    }
+
+    fn roots(&self) -> Result<Option<RootCertStore>, Error> {
+        let ca = match self.ca {
+            None => return Ok(None),
+            Some(ref ca) => ca,
+        };
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(ca.clone())).map_err(|err| Error::Tls(err.to_string()))?;
+        Ok(Some(roots))
+    }
+
+    fn client_config(&self) -> Result<ClientConfig, Error> {
+        let roots = self.roots()?.ok_or_else(|| {
+            Error::Tls(s!("certificate authority is required for connecting to a TLS peer"))
+        })?;
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_single_cert(self.cert_chain(), PrivateKey(self.key.clone()))
+            .map_err(|err| Error::Tls(err.to_string()))
+    }
+
+    fn server_config(&self) -> Result<ServerConfig, Error> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match self.roots()? {
+            Some(roots) => {
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            }
+            None => builder.with_no_client_auth(),
+        };
+        builder
+            .with_single_cert(self.cert_chain(), PrivateKey(self.key.clone()))
+            .map_err(|err| Error::Tls(err.to_string()))
+    }
+}
+
+/// TLS configuration secures connections with TLS sessions
+impl PeerSecurity for TlsConfig {
+    fn connect_peer(&self, endpoint: NodeAddr) -> Result<PeerConnection, Error> {
+        let (_, remote) = tcp_endpoint(endpoint, "TLS connection")?;
+        let server_name = self.server_name.clone().unwrap_or_else(|| remote.ip().to_string());
+        let session = TlsSession::connect(remote, &server_name, self)?;
+        let stream = session.try_clone_stream()?;
+        Ok(PeerConnection::with_stream(session, stream))
+    }
+
+    fn accept_peer(&self, endpoint: NodeAddr) -> Result<PeerConnection, Error> {
+        let (_, local) = tcp_endpoint(endpoint, "TLS connection")?;
+        let session = TlsSession::accept(&TcpListener::bind(local)?, self)?;
+        let stream = session.try_clone_stream()?;
+        Ok(PeerConnection::with_stream(session, stream))
+    }
+}
+
+enum TlsStream {
+    Client(StreamOwned<ClientConnection, TcpStream>),
+    Server(StreamOwned<ServerConnection, TcpStream>),
+}
+
+impl TlsStream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            TlsStream::Client(stream) => &stream.sock,
+            TlsStream::Server(stream) => &stream.sock,
+        }
+    }
+
+    /// Reads already decrypted data, failing with [`io::ErrorKind::WouldBlock`]
+    /// if there are none
+    fn read_plaintext(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Client(stream) => stream.conn.reader().read(buf),
+            TlsStream::Server(stream) => stream.conn.reader().read(buf),
+        }
+    }
+
+    /// Reads and processes TLS records from the socket, blocking only if no
+    /// data are available. Returns zero on EOF.
+    fn read_records(&mut self) -> io::Result<usize> {
+        match self {
+            TlsStream::Client(stream) => read_records(&mut *stream.conn, &mut stream.sock),
+            TlsStream::Server(stream) => read_records(&mut *stream.conn, &mut stream.sock),
+        }
+    }
+}
+
+fn read_records<T>(conn: &mut ConnectionCommon<T>, sock: &mut TcpStream) -> io::Result<usize> {
+    let len = conn.read_tls(sock)?;
+    conn.process_new_packets()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    // Handshake messages and alerts produced in response
+    while conn.wants_write() {
+        conn.write_tls(sock)?;
+    }
+    Ok(len)
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Client(stream) => stream.write(buf),
+            TlsStream::Server(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TlsStream::Client(stream) => stream.flush(),
+            TlsStream::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Session running over TLS-protected TCP stream
+pub struct TlsSession {
+    stream: Arc<Mutex<TlsStream>>,
+    /// TCP socket used to wait for incoming data without locking the session
+    socket: Arc<TcpStream>,
+    /// Data of a partially received frame
+    pending: Vec<u8>,
+}
+
+impl TlsSession {
+    /// Connects to the remote peer at `remote` and establishes TLS session,
+    /// verifying that the peer certificate is issued for `server_name` by the
+    /// authority from `config`
+    pub fn connect(
+        remote: SocketAddr,
+        server_name: &str,
+        config: &TlsConfig,
+    ) -> Result<Self, Error> {
+        let name = ServerName::try_from(server_name)
+            .map_err(|_| Error::Tls(format!("invalid TLS server name `{}`", server_name)))?;
+        let conn = ClientConnection::new(Arc::new(config.client_config()?), name)
+            .map_err(|err| Error::Tls(err.to_string()))?;
+        let stream =
+            TcpStream::connect_timeout(&remote, config.handshake_timeout).map_err(timeout_error)?;
+        Self::handshake(TlsStream::Client(StreamOwned::new(conn, stream)), config)
+    }
+
+    /// Accepts next TCP connection from the `listener` and establishes TLS
+    /// session with the connected peer
+    pub fn accept(listener: &TcpListener, config: &TlsConfig) -> Result<Self, Error> {
+        let (stream, remote) = listener.accept()?;
+        debug!("Accepted TLS peer connection from {}", remote);
+        Self::with_stream(stream, config)
+    }
+
+    /// Establishes server-side TLS session over already accepted TCP stream
+    pub fn with_stream(stream: TcpStream, config: &TlsConfig) -> Result<Self, Error> {
+        let conn = ServerConnection::new(Arc::new(config.server_config()?))
+            .map_err(|err| Error::Tls(err.to_string()))?;
+        Self::handshake(TlsStream::Server(StreamOwned::new(conn, stream)), config)
+    }
+
+    /// Returns handle to the underlying TCP stream
+    pub fn try_clone_stream(&self) -> io::Result<TcpStream> { self.socket.try_clone() }
+
+    /// Completes TLS handshake within the time limit from `config`
+    fn handshake(mut stream: TlsStream, config: &TlsConfig) -> Result<Self, Error> {
+        let socket = stream.tcp().try_clone()?;
+        socket.set_read_timeout(Some(config.handshake_timeout))?;
+        socket.set_write_timeout(Some(config.handshake_timeout))?;
+        let res = match stream {
+            TlsStream::Client(ref mut stream) => stream.conn.complete_io(&mut stream.sock),
+            TlsStream::Server(ref mut stream) => stream.conn.complete_io(&mut stream.sock),
+        };
+        res.map_err(|err| match timeout_error(err) {
+            Error::Timeout => Error::Timeout,
+            err => Error::Tls(err.to_string()),
+        })?;
+        socket.set_read_timeout(None)?;
+        socket.set_write_timeout(None)?;
+        Ok(Self { stream: Arc::new(Mutex::new(stream)), socket: Arc::new(socket), pending: vec![] })
+    }
+
+    /// Receives single message frame. While waiting for the data from the
+    /// remote peer, the session is not locked, so the sending half of the
+    /// split session may proceed.
+    fn recv_frame(&mut self) -> Result<Vec<u8>, Error> {
+        let mut buf = [0u8; 4096];
+        let mut eof = false;
+        loop {
+            if let Some(frame) = take_frame(&mut self.pending)? {
+                return Ok(frame);
+            }
+            let mut stream = self.stream.lock().expect("TLS session lock is poisoned");
+            match stream.read_plaintext(&mut buf) {
+                Ok(0) => return Err(Error::Io(io::ErrorKind::UnexpectedEof)),
+                Ok(len) => {
+                    self.pending.extend_from_slice(&buf[..len]);
+                    continue;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock && eof => {
+                    return Err(Error::Io(io::ErrorKind::UnexpectedEof))
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err.into()),
+            }
+            drop(stream);
+            self.socket.peek(&mut [0u8; 1])?;
+            let mut stream = self.stream.lock().expect("TLS session lock is poisoned");
+            eof = stream.read_records()? == 0;
+        }
+    }
+
+    /// Sends single message frame, reporting frames which were written only
+    /// partially as [`Error::PartialSend`]
+    pub(super) fn send_frame(&mut self, raw: &[u8]) -> Result<usize, Error> {
+        let frame = plain_frame(raw)?;
+        let mut stream = self.stream.lock().expect("TLS session lock is poisoned");
+        write_frame(&mut *stream, &frame)?;
+        stream.flush()?;
+        Ok(raw.len())
+    }
+}
+
+/// Extracts first complete FTCP frame from the buffer, if any
+fn take_frame(pending: &mut Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+    if pending.len() < FRAME_PREFIX_SIZE {
+        return Ok(None);
+    }
+    let len = u16::from_be_bytes([pending[0], pending[1]]) as usize;
+    let frame_len = FRAME_PREFIX_SIZE + len + FRAME_SUFFIX_SIZE;
+    if pending.len() < frame_len {
+        return Ok(None);
+    }
+    let frame = pending.drain(..frame_len).collect::<Vec<_>>();
+    PlainTranscoder.decrypt(frame).map(Some).map_err(|_| Error::Io(io::ErrorKind::InvalidData))
+}
+
+/// Reports elapsed time limit as [`Error::Timeout`]
+fn timeout_error(err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+        _ => err.into(),
+    }
+}
+
+/// Converts error for the `internet2` session interfaces, which report
+/// partial sends as timed out writes
+fn transport_error(err: Error) -> presentation::Error {
+    let kind = match err {
+        Error::Presentation(err) => return err,
+        Error::Io(kind) => kind,
+        Error::PartialSend { .. } | Error::Timeout => io::ErrorKind::TimedOut,
+        _ => io::ErrorKind::Other,
+    };
+    presentation::Error::from(transport::Error::SocketIo(kind))
+}
+
+fn routing_unsupported() -> presentation::Error {
+    transport_error(Error::Io(io::ErrorKind::Unsupported))
+}
+
+impl Session for TlsSession {
+    fn recv_raw_message(&mut self) -> Result<Vec<u8>, presentation::Error> {
+        self.recv_frame().map_err(transport_error)
+    }
+
+    fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, presentation::Error> {
+        self.send_frame(raw).map_err(transport_error)
+    }
+
+    fn recv_routed_message(&mut self) -> Result<RoutedFrame, presentation::Error> {
+        Err(routing_unsupported())
+    }
+
+    fn send_routed_message(
+        &mut self,
+        _source: &[u8],
+        _route: &[u8],
+        _dest: &[u8],
+        _raw: &[u8],
+    ) -> Result<usize, presentation::Error> {
+        Err(routing_unsupported())
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+}
+
+/// Split TLS session halves share the same TLS state, which can't be
+/// divided, under a lock. The receiving half waits for incoming data without
+/// holding the lock, so it does not block the sending half.
+impl Split for TlsSession {
+    fn split(self) -> (Box<dyn Input + Send>, Box<dyn Output + Send>) {
+        let output = TlsOutput(TlsSession {
+            stream: self.stream.clone(),
+            socket: self.socket.clone(),
+            pending: vec![],
+        });
+        (Box::new(TlsInput(self)), Box::new(output))
+    }
+}
+
+struct TlsInput(TlsSession);
+
+impl Input for TlsInput {
+    fn recv_raw_message(&mut self) -> Result<Vec<u8>, presentation::Error> {
+        self.0.recv_frame().map_err(transport_error)
+    }
+
+    fn recv_routed_message(&mut self) -> Result<RoutedFrame, presentation::Error> {
+        Err(routing_unsupported())
+    }
+}
+
+struct TlsOutput(TlsSession);
+
+impl Output for TlsOutput {
+    fn send_raw_message(&mut self, raw: &[u8]) -> Result<usize, presentation::Error> {
+        self.0.send_frame(raw).map_err(transport_error)
+    }
+
+    fn send_routed_message(
+        &mut self,
+        _source: &[u8],
+        _route: &[u8],
+        _dest: &[u8],
+        _raw: &[u8],
+    ) -> Result<usize, presentation::Error> {
+        Err(routing_unsupported())
+    }
+}
@@ -0,0 +1,302 @@
+// LNP/BP Core Library implementing LNPBP specifications & standards
+// Written in 2020 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the MIT License
+// along with this software.
+// If not, see <https://opensource.org/licenses/MIT>.
+
+//! BOLT-1 TLV extension streams carried at the end of peer messages
+
+use std::collections::BTreeMap;
+use std::io;
+
+use lightning_encoding::{LightningDecode, LightningEncode};
+
+/// Maximal size of BOLT-1 message payload, which limits the size of the TLV
+/// stream it may carry
+pub const MAX_TLV_STREAM_LEN: usize = u16::MAX as usize;
+
+/// Violations of BOLT-1 TLV stream encoding rules
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum TlvError {
+    /// TLV stream is truncated in the middle of a record
+    Truncated,
+
+    /// TLV stream contains non-minimally encoded `BigSize` integer
+    NonCanonicalBigSize,
+
+    /// TLV record of type {0} follows record with a greater type
+    OutOfOrder(u64),
+
+    /// TLV stream contains more than one record of type {0}
+    Duplicate(u64),
+
+    /// TLV stream contains unknown record of even type {0}
+    UnknownEven(u64),
+
+    /// TLV stream of {0} bytes does not fit into a peer message
+    TooLarge(usize),
+}
+
+impl From<TlvError> for lightning_encoding::Error {
+    fn from(err: TlvError) -> Self {
+        lightning_encoding::Error::DataIntegrityError(err.to_string())
+    }
+}
+
+/// TLV extension stream of a peer message, mapping record types to their raw
+/// values.
+///
+/// Records are always kept in the canonical (ascending type) order, so the
+/// encoded stream is valid regardless of the order in which the records were
+/// inserted. Decoding is strict: streams with out-of-order or duplicate
+/// records, or with non-minimal `BigSize` integers, are rejected.
+///
+/// The stream implements lightning encoding consuming all the remaining
+/// message data, so it can be used as the last field of the message structs
+/// sent with [`super::SendMessage::send_message`] and received with
+/// [`super::RecvMessage::recv_message`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct TlvStream(BTreeMap<u64, Vec<u8>>);
+
+impl TlvStream {
+    /// Constructs empty TLV stream
+    pub fn new() -> Self { Self::default() }
+
+    /// Number of records in the stream
+    pub fn len(&self) -> usize { self.0.len() }
+
+    /// Checks whether the stream has no records
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+
+    /// Adds record to the stream, returning the previous value of the record
+    /// with the same type, if any
+    pub fn insert(&mut self, tlv_type: u64, value: impl Into<Vec<u8>>) -> Option<Vec<u8>> {
+        self.0.insert(tlv_type, value.into())
+    }
+
+    /// Returns value of the record with `tlv_type`
+    pub fn get(&self, tlv_type: u64) -> Option<&[u8]> { self.0.get(&tlv_type).map(Vec::as_slice) }
+
+    /// Removes record with `tlv_type` from the stream, returning its value
+    pub fn remove(&mut self, tlv_type: u64) -> Option<Vec<u8>> { self.0.remove(&tlv_type) }
+
+    /// Iterates over the records in the canonical order
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.0.iter().map(|(tlv_type, value)| (*tlv_type, value.as_slice()))
+    }
+
+    /// Checks that the stream does not contain records of even types other
+    /// than `known`, following BOLT-1 "it's ok to be odd" rule
+    pub fn check_known(&self, known: &[u64]) -> Result<(), TlvError> {
+        match self.0.keys().find(|tlv_type| *tlv_type % 2 == 0 && !known.contains(tlv_type)) {
+            Some(tlv_type) => Err(TlvError::UnknownEven(*tlv_type)),
+            None => Ok(()),
+        }
+    }
+
+    /// Encodes the stream in the canonical order, checking that it fits into
+    /// a peer message
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TlvError> {
+        let mut data = vec![];
+        for (tlv_type, value) in &self.0 {
+            write_bigsize(&mut data, *tlv_type);
+            write_bigsize(&mut data, value.len() as u64);
+            data.extend_from_slice(value);
+        }
+        if data.len() > MAX_TLV_STREAM_LEN {
+            return Err(TlvError::TooLarge(data.len()));
+        }
+        Ok(data)
+    }
+
+    /// Strictly parses TLV stream occupying all of the `data`
+    pub fn from_bytes(mut data: &[u8]) -> Result<Self, TlvError> {
+        if data.len() > MAX_TLV_STREAM_LEN {
+            return Err(TlvError::TooLarge(data.len()));
+        }
+        let mut stream = BTreeMap::new();
+        let mut last_type = None;
+        while !data.is_empty() {
+            let tlv_type = read_bigsize(&mut data)?;
+            match last_type {
+                Some(last) if tlv_type == last => return Err(TlvError::Duplicate(tlv_type)),
+                Some(last) if tlv_type < last => return Err(TlvError::OutOfOrder(tlv_type)),
+                _ => {}
+            }
+            last_type = Some(tlv_type);
+            let len = read_bigsize(&mut data)?;
+            if len > data.len() as u64 {
+                return Err(TlvError::Truncated);
+            }
+            let (value, rest) = data.split_at(len as usize);
+            data = rest;
+            stream.insert(tlv_type, value.to_vec());
+        }
+        Ok(TlvStream(stream))
+    }
+}
+
+impl LightningEncode for TlvStream {
+    fn lightning_encode<E: io::Write>(&self, mut e: E) -> Result<usize, lightning_encoding::Error> {
+        let data = self.to_bytes()?;
+        e.write_all(&data)?;
+        Ok(data.len())
+    }
+}
+
+impl LightningDecode for TlvStream {
+    fn lightning_decode<D: io::Read>(mut d: D) -> Result<Self, lightning_encoding::Error> {
+        let mut data = vec![];
+        d.read_to_end(&mut data)?;
+        Ok(TlvStream::from_bytes(&data)?)
+    }
+}
+
+/// Writes BOLT-1 `BigSize` integer using minimal encoding
+fn write_bigsize(data: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0xFC => data.push(value as u8),
+        0xFD..=0xFFFF => {
+            data.push(0xFD);
+            data.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        0x10000..=0xFFFF_FFFF => {
+            data.push(0xFE);
+            data.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        _ => {
+            data.push(0xFF);
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+}
+
+/// Reads BOLT-1 `BigSize` integer, rejecting non-minimal encodings
+fn read_bigsize(data: &mut &[u8]) -> Result<u64, TlvError> {
+    let (prefix, rest) = data.split_first().ok_or(TlvError::Truncated)?;
+    let (len, min) = match prefix {
+        0xFD => (2, 0xFD),
+        0xFE => (4, 0x10000),
+        0xFF => (8, 0x1_0000_0000),
+        value => {
+            *data = rest;
+            return Ok(*value as u64);
+        }
+    };
+    if rest.len() < len {
+        return Err(TlvError::Truncated);
+    }
+    let value = rest[..len].iter().fold(0u64, |acc, byte| acc << 8 | *byte as u64);
+    if value < min {
+        return Err(TlvError::NonCanonicalBigSize);
+    }
+    *data = &rest[len..];
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// BOLT-1 `BigSize` test vectors
+    const BIGSIZE_VECTORS: &[(u64, &[u8])] = &[
+        (0, &[0x00]),
+        (252, &[0xFC]),
+        (253, &[0xFD, 0x00, 0xFD]),
+        (65535, &[0xFD, 0xFF, 0xFF]),
+        (65536, &[0xFE, 0x00, 0x01, 0x00, 0x00]),
+        (4294967295, &[0xFE, 0xFF, 0xFF, 0xFF, 0xFF]),
+        (4294967296, &[0xFF, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]),
+        (u64::MAX, &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]),
+    ];
+
+    #[test]
+    fn bigsize_vectors() {
+        for (value, encoded) in BIGSIZE_VECTORS {
+            let mut data = vec![];
+            write_bigsize(&mut data, *value);
+            assert_eq!(&data, encoded);
+
+            let mut slice = *encoded;
+            assert_eq!(read_bigsize(&mut slice), Ok(*value));
+            assert!(slice.is_empty());
+        }
+    }
+
+    #[test]
+    fn bigsize_non_canonical() {
+        let vectors: &[&[u8]] = &[&[0xFD, 0x00, 0xFC], &[0xFE, 0x00, 0x00, 0xFF, 0xFF], &[
+            0xFF, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF,
+        ]];
+        for mut data in vectors.iter().copied() {
+            assert_eq!(read_bigsize(&mut data), Err(TlvError::NonCanonicalBigSize));
+        }
+    }
+
+    #[test]
+    fn bigsize_truncated() {
+        let vectors: &[&[u8]] = &[&[], &[0xFD, 0x00], &[0xFE, 0xFF, 0xFF], &[0xFF, 0xFF, 0xFF]];
+        for mut data in vectors.iter().copied() {
+            assert_eq!(read_bigsize(&mut data), Err(TlvError::Truncated));
+        }
+    }
+
+    #[test]
+    fn canonical_round_trip() {
+        let mut stream = TlvStream::new();
+        stream.insert(254, vec![0xAA; 3]);
+        stream.insert(1, vec![]);
+        stream.insert(3, vec![0x01, 0x02]);
+
+        let data = stream.to_bytes().unwrap();
+        assert_eq!(data, vec![
+            0x01, 0x00, 0x03, 0x02, 0x01, 0x02, 0xFD, 0x00, 0xFE, 0x03, 0xAA, 0xAA, 0xAA
+        ]);
+        let decoded = TlvStream::from_bytes(&data).unwrap();
+        assert_eq!(decoded, stream);
+        assert_eq!(decoded.iter().map(|(tlv_type, _)| tlv_type).collect::<Vec<_>>(), vec![
+            1, 3, 254
+        ]);
+        assert_eq!(TlvStream::from_bytes(&[]).unwrap(), TlvStream::new());
+    }
+
+    #[test]
+    fn strict_decoding() {
+        assert_eq!(TlvStream::from_bytes(&[0x03, 0x00, 0x01, 0x00]), Err(TlvError::OutOfOrder(1)));
+        assert_eq!(TlvStream::from_bytes(&[0x01, 0x00, 0x01, 0x00]), Err(TlvError::Duplicate(1)));
+        assert_eq!(TlvStream::from_bytes(&[0x01, 0x02, 0x00]), Err(TlvError::Truncated));
+        assert_eq!(TlvStream::from_bytes(&[0x01]), Err(TlvError::Truncated));
+        assert_eq!(
+            TlvStream::from_bytes(&[0xFD, 0x00, 0x01, 0x00]),
+            Err(TlvError::NonCanonicalBigSize)
+        );
+    }
+
+    #[test]
+    fn size_limit() {
+        let mut stream = TlvStream::new();
+        stream.insert(1, vec![0u8; MAX_TLV_STREAM_LEN]);
+        assert!(matches!(stream.to_bytes(), Err(TlvError::TooLarge(_))));
+        assert_eq!(
+            TlvStream::from_bytes(&vec![0u8; MAX_TLV_STREAM_LEN + 1]),
+            Err(TlvError::TooLarge(MAX_TLV_STREAM_LEN + 1))
+        );
+    }
+
+    #[test]
+    fn unknown_even_records() {
+        let mut stream = TlvStream::new();
+        stream.insert(1, vec![]);
+        stream.insert(4, vec![]);
+        assert_eq!(stream.check_known(&[4]), Ok(()));
+        assert_eq!(stream.check_known(&[]), Err(TlvError::UnknownEven(4)));
+    }
+}
@@ -108,6 +108,9 @@ where
 {
     type ErrorType = Error;
 
+    /// The run loop never terminates cleanly
+    type ShutdownReport = ();
+
     fn try_run_loop(mut self) -> Result<(), Self::ErrorType> {
         loop {
             match self.run() {
@@ -18,7 +18,12 @@ use internet2::session::{Connect, Session};
 use internet2::{LocalNode, ToNodeAddr};
 
 /// Marker trait for LNP RPC requests
-pub trait Request: Debug + Display + TypedEnum + CreateUnmarshaller {}
+pub trait Request: Debug + Display + TypedEnum + CreateUnmarshaller {
+    /// Returns size of the serialized request without message header and
+    /// transport framing. Default implementation serializes the request;
+    /// types may override it with a cheaper computed estimate.
+    fn serialized_size(&self) -> usize { self.serialize().len() }
+}
 
 /// Marker trait for LNP RPC replies
 pub trait Reply: Debug + Display + TypedEnum + CreateUnmarshaller {}